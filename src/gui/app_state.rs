@@ -1,7 +1,9 @@
+use crate::omikron::omikron_connection::ConnectionStatus;
 use crate::{ACTIVE_TASKS, APP_STATE, SHUTDOWN, gui::elements::log_card::UiLogEntry};
 use json::{JsonValue, object};
 use std::{collections::VecDeque, thread, time::Duration};
 use sysinfo::{RefreshKind, System};
+use tokio::sync::watch;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -12,6 +14,8 @@ pub struct AppState {
     pub net_up: Vec<(f64, f64)>,
     pub net_down: Vec<(f64, f64)>,
     pub sys_info: String,
+    pub omikron_status: String,
+    pub rate_limited: u64,
 }
 const MAX_POINTS: usize = 1000;
 const MAX_LOGS: usize = 100;
@@ -26,6 +30,8 @@ impl AppState {
             net_up: Vec::new(),
             net_down: Vec::new(),
             sys_info: String::from("Loading..."),
+            omikron_status: String::from("offline"),
+            rate_limited: 0,
         }
     }
 
@@ -199,3 +205,16 @@ pub fn setup() {
         ACTIVE_TASKS.remove("System info loader");
     });
 }
+
+/// Mirror the Omikron connection status into the app state for the Ping card.
+pub fn watch_omikron(mut status_rx: watch::Receiver<ConnectionStatus>) {
+    tokio::spawn(async move {
+        loop {
+            let status = status_rx.borrow_and_update().to_string();
+            APP_STATE.lock().unwrap().omikron_status = status;
+            if status_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+}
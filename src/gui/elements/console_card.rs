@@ -6,7 +6,6 @@ use ratatui::{
     text::{Line, Span},
     widgets::{Block, Borders, Paragraph},
 };
-use ttp_core::{CommunicationType, CommunicationValue};
 use uuid::Uuid;
 
 use crate::{
@@ -17,10 +16,15 @@ use crate::{
         ui::FPS,
         util::borders::draw_block_joins,
     },
-    log, log_command, log_cv,
-    omikron::omikron_connection::OMIKRON_CONNECTION,
-    users::{user_manager, user_profile::UserProfile},
-    util::file_util,
+    log, log_command,
+    omikron::{omikron_connection::OMIKRON_CONNECTION, rate_limit, requests::Ping},
+    users::{
+        key_bundle::{self, Protection},
+        user_manager, user_migration,
+        user_profile::UserProfile,
+        user_purge,
+    },
+    util::{export_util, file_util, keystore},
 };
 use std::{
     any::Any,
@@ -401,7 +405,7 @@ pub async fn run_command(command: &str) {
         }
 
         ["help"] => {
-            log!("Available commands: tasks, fps, ping, user");
+            log!("Available commands: tasks, fps, ping, limits, user, keys");
         }
 
         ["help", "tasks"] => {
@@ -413,8 +417,21 @@ pub async fn run_command(command: &str) {
         ["help", "ping"] => {
             log!("Ping command usage: ping [time]");
         }
+        ["help", "limits"] => {
+            log!("Limits command usage: limits");
+        }
         ["help", "user"] => {
-            log!("User command usage: user add <username> | user remove <username> | user list");
+            log!(
+                "User command usage: user add <username> | \
+                 user remove <username> [days [export_file]] | \
+                 user restore <username> | user info <username> | user list | \
+                 user export <username> <file> [html] | user import <username> <file> | \
+                 user reissue <username> | user migrate <username> <target_public_key> | \
+                 user unmigrate <username> | user receive <file> <source_public_key>"
+            );
+        }
+        ["help", "keys"] => {
+            log!("Keys command usage: keys rewrap");
         }
 
         ["ping"] => {
@@ -424,19 +441,107 @@ pub async fn run_command(command: &str) {
             let time = time.parse::<u64>().unwrap_or(20);
             ping(time).await;
         }
+        ["limits"] => {
+            let rejected = rate_limit::rejected();
+            if rejected.is_empty() {
+                log!("No requests were rate limited");
+            }
+            for (communication_type, count) in rejected {
+                log!("> {}: {} rejected", communication_type, count);
+            }
+        }
         ["user", "add", username] => {
-            if let (Some(user), Some(_)) = user_manager::create_user(username).await {
+            if let Some(user) = user_manager::create_user(username, &Protection::Iota).await {
                 log!("Created user {}", user.user_id);
+                log!("Download the key once from the users page of the web UI");
             } else {
                 log!("Failed to create user");
             }
         }
-        ["user", "remove", username] => {
-            if let Some(user) = user_manager::get_user_by_username(username) {
-                user_manager::remove_user(user.user_id);
-                log!("Removed user {}", user.user_id);
-            } else {
+        ["user", "remove", username] => remove_user(username, 0, None).await,
+        ["user", "remove", username, days, export @ ..] if export.len() <= 1 => {
+            // A mistyped grace period must not turn into an immediate purge.
+            let Ok(days) = days.parse::<u64>() else {
+                log!("User remove usage: user remove <username> [days [export_file]]");
+                return;
+            };
+            remove_user(username, days, export.first().copied()).await
+        }
+        ["user", "restore", username] => {
+            let Some(user) = user_manager::get_user_by_username(username) else {
                 log!("Failed to find user");
+                return;
+            };
+            match user_purge::cancel(user.user_id).await {
+                Ok(true) => log!("User {} will be kept", user.user_id),
+                Ok(false) => log!("User {} was not scheduled for removal", user.user_id),
+                Err(e) => log!("Failed to restore user: {}", e),
+            }
+        }
+        ["user", "reissue", username] => {
+            let Some(user) = user_manager::get_user_by_username(username) else {
+                log!("Failed to find user");
+                return;
+            };
+            log!(
+                "Issuing a new key for {}; the old key stops working",
+                username
+            );
+            match user_manager::reissue_key(user.user_id, &Protection::Iota).await {
+                Ok(()) => log!("Download the new key once from the users page of the web UI"),
+                Err(e) => log!("Re-issuing the key failed: {}", e),
+            }
+        }
+        ["user", "migrate", username, target_public_key] => {
+            let Some(user) = user_manager::get_user_by_username(username) else {
+                log!("Failed to find user");
+                return;
+            };
+            match user_migration::export(user.user_id, target_public_key).await {
+                Ok(bundle) => {
+                    let dir = user_migration::MIGRATION_DIR;
+                    let name = format!("{}.migration", user.user_id);
+                    file_util::save_file(dir, &name, &bundle);
+                    let source = keystore::get_keys()
+                        .map(|k| k.public_key)
+                        .unwrap_or_default();
+                    log!(
+                        "Wrote {}/{}; on the target run user receive <file> {}",
+                        dir,
+                        name,
+                        source
+                    );
+                    log!(
+                        "{} is refused here from now on (user unmigrate {} to undo)",
+                        username,
+                        username
+                    );
+                }
+                Err(e) => log!("Migration failed: {}", e),
+            }
+        }
+        ["user", "unmigrate", username] => {
+            let Some(user) = user_manager::get_user_by_username(username) else {
+                log!("Failed to find user");
+                return;
+            };
+            match user_migration::cancel(user.user_id).await {
+                Ok(true) => log!("User {} is served here again", user.user_id),
+                Ok(false) => log!("User {} was not migrated", user.user_id),
+                Err(e) => log!("Failed to unmigrate user: {}", e),
+            }
+        }
+        ["user", "receive", path, source_public_key] => {
+            let bundle = match std::fs::read_to_string(path) {
+                Ok(bundle) => bundle,
+                Err(e) => {
+                    log!("Reading {} failed: {}", path, e);
+                    return;
+                }
+            };
+            match user_migration::import(&bundle, source_public_key).await {
+                Ok(user) => log!("Took over user {} ({})", user.username, user.user_id),
+                Err(e) => log!("Receiving the user failed: {}", e),
             }
         }
         ["user", "list"] => {
@@ -452,14 +557,54 @@ pub async fn run_command(command: &str) {
                 );
             }
         }
+        ["user", "export", username, path] => export_user(username, path, false).await,
+        ["user", "export", username, path, "html"] => export_user(username, path, true).await,
+        ["user", "import", username, path] => {
+            let Some(user) = user_manager::get_user_by_username(username) else {
+                log!("Failed to find user");
+                return;
+            };
+            let imported = match std::fs::read_to_string(path) {
+                Ok(jsonl) => export_util::import(user.user_id, &jsonl).await,
+                Err(e) => Err(e.to_string()),
+            };
+            match imported {
+                Ok(summary) => log!(
+                    "Imported {} messages ({} already stored), {} contacts, {} communities",
+                    summary.messages,
+                    summary.skipped,
+                    summary.contacts,
+                    summary.communities
+                ),
+                Err(e) => log!("Import failed: {}", e),
+            }
+        }
         ["user", "info", username] => {
-            if let Some(user) = user_manager::get_user_by_username(username) {
-                user_manager::remove_user(user.user_id);
-                log!("Removed user {}", user.user_id);
-            } else {
+            let Some(user) = user_manager::get_user_by_username(username) else {
                 log!("Failed to find user");
+                return;
+            };
+            log!(
+                "> Username: {}, ID: {}, created at: {}, storage: {}",
+                user.username,
+                user.user_id,
+                user.created_at,
+                file_util::get_designed_storage(user.user_id)
+            );
+            if key_bundle::has_bundle(user.user_id) {
+                log!("> Key bundle waiting to be downloaded");
+            }
+            if let Some(purge_at) = user_purge::scheduled_at(user.user_id).await {
+                log!("> Scheduled for removal at {}", purge_at);
+            }
+            if let Some(moved_at) = user.moved_at {
+                log!("> Moved to another Iota at {}", moved_at);
             }
         }
+        ["keys", "rewrap"] => match keystore::rewrap() {
+            Ok(()) => log!("Re-encrypted the keystore under a new wrapping key"),
+            Err(e) => log!("Rewrapping the keystore failed: {}", e),
+        },
         ["reload"] | ["restart"] => {
             log!("Restarting");
             *RELOAD.write().await = true;
@@ -475,17 +620,90 @@ pub async fn run_command(command: &str) {
     }
 }
 
+/// Removes `username` now, or after `days`. With `export` the user's
+/// conversations are first written to that file, which the operator keeps;
+/// nothing is removed if that fails.
+async fn remove_user(username: &str, days: u64, export: Option<&str>) {
+    let Some(user) = user_manager::get_user_by_username(username) else {
+        log!("Failed to find user");
+        return;
+    };
+    if let Some(path) = export {
+        if let Err(e) = export_util::export(user.user_id)
+            .await
+            .and_then(|jsonl| std::fs::write(path, jsonl).map_err(|e| e.to_string()))
+        {
+            log!("Export failed, {} was not removed: {}", username, e);
+            return;
+        }
+        log!("Exported {} to {}", username, path);
+    }
+
+    if days == 0 {
+        match user_purge::purge(user.user_id).await {
+            Ok(()) => log!("Removed user {}", user.user_id),
+            Err(e) => log!("Failed to remove user: {}", e),
+        }
+        return;
+    }
+    let Some(grace) = user_purge::grace_period(days) else {
+        log!("Failed to schedule removal: {} days is too long", days);
+        return;
+    };
+    match user_purge::schedule(user.user_id, grace).await {
+        Ok(_) => log!(
+            "User {} will be removed in {} days (user restore {} to keep them)",
+            user.user_id,
+            days,
+            username
+        ),
+        Err(e) => log!("Failed to schedule removal: {}", e),
+    }
+}
+
+/// Writes the user's conversations to `path`, which the operator keeps; the
+/// Iota keeps no copy. With `html` the file holds the readable render instead
+/// of the JSON Lines.
+async fn export_user(username: &str, path: &str, html: bool) {
+    let Some(user) = user_manager::get_user_by_username(username) else {
+        log!("Failed to find user");
+        return;
+    };
+    let jsonl = match export_util::export(user.user_id).await {
+        Ok(jsonl) => jsonl,
+        Err(e) => {
+            log!("Export failed: {}", e);
+            return;
+        }
+    };
+    let contents = if html {
+        export_util::render_html(&jsonl)
+    } else {
+        jsonl
+    };
+    match std::fs::write(path, contents) {
+        Ok(()) => log!("Exported {} to {}", user.username, path),
+        Err(e) => log!("Export failed: {}", e),
+    }
+}
+
 pub async fn ping(time: u64) {
     let conn = OMIKRON_CONNECTION.clone();
 
-    let response_cv = conn
-        .await_response(
-            &CommunicationValue::new(CommunicationType::ping),
-            Some(Duration::from_secs(time)),
-        )
-        .await;
-    match response_cv {
-        Ok(response) => log_cv!(response),
-        Err(err) => log!("Ping error: {:?}", err),
+    if !conn.is_connected().await {
+        log!("Omikron is {}, waiting up to {}s...", conn.status(), time);
+        if let Err(err) = conn.await_connection(Some(Duration::from_secs(time))).await {
+            log!("Ping error: {}", err);
+            return;
+        }
+    }
+
+    let start = Instant::now();
+    let ping = Ping {
+        timeout: Duration::from_secs(time),
+    };
+    match conn.request(&ping).await {
+        Ok(()) => log!("Pong after {}ms", start.elapsed().as_millis()),
+        Err(err) => log!("Ping error: {}", err),
     }
 }
@@ -107,10 +107,25 @@ impl Element for GraphCard {
                 .unwrap_or(0.0);
             let max_y = graph.iter().map(|(_, y)| *y).fold(-1.0, f64::max);
 
+            let title = match self.graph_type {
+                GRAPHS::Ping => {
+                    let state = APP_STATE.lock().unwrap();
+                    if state.rate_limited > 0 {
+                        format!(
+                            "{}[{}|{} limited]",
+                            self.title, state.omikron_status, state.rate_limited
+                        )
+                    } else {
+                        format!("{}[{}]", self.title, state.omikron_status)
+                    }
+                }
+                _ => self.title.clone(),
+            };
+
             let block = Block::default()
                 .title(format!(
                     "{}:─{}{}─{}min/{}max",
-                    self.title,
+                    title,
                     graph.last().unwrap_or(&(0.0, 0.0)).1 as i64,
                     unit,
                     min_y as i64,
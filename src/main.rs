@@ -22,11 +22,14 @@ use crate::gui::ui::start_tui;
 use crate::langu::language_creator;
 use crate::omikron::omikron_connection::OmikronConnection;
 use crate::terms::consent_state;
-use crate::users::user_manager;
+use crate::users::{key_bundle, user_manager};
 use crate::util::config_util::CONFIG;
+use crate::util::db;
 use crate::util::file_util::download_and_extract_zip;
 use crate::util::file_util::has_dir;
+use crate::util::keystore;
 use crate::util::logger;
+use crate::util::message_crypto;
 
 pub static APP_STATE: LazyLock<Arc<Mutex<AppState>>> =
     LazyLock::new(|| Arc::new(Mutex::new(AppState::new())));
@@ -90,10 +93,44 @@ async fn main() {
         // BASIC CONFIGURATION
         &CONFIG.write().await.load();
 
+        // KEYSTORE
+        if let Err(e) = keystore::import_from_config().await {
+            println!("Importing keys from config.json failed: {}", e);
+            return;
+        }
+        match keystore::load() {
+            Ok(true) => {}
+            // Omikron knows this Iota by its old key, and stored messages are
+            // sealed under keys derived from it; a new identity helps neither.
+            Ok(false) if CONFIG.read().await.get_iota_id() != 0 => {
+                println!(
+                    "This Iota is registered but keystore.json is missing; \
+                     restore it from a backup, or reset iota_id in config.json \
+                     to register as a new Iota"
+                );
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                println!("Keystore could not be loaded: {}", e);
+                return;
+            }
+        }
+
+        // DATABASE
+        if let Err(e) = db::init_messages_db() {
+            println!("Preparing the messages database failed: {}", e);
+            return;
+        }
+
         // USER MANAGEMENT
         if let Err(_) = user_manager::load_users().await {
             log_t!("user_load_failed");
         }
+        match key_bundle::seal_legacy_files() {
+            0 => {}
+            sealed => log!("Sealed {} plaintext user key files", sealed),
+        }
 
         let mut sb = "".to_string();
 
@@ -112,6 +149,13 @@ async fn main() {
         );
         log!("User IDS: {}", sb);
 
+        // ENCRYPTION AT REST
+        match message_crypto::encrypt_existing_rows() {
+            Ok(0) => {}
+            Ok(sealed) => log!("Encrypted {} stored messages", sealed),
+            Err(e) => log!("Encrypting stored messages failed: {}", e),
+        }
+
         // COMMUNITY MANAGEMENT
         /*        registry::load_interactables().await;
         community_manager::load_communities().await;
@@ -155,7 +199,11 @@ async fn main() {
             )
             .await;
         }
-        let _ = omikron::omikron_connection::get_omikron_connection().await;
+        // OMIKRON
+        omikron::handlers::load_handlers();
+        let omikron = omikron::omikron_connection::get_omikron_connection().await;
+        app_state::watch_omikron(omikron.subscribe());
+        omikron::retention_task::start();
 
         log_t!("setup_completed");
         loop {
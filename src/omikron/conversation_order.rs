@@ -0,0 +1,140 @@
+//! Keeps the messages of one conversation in arrival order.
+//!
+//! Handlers run detached, so a `message_state` could otherwise be handled
+//! before the `message_send` it refers to, or a `message_delete` before the
+//! edit it follows. Every ordered message takes a `Turn` in the read loop,
+//! before anything is spawned; a turn waits for the one taken before it in
+//! the same conversation. Other conversations and other types never wait.
+
+use crate::omikron::handlers::data_i64;
+use dashmap::DashMap;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::oneshot;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes};
+
+/// Types whose effect depends on what arrived before them in a conversation.
+const ORDERED_TYPES: [CommunicationType; 5] = [
+    CommunicationType::message_send,
+    CommunicationType::message_other_iota,
+    CommunicationType::message_state,
+    CommunicationType::message_edit,
+    CommunicationType::message_delete,
+];
+
+/// The last turn taken per conversation, resolved once it is finished.
+static TAILS: LazyLock<DashMap<(i64, i64), (u64, oneshot::Receiver<()>)>> =
+    LazyLock::new(|| DashMap::new());
+static TAKEN: AtomicU64 = AtomicU64::new(0);
+
+/// The two users `cv` is between, smaller id first, if it has to be handled
+/// in order. `message_send` names them in its data, the rest on the envelope.
+fn conversation(cv: &CommunicationValue) -> Option<(i64, i64)> {
+    if !ORDERED_TYPES.into_iter().any(|ct| cv.is_type(ct)) {
+        return None;
+    }
+    let (a, b) = if cv.is_type(CommunicationType::message_send) {
+        (
+            data_i64(cv, DataTypes::sender_id, 0),
+            data_i64(cv, DataTypes::receiver_id, 0),
+        )
+    } else {
+        (cv.get_sender() as i64, cv.get_receiver() as i64)
+    };
+    Some((a.min(b), a.max(b)))
+}
+
+/// A place in a conversation's queue. Dropping it, also when the handler
+/// panicked, lets the next turn go.
+pub struct Turn {
+    conversation: (i64, i64),
+    number: u64,
+    previous: Option<oneshot::Receiver<()>>,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl Turn {
+    /// Queues `cv` behind the messages taken before it; `None` if it is
+    /// not ordered. Must be called in arrival order.
+    pub fn take(cv: &CommunicationValue) -> Option<Turn> {
+        let conversation = conversation(cv)?;
+        let number = TAKEN.fetch_add(1, Ordering::Relaxed);
+        let (done, finished) = oneshot::channel();
+        let previous = TAILS
+            .insert(conversation, (number, finished))
+            .map(|(_, previous)| previous);
+        Some(Turn {
+            conversation,
+            number,
+            previous,
+            done: Some(done),
+        })
+    }
+
+    /// Waits until the previous turn in the conversation is finished.
+    pub async fn wait(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            // A dropped sender also means the previous turn is over.
+            let _ = previous.await;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        // The entry is only this turn's while nothing was queued behind it.
+        TAILS.remove_if(&self.conversation, |_, (number, _)| *number == self.number);
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+    use ttp_core::DataValue;
+
+    fn state(sender: u64, receiver: u64) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::message_state)
+            .with_sender(sender)
+            .with_receiver(receiver)
+    }
+
+    #[test]
+    fn both_directions_share_a_conversation() {
+        let send = CommunicationValue::new(CommunicationType::message_send)
+            .add_data(DataTypes::sender_id, DataValue::Number(9))
+            .add_data(DataTypes::receiver_id, DataValue::Number(4));
+        assert_eq!(conversation(&send), Some((4, 9)));
+        assert_eq!(conversation(&state(4, 9)), Some((4, 9)));
+        assert_eq!(
+            conversation(&CommunicationValue::new(CommunicationType::messages_get)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn turns_run_in_the_order_they_were_taken() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut handles = Vec::new();
+        for i in 0..3u64 {
+            let mut turn = Turn::take(&state(1_000_001, 1_000_002)).unwrap();
+            let order = order.clone();
+            handles.push(tokio::spawn(async move {
+                turn.wait().await;
+                // The first turn is the slowest; it still finishes first.
+                tokio::time::sleep(Duration::from_millis(30 - i * 10)).await;
+                order.lock().await.push(i);
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*order.lock().await, vec![0, 1, 2]);
+        assert!(!TAILS.contains_key(&(1_000_001, 1_000_002)));
+    }
+}
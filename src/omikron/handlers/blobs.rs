@@ -0,0 +1,130 @@
+use crate::omikron::handlers::{OmikronHandler, OmikronSender, reject};
+use crate::util::blob_store;
+use crate::util::config_util::CONFIG;
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use std::sync::Arc;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+// ************************************************ //
+// Attachments                                      //
+// ************************************************ //
+
+/// Announces an upload. Answers with an `upload_id` to send chunks to, or
+/// without one when the blob is already stored.
+pub struct BlobUploadStartHandler;
+
+#[async_trait]
+impl OmikronHandler for BlobUploadStartHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::blob_upload_start
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let hash = cv.get_data(DataTypes::blob_hash).as_str().unwrap_or("");
+        let size = cv.get_data(DataTypes::size).as_number().unwrap_or(-1);
+        if size < 0 {
+            reject(&sender, &cv, "missing size").await;
+            return;
+        }
+        let quota = CONFIG.read().await.get_blob_quota();
+
+        match blob_store::start_upload(my_id as i64, hash, size as u64, quota) {
+            Ok(upload_id) => {
+                let mut resp = CommunicationValue::new(CommunicationType::blob_upload_start)
+                    .with_id(cv.get_id())
+                    .with_receiver(my_id)
+                    .add_data(DataTypes::blob_hash, DataValue::Str(hash.to_string()))
+                    .add_data(
+                        DataTypes::chunk_size,
+                        DataValue::Number(blob_store::CHUNK_SIZE as i64),
+                    );
+                if let Some(upload_id) = upload_id {
+                    resp = resp.add_data(DataTypes::upload_id, DataValue::Str(upload_id));
+                }
+                sender.send_message(&resp).await;
+            }
+            Err(e) => reject(&sender, &cv, &e.to_string()).await,
+        }
+    }
+}
+
+/// One base64 chunk of an upload. The reply carries `blob_hash` once the
+/// last chunk arrived and the content was verified.
+pub struct BlobUploadChunkHandler;
+
+#[async_trait]
+impl OmikronHandler for BlobUploadChunkHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::blob_upload_chunk
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let upload_id = cv.get_data(DataTypes::upload_id).as_str().unwrap_or("");
+        let offset = cv.get_data(DataTypes::offset).as_number().unwrap_or(-1);
+        let Ok(chunk) = STANDARD.decode(cv.get_data(DataTypes::chunk).as_str().unwrap_or(""))
+        else {
+            reject(&sender, &cv, "chunk is not base64").await;
+            return;
+        };
+        if offset < 0 {
+            reject(&sender, &cv, "missing offset").await;
+            return;
+        }
+
+        match blob_store::append_chunk(my_id as i64, upload_id, offset as u64, &chunk) {
+            Ok(done) => {
+                let mut resp = CommunicationValue::new(CommunicationType::blob_upload_chunk)
+                    .with_id(cv.get_id())
+                    .with_receiver(my_id)
+                    .add_data(DataTypes::upload_id, DataValue::Str(upload_id.to_string()))
+                    .add_data(
+                        DataTypes::offset,
+                        DataValue::Number(offset + chunk.len() as i64),
+                    );
+                if let Some(hash) = done {
+                    resp = resp.add_data(DataTypes::blob_hash, DataValue::Str(hash));
+                }
+                sender.send_message(&resp).await;
+            }
+            Err(e) => reject(&sender, &cv, &e.to_string()).await,
+        }
+    }
+}
+
+/// Returns the chunk of a blob starting at `offset` along with its total
+/// `size`; clients keep asking until they have `size` bytes.
+pub struct BlobDownloadHandler;
+
+#[async_trait]
+impl OmikronHandler for BlobDownloadHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::blob_download
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let hash = cv.get_data(DataTypes::blob_hash).as_str().unwrap_or("");
+        let offset = cv
+            .get_data(DataTypes::offset)
+            .as_number()
+            .unwrap_or(0)
+            .max(0);
+
+        match blob_store::read_chunk(my_id as i64, hash, offset as u64) {
+            Ok((chunk, size)) => {
+                let resp = CommunicationValue::new(CommunicationType::blob_download)
+                    .with_id(cv.get_id())
+                    .with_receiver(my_id)
+                    .add_data(DataTypes::blob_hash, DataValue::Str(hash.to_string()))
+                    .add_data(DataTypes::offset, DataValue::Number(offset))
+                    .add_data(DataTypes::size, DataValue::Number(size as i64))
+                    .add_data(DataTypes::chunk, DataValue::Str(STANDARD.encode(chunk)));
+                sender.send_message(&resp).await;
+            }
+            Err(e) => reject(&sender, &cv, &e.to_string()).await,
+        }
+    }
+}
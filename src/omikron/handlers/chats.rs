@@ -0,0 +1,131 @@
+use crate::omikron::handlers::{
+    OmikronHandler, OmikronSender, data_bool, data_i64, now_millis, reject,
+};
+use crate::users::contact::Contact;
+use crate::util::chats_util::ContactFlags;
+use crate::util::repository::ContactStore;
+use async_trait::async_trait;
+use std::sync::Arc;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+pub struct GetChatsHandler;
+
+#[async_trait]
+impl OmikronHandler for GetChatsHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::get_chats
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let user_id = cv.get_sender();
+        let users = sender.store().get_users(user_id as i64).await;
+        let mut user_array = Vec::new();
+        for user in users {
+            let mut container = Vec::new();
+            container.push((DataTypes::user_id, DataValue::Number(user.user_id)));
+            if let Some(name) = user.user_name {
+                container.push((DataTypes::username, DataValue::Str(name)));
+            }
+            if let Some(ts) = user.last_message_at {
+                container.push((DataTypes::last_message_at, DataValue::Number(ts)));
+            }
+            if let Some(id) = user.last_read_id {
+                container.push((DataTypes::last_read_id, DataValue::Number(id)));
+            }
+            container.push((
+                DataTypes::unread_count,
+                DataValue::Number(user.unread_count),
+            ));
+            container.extend(flag_data(&user));
+            user_array.push(DataValue::Container(container));
+        }
+        let resp = CommunicationValue::new(CommunicationType::get_chats)
+            .with_id(cv.get_id())
+            .with_receiver(user_id)
+            .add_data(DataTypes::user_ids, DataValue::Array(user_array));
+        sender.send_message(&resp).await;
+    }
+}
+
+pub struct AddConversationHandler;
+
+#[async_trait]
+impl OmikronHandler for AddConversationHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::add_conversation
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let user_id = cv.get_sender();
+        let other_id = data_i64(&cv, DataTypes::chat_partner_id, 0);
+        let mut contact = sender
+            .store()
+            .get_user(user_id as i64, other_id)
+            .await
+            .unwrap_or(Contact::new(other_id));
+
+        if let Some(name) = cv.get_data(DataTypes::chat_partner_name).as_str() {
+            contact.user_name = Some(name.to_string());
+        }
+
+        contact.set_last_message_at(now_millis());
+        sender.store().mod_user(user_id as i64, &contact).await;
+        let resp = CommunicationValue::new(CommunicationType::add_conversation)
+            .with_id(cv.get_id())
+            .with_receiver(user_id);
+        sender.send_message(&resp).await;
+    }
+}
+
+fn flag_data(contact: &Contact) -> [(DataTypes, DataValue); 4] {
+    [
+        (DataTypes::blocked, DataValue::Bool(contact.blocked)),
+        (DataTypes::muted, DataValue::Bool(contact.muted)),
+        (DataTypes::archived, DataValue::Bool(contact.archived)),
+        (DataTypes::pinned, DataValue::Bool(contact.pinned)),
+    ]
+}
+
+/// Blocks, mutes, archives or pins a contact. Flags missing from the request
+/// keep their value; the answer carries all four as stored.
+pub struct ContactFlagsHandler;
+
+#[async_trait]
+impl OmikronHandler for ContactFlagsHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::contact_flags
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let user_id = cv.get_sender();
+        let other_id = data_i64(&cv, DataTypes::user_id, 0);
+        if other_id == 0 || other_id == user_id as i64 {
+            reject(&sender, &cv, "invalid user_id").await;
+            return;
+        }
+
+        let flags = ContactFlags {
+            blocked: data_bool(&cv, DataTypes::blocked),
+            muted: data_bool(&cv, DataTypes::muted),
+            archived: data_bool(&cv, DataTypes::archived),
+            pinned: data_bool(&cv, DataTypes::pinned),
+        };
+        match sender
+            .store()
+            .set_flags(user_id as i64, other_id, flags)
+            .await
+        {
+            Ok(contact) => {
+                let mut resp = CommunicationValue::new(CommunicationType::contact_flags)
+                    .with_id(cv.get_id())
+                    .with_receiver(user_id)
+                    .add_data(DataTypes::user_id, DataValue::Number(other_id));
+                for (key, value) in flag_data(&contact) {
+                    resp = resp.add_data(key, value);
+                }
+                sender.send_message(&resp).await;
+            }
+            Err(e) => reject(&sender, &cv, &e).await,
+        }
+    }
+}
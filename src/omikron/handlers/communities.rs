@@ -0,0 +1,91 @@
+use crate::omikron::handlers::{OmikronHandler, OmikronSender};
+use crate::util::repository::CommunityStore;
+use async_trait::async_trait;
+use std::sync::Arc;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+pub struct AddCommunityHandler;
+
+#[async_trait]
+impl OmikronHandler for AddCommunityHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::add_community
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        sender
+            .store()
+            .add_community(
+                cv.get_sender() as i64,
+                cv.get_data(DataTypes::community_address).as_str().unwrap(),
+                cv.get_data(DataTypes::community_title).as_str().unwrap(),
+                cv.get_data(DataTypes::position).as_str().unwrap(),
+            )
+            .await;
+        let resp = CommunicationValue::new(CommunicationType::add_community)
+            .with_id(cv.get_id())
+            .with_receiver(cv.get_sender());
+        sender.send_message(&resp).await;
+    }
+}
+
+pub struct GetCommunitiesHandler;
+
+#[async_trait]
+impl OmikronHandler for GetCommunitiesHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::get_communities
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let mut comm_array = Vec::new();
+        for c in sender.store().get_communities(cv.get_sender() as i64).await {
+            let mut container: Vec<(DataTypes, DataValue)> = Vec::new();
+            if let Some(address) = c["address"].as_str() {
+                container.push((
+                    DataTypes::community_address,
+                    DataValue::Str(address.to_string()),
+                ));
+            }
+            if let Some(title) = c["title"].as_str() {
+                container.push((
+                    DataTypes::community_title,
+                    DataValue::Str(title.to_string()),
+                ));
+            }
+            if let Some(position) = c["position"].as_str() {
+                container.push((DataTypes::position, DataValue::Str(position.to_string())));
+            }
+            comm_array.push(DataValue::Container(container));
+        }
+
+        let resp = CommunicationValue::new(CommunicationType::get_communities)
+            .with_id(cv.get_id())
+            .with_receiver(cv.get_sender())
+            .add_data(DataTypes::communities, DataValue::Array(comm_array));
+        sender.send_message(&resp).await;
+    }
+}
+
+pub struct RemoveCommunityHandler;
+
+#[async_trait]
+impl OmikronHandler for RemoveCommunityHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::remove_community
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        sender
+            .store()
+            .remove_community(
+                cv.get_sender() as i64,
+                cv.get_data(DataTypes::community_address).as_str().unwrap(),
+            )
+            .await;
+        let resp = CommunicationValue::new(CommunicationType::remove_community)
+            .with_id(cv.get_id())
+            .with_receiver(cv.get_sender());
+        sender.send_message(&resp).await;
+    }
+}
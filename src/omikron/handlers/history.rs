@@ -0,0 +1,78 @@
+use crate::log;
+use crate::omikron::handlers::{OmikronHandler, OmikronSender, reject};
+use crate::util::export_util;
+use async_trait::async_trait;
+use std::sync::Arc;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+// ************************************************ //
+// Export and import                                //
+// ************************************************ //
+
+/// Everything the requesting user has stored, as JSON Lines in `payload`;
+/// with `html` set, the rendered page comes along in `content`.
+pub struct HistoryExportHandler;
+
+#[async_trait]
+impl OmikronHandler for HistoryExportHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::history_export
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let jsonl = match export_util::export(my_id as i64).await {
+            Ok(jsonl) => jsonl,
+            Err(e) => {
+                log!("Export for {} failed: {}", my_id, e);
+                reject(&sender, &cv, "storage error").await;
+                return;
+            }
+        };
+
+        let mut resp = CommunicationValue::new(CommunicationType::history_export)
+            .with_id(cv.get_id())
+            .with_receiver(my_id);
+        if cv.get_data(DataTypes::html).as_bool().unwrap_or(false) {
+            resp = resp.add_data(
+                DataTypes::content,
+                DataValue::Str(export_util::render_html(&jsonl)),
+            );
+        }
+        resp = resp.add_data(DataTypes::payload, DataValue::Str(jsonl));
+        sender.send_message(&resp).await;
+    }
+}
+
+/// Merges an export sent in `payload` into the requesting user's storage.
+pub struct HistoryImportHandler;
+
+#[async_trait]
+impl OmikronHandler for HistoryImportHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::history_import
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let jsonl = cv.get_data(DataTypes::payload).as_str().unwrap_or("");
+
+        match export_util::import(my_id as i64, jsonl).await {
+            Ok(summary) => {
+                let resp = CommunicationValue::new(CommunicationType::history_import)
+                    .with_id(cv.get_id())
+                    .with_receiver(my_id)
+                    .add_data(
+                        DataTypes::amount,
+                        DataValue::Number(summary.messages as i64),
+                    )
+                    .add_data(
+                        DataTypes::skipped,
+                        DataValue::Number(summary.skipped as i64),
+                    );
+                sender.send_message(&resp).await;
+            }
+            Err(e) => reject(&sender, &cv, &e).await,
+        }
+    }
+}
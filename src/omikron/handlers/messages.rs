@@ -0,0 +1,809 @@
+use crate::log;
+use crate::omikron::handlers::{OmikronHandler, OmikronSender, data_i64, now_millis, reject};
+use crate::util::blob_store;
+use crate::util::chat_files::{MessageCursor, MessageState, NewMessage, SearchFilter};
+use crate::util::repository::MessageStore;
+use crate::util::retention_util::{self, RetentionPolicy};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+// ************************************************ //
+// Direct messages                                  //
+// ************************************************ //
+
+pub struct MessageStateHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageStateHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_state
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let sender_id = cv.get_sender();
+        let receiver_id = cv.get_receiver();
+
+        // Parse send_time robustly: accept numeric or string, fallback to current time
+        let timestamp_i64 = data_i64(&cv, DataTypes::send_time, now_millis());
+
+        let state =
+            MessageState::from_str(cv.get_data(DataTypes::message_state).as_str().unwrap_or(""));
+
+        // A read receipt also marks the reader's own copy, which moves their
+        // last-read marker and unread count for this conversation.
+        if state == MessageState::Read {
+            let _ = sender
+                .store()
+                .change_message_state(
+                    cv.get_id(),
+                    timestamp_i64,
+                    sender_id as i64,
+                    receiver_id as i64,
+                    MessageState::Read,
+                )
+                .await;
+        }
+
+        let _ = sender
+            .store()
+            .change_message_state(
+                cv.get_id(),
+                timestamp_i64,
+                receiver_id as i64,
+                sender_id as i64,
+                state,
+            )
+            .await;
+    }
+}
+
+/// Incoming stored message: store for the recipient, attempt local delivery, notify sender.
+pub struct MessageSendHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageSendHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_send
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let sender_id = data_i64(&cv, DataTypes::sender_id, 0);
+
+        // parse receiver_id (the storage owner for this incoming message)
+        let receiver_id = data_i64(&cv, DataTypes::receiver_id, 0);
+
+        // parse send_time robustly (number or string), fallback to now
+        let timestamp_i64 = data_i64(&cv, DataTypes::send_time, now_millis());
+        let timestamp_u128 = timestamp_i64 as u128;
+
+        // content may be missing; default to empty string
+        let content = cv
+            .get_data(DataTypes::content)
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        let height = cv.get_data(DataTypes::height).as_number().unwrap_or(0) as i64;
+        let blob_hash = cv.get_data(DataTypes::blob_hash).as_string();
+        let expires_at = cv.get_data(DataTypes::expires_at).as_number();
+
+        // A receiver who blocked the sender gets no copy; the sender keeps
+        // theirs, marked as blocked.
+        let blocked = sender.store().is_blocked(receiver_id, sender_id).await;
+
+        // persist message for the receiver (storage_owner = receiver_id)
+        let redelivered = !blocked
+            && sender
+                .store()
+                .add_message(NewMessage {
+                    message_id: cv.get_id(),
+                    send_time: timestamp_u128,
+                    sent_by_self: false,
+                    storage_owner: receiver_id,
+                    external_user: sender_id,
+                    content: content.clone(),
+                    height,
+                })
+                .await;
+
+        // persist message for the sender (storage_owner = sender_id)
+        sender
+            .store()
+            .add_message(NewMessage {
+                message_id: cv.get_id(),
+                send_time: timestamp_u128,
+                sent_by_self: true,
+                storage_owner: sender_id,
+                external_user: receiver_id,
+                content: content.clone(),
+                height,
+            })
+            .await;
+        let copies: &[(i64, i64)] = if blocked {
+            &[(sender_id, receiver_id)]
+        } else {
+            &[(receiver_id, sender_id), (sender_id, receiver_id)]
+        };
+        if let Some(hash) = &blob_hash {
+            attach_blob(cv.get_id(), hash, copies);
+        }
+        if let Some(expires_at) = expires_at {
+            set_expiry(cv.get_id(), expires_at, copies);
+        }
+
+        // send confirmation back to sender
+        let conf_msg = CommunicationValue::new(CommunicationType::message_send)
+            .with_id(cv.get_id())
+            .with_receiver(sender_id as u64);
+        sender.send_message(&conf_msg).await;
+
+        if blocked {
+            let _ = sender
+                .store()
+                .change_message_state(
+                    cv.get_id(),
+                    timestamp_i64,
+                    sender_id,
+                    receiver_id,
+                    MessageState::Blocked,
+                )
+                .await;
+            send_state(
+                &sender,
+                &cv,
+                receiver_id,
+                sender_id,
+                timestamp_i64,
+                MessageState::Blocked,
+            )
+            .await;
+            return;
+        }
+
+        // A redelivery was already pushed to the client; only its stored
+        // state is sent again.
+        if redelivered {
+            send_stored_state(&sender, &cv, receiver_id, sender_id, timestamp_i64).await;
+            return;
+        }
+
+        // Build a live-delivery message for the local client (recipient)
+        let user_forward = CommunicationValue::new(CommunicationType::message_live)
+            .with_id(cv.get_id())
+            .with_receiver(receiver_id as u64)
+            .add_data(DataTypes::send_time, DataValue::Number(timestamp_i64))
+            .add_data(DataTypes::content, DataValue::Str(content.clone()))
+            .add_data(DataTypes::sender_id, DataValue::Number(sender_id))
+            .add_data(DataTypes::height, DataValue::Number(height));
+        let user_forward = with_blob(user_forward, &blob_hash);
+        let user_forward = match expires_at {
+            Some(expires_at) => {
+                user_forward.add_data(DataTypes::expires_at, DataValue::Number(expires_at))
+            }
+            None => user_forward,
+        };
+
+        // Attempt delivery and await a response from the local client
+        let user_resp = sender
+            .await_response(&user_forward, Some(Duration::from_secs(10)))
+            .await;
+
+        let ms = match user_resp {
+            Ok(user_resp) => {
+                let ms_raw = user_resp
+                    .get_data(DataTypes::message_state)
+                    .as_string()
+                    .unwrap_or_else(|| "".to_string());
+                MessageState::from_str(&ms_raw).upgrade(MessageState::Received)
+            }
+            // Delivery failed or timed out; mark as Sent
+            Err(_) => MessageState::Sent,
+        };
+
+        // update stored message state for receiver
+        let _ = sender
+            .store()
+            .change_message_state(
+                cv.get_id(),
+                timestamp_i64,
+                receiver_id,
+                sender_id,
+                ms.clone(),
+            )
+            .await;
+
+        // update stored message state for sender
+        let _ = sender
+            .store()
+            .change_message_state(
+                cv.get_id(),
+                timestamp_i64,
+                sender_id,
+                receiver_id,
+                ms.clone(),
+            )
+            .await;
+
+        // notify original sender about the delivered/read state
+        send_state(&sender, &cv, receiver_id, sender_id, timestamp_i64, ms).await;
+    }
+}
+
+/// Points the stored `(owner, partner)` copies at the attachment `hash`.
+fn attach_blob(message_id: u32, hash: &str, copies: &[(i64, i64)]) {
+    for &(owner, partner) in copies {
+        if let Err(e) = blob_store::attach(owner, partner, message_id, hash) {
+            log!(
+                "Failed to attach blob {} to message {}: {}",
+                hash,
+                message_id,
+                e
+            );
+        }
+    }
+}
+
+/// Makes the stored `(owner, partner)` copies disappear at `expires_at`.
+fn set_expiry(message_id: u32, expires_at: i64, copies: &[(i64, i64)]) {
+    for &(owner, partner) in copies {
+        if let Err(e) = retention_util::set_expiry(owner, partner, message_id, expires_at) {
+            log!("Failed to set expiry of message {}: {}", message_id, e);
+        }
+    }
+}
+
+fn with_blob(cv: CommunicationValue, blob_hash: &Option<String>) -> CommunicationValue {
+    match blob_hash {
+        Some(hash) => cv.add_data(DataTypes::blob_hash, DataValue::Str(hash.clone())),
+        None => cv,
+    }
+}
+
+/// A message relayed from another Iota: only the receiver's copy lives here.
+pub struct MessageOtherIotaHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageOtherIotaHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_other_iota
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let sender_id = cv.get_sender();
+        let receiver_id = cv.get_receiver();
+
+        // parse send_time safely (number or string), fallback to now
+        let timestamp = data_i64(&cv, DataTypes::send_time, now_millis());
+
+        // content may be missing or non-string; default to empty string
+        let content = cv
+            .get_data(DataTypes::content)
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+
+        let height = cv.get_data(DataTypes::height).as_number().unwrap_or(0) as i64;
+        let blob_hash = cv.get_data(DataTypes::blob_hash).as_string();
+        let expires_at = cv.get_data(DataTypes::expires_at).as_number();
+
+        // Nothing from a blocked sender is stored; their Iota marks its copy.
+        if sender
+            .store()
+            .is_blocked(receiver_id as i64, sender_id as i64)
+            .await
+        {
+            send_state(
+                &sender,
+                &cv,
+                receiver_id as i64,
+                sender_id as i64,
+                timestamp,
+                MessageState::Blocked,
+            )
+            .await;
+            return;
+        }
+
+        let redelivered = sender
+            .store()
+            .add_message(NewMessage {
+                message_id: cv.get_id(),
+                send_time: timestamp as u128,
+                sent_by_self: false,
+                storage_owner: receiver_id as i64,
+                external_user: sender_id as i64,
+                content: content.clone(),
+                height,
+            })
+            .await;
+        if let Some(hash) = &blob_hash {
+            attach_blob(cv.get_id(), hash, &[(receiver_id as i64, sender_id as i64)]);
+        }
+        if let Some(expires_at) = expires_at {
+            set_expiry(
+                cv.get_id(),
+                expires_at,
+                &[(receiver_id as i64, sender_id as i64)],
+            );
+        }
+        if redelivered {
+            send_stored_state(
+                &sender,
+                &cv,
+                receiver_id as i64,
+                sender_id as i64,
+                timestamp,
+            )
+            .await;
+            return;
+        }
+
+        // Build user_forward using the parsed numeric timestamp and safe content string
+        let user_forward = CommunicationValue::new(CommunicationType::message_live)
+            .with_id(cv.get_id())
+            .with_receiver(receiver_id)
+            .add_data(DataTypes::send_time, DataValue::Number(timestamp))
+            .add_data(DataTypes::content, DataValue::Str(content.clone()))
+            .add_data(DataTypes::sender_id, DataValue::Number(sender_id as i64))
+            .add_data(DataTypes::height, DataValue::Number(height));
+        let user_forward = with_blob(user_forward, &blob_hash);
+        let user_forward = match expires_at {
+            Some(expires_at) => {
+                user_forward.add_data(DataTypes::expires_at, DataValue::Number(expires_at))
+            }
+            None => user_forward,
+        };
+
+        let user_resp = sender
+            .await_response(&user_forward, Some(Duration::from_secs(10)))
+            .await;
+
+        let ms = match user_resp {
+            Ok(user_resp) => {
+                let ms_raw = user_resp
+                    .get_data(DataTypes::message_state)
+                    .as_string()
+                    .unwrap_or_else(|| "".to_string());
+                MessageState::from_str(&ms_raw).upgrade(MessageState::Received)
+            }
+            // Delivery failed or timed out; mark as Sent
+            Err(_) => MessageState::Sent,
+        };
+
+        let _ = sender
+            .store()
+            .change_message_state(
+                cv.get_id(),
+                timestamp,
+                receiver_id as i64,
+                sender_id as i64,
+                ms.clone(),
+            )
+            .await;
+
+        send_state(
+            &sender,
+            &cv,
+            receiver_id as i64,
+            sender_id as i64,
+            timestamp,
+            ms,
+        )
+        .await;
+    }
+}
+
+/// Answers a retried delivery with the state already recorded for the
+/// receiver's copy instead of storing and delivering it a second time.
+async fn send_stored_state(
+    sender: &Arc<dyn OmikronSender>,
+    cv: &CommunicationValue,
+    receiver_id: i64,
+    sender_id: i64,
+    timestamp: i64,
+) {
+    let ms = sender
+        .store()
+        .get_message_state(receiver_id, sender_id, cv.get_id())
+        .await
+        .unwrap_or(MessageState::Sent);
+    send_state(sender, cv, receiver_id, sender_id, timestamp, ms).await;
+}
+
+/// Tells the original sender the state of the receiver's copy.
+async fn send_state(
+    sender: &Arc<dyn OmikronSender>,
+    cv: &CommunicationValue,
+    receiver_id: i64,
+    sender_id: i64,
+    timestamp: i64,
+    ms: MessageState,
+) {
+    sender
+        .send_message(
+            &CommunicationValue::new(CommunicationType::message_state)
+                .with_id(cv.get_id())
+                .with_receiver(sender_id as u64)
+                .with_sender(receiver_id as u64)
+                .add_data(DataTypes::send_time, DataValue::Number(timestamp))
+                .add_data(
+                    DataTypes::message_state,
+                    DataValue::Str(ms.as_str().to_string()),
+                ),
+        )
+        .await;
+}
+
+enum MessageChange {
+    Edit(String),
+    Delete,
+}
+
+impl MessageChange {
+    fn communication_type(&self) -> CommunicationType {
+        match self {
+            MessageChange::Edit(_) => CommunicationType::message_edit,
+            MessageChange::Delete => CommunicationType::message_delete,
+        }
+    }
+}
+
+/// Edits or deletes both stored copies of a direct message. Requests from
+/// the author's client name their copy by `message_id` (the id from
+/// `messages_get`); relays from the author's Iota carry the shared wire id
+/// as their own id, and only the receiver's copy is stored here.
+async fn change_message(
+    sender: &Arc<dyn OmikronSender>,
+    cv: &CommunicationValue,
+    change: MessageChange,
+) {
+    let author = cv.get_sender() as i64;
+    let now = now_millis();
+
+    let (own, partner, wire_id, message_time) = match cv.get_data(DataTypes::message_id).as_number()
+    {
+        Some(row_id) => match sender.store().get_copy(author, row_id).await {
+            Some(own) if own.sent_by_self => {
+                let (partner, wire_id, time) =
+                    (own.external_user, own.message_id, own.message_time);
+                (Some(own), partner, wire_id, time)
+            }
+            _ => {
+                reject(sender, cv, "not your message").await;
+                return;
+            }
+        },
+        None => (
+            None,
+            cv.get_receiver() as i64,
+            Some(cv.get_id()),
+            data_i64(cv, DataTypes::send_time, 0),
+        ),
+    };
+    let theirs = sender
+        .store()
+        .find_copy(partner, author, wire_id, message_time)
+        .await
+        .filter(|copy| !copy.sent_by_self);
+
+    let copies: Vec<_> = own.iter().chain(theirs.iter()).collect();
+    if copies.is_empty() {
+        reject(sender, cv, "unknown message").await;
+        return;
+    }
+    if copies.iter().any(|copy| copy.deleted) {
+        reject(sender, cv, "message was deleted").await;
+        return;
+    }
+
+    for copy in &copies {
+        let res = match &change {
+            MessageChange::Edit(content) => {
+                sender.store().edit_copy(copy.row_id, content, now).await
+            }
+            MessageChange::Delete => sender.store().delete_copy(copy.row_id, now).await,
+        };
+        if let Err(e) = res {
+            log!("Failed to change message {}: {}", copy.row_id, e);
+            reject(sender, cv, "storage error").await;
+            return;
+        }
+    }
+
+    if own.is_some() {
+        sender
+            .send_message(
+                &CommunicationValue::new(change.communication_type())
+                    .with_id(cv.get_id())
+                    .with_receiver(author as u64),
+            )
+            .await;
+    }
+
+    // Live update for the other side, addressed like `message_live`.
+    let mut live = CommunicationValue::new(change.communication_type())
+        .with_id(wire_id.unwrap_or(cv.get_id()))
+        .with_sender(author as u64)
+        .with_receiver(partner as u64)
+        .add_data(DataTypes::sender_id, DataValue::Number(author))
+        .add_data(DataTypes::send_time, DataValue::Number(message_time));
+    if let Some(theirs) = &theirs {
+        live = live.add_data(DataTypes::message_id, DataValue::Number(theirs.row_id));
+    }
+    live = match change {
+        MessageChange::Edit(content) => live
+            .add_data(DataTypes::content, DataValue::Str(content))
+            .add_data(DataTypes::edited_at, DataValue::Number(now)),
+        MessageChange::Delete => live.add_data(DataTypes::deleted_at, DataValue::Number(now)),
+    };
+    sender.send_message(&live).await;
+}
+
+pub struct MessageEditHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageEditHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_edit
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let Some(content) = cv.get_data(DataTypes::content).as_string() else {
+            reject(&sender, &cv, "content missing").await;
+            return;
+        };
+        change_message(&sender, &cv, MessageChange::Edit(content)).await;
+    }
+}
+
+pub struct MessageDeleteHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageDeleteHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_delete
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        change_message(&sender, &cv, MessageChange::Delete).await;
+    }
+}
+
+/// Most messages one `messages_get` or `messages_search` returns.
+const MAX_PAGE: i64 = 200;
+
+pub struct MessagesGetHandler;
+
+#[async_trait]
+impl OmikronHandler for MessagesGetHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::messages_get
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let partner_id = cv.get_data(DataTypes::user_id).as_number().unwrap_or(0);
+        let amount = cv
+            .get_data(DataTypes::amount)
+            .as_number()
+            .unwrap_or(0)
+            .min(MAX_PAGE);
+        // Cursors take precedence; `offset` is kept for older clients.
+        let cursor = if let Some(id) = cv.get_data(DataTypes::before_id).as_number() {
+            MessageCursor::Before(id)
+        } else if let Some(id) = cv.get_data(DataTypes::after_id).as_number() {
+            MessageCursor::After(id)
+        } else {
+            MessageCursor::Offset(cv.get_data(DataTypes::offset).as_number().unwrap_or(0))
+        };
+        let messages = match sender
+            .store()
+            .get_messages(my_id as i64, partner_id, cursor, amount)
+            .await
+        {
+            Ok(messages) => messages,
+            Err(e) => {
+                reject(&sender, &cv, &e).await;
+                return;
+            }
+        };
+        let mut msg_array: Vec<DataValue> = Vec::new();
+        for m in messages.members() {
+            let id: i64 = m["id"].as_i64().unwrap_or(0);
+            let message_time: i64 = m["message_time"].as_i64().unwrap_or(0);
+            let content: String = m["content"].as_str().unwrap_or("").to_string();
+            let sent_by_self: bool = m["sent_by_self"].as_bool().unwrap_or(false);
+            let height: i64 = m["height"].as_i64().unwrap_or(0);
+            let sender_id: i64 = if sent_by_self {
+                my_id as i64
+            } else {
+                data_i64(&cv, DataTypes::chat_partner_id, partner_id as i64)
+            };
+            let message_state: String = m["message_state"].as_str().unwrap_or("").to_string();
+
+            let mut container = Vec::new();
+            container.push((DataTypes::message_id, DataValue::Number(id)));
+            container.push((DataTypes::send_time, DataValue::Number(message_time)));
+            container.push((DataTypes::content, DataValue::Str(content)));
+            container.push((DataTypes::sender_id, DataValue::Number(sender_id)));
+            container.push((DataTypes::message_state, DataValue::Str(message_state)));
+            container.push((DataTypes::height, DataValue::Number(height)));
+            container.push((DataTypes::sent_by_self, DataValue::Bool(sent_by_self)));
+            if let Some(edited_at) = m["edited_at"].as_i64() {
+                container.push((DataTypes::edited_at, DataValue::Number(edited_at)));
+            }
+            if let Some(deleted_at) = m["deleted_at"].as_i64() {
+                container.push((DataTypes::deleted_at, DataValue::Number(deleted_at)));
+            }
+            if let Some(expires_at) = m["expires_at"].as_i64() {
+                container.push((DataTypes::expires_at, DataValue::Number(expires_at)));
+            }
+            if let Some(blob_hash) = m["blob_hash"].as_str() {
+                container.push((DataTypes::blob_hash, DataValue::Str(blob_hash.to_string())));
+            }
+            msg_array.push(DataValue::Container(container));
+        }
+
+        let resp = CommunicationValue::new(CommunicationType::messages_get)
+            .with_id(cv.get_id())
+            .with_receiver(my_id)
+            .add_data(DataTypes::messages, DataValue::Array(msg_array));
+
+        sender.send_message(&resp).await;
+    }
+}
+
+pub struct MessagesSearchHandler;
+
+#[async_trait]
+impl OmikronHandler for MessagesSearchHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::messages_search
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let query = cv
+            .get_data(DataTypes::content)
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        let filter = SearchFilter {
+            external_user: cv.get_data(DataTypes::user_id).as_number(),
+            since: cv.get_data(DataTypes::since).as_number(),
+            until: cv.get_data(DataTypes::until).as_number(),
+        };
+        let offset = cv.get_data(DataTypes::offset).as_number().unwrap_or(0);
+        let amount = cv
+            .get_data(DataTypes::amount)
+            .as_number()
+            .unwrap_or(20)
+            .min(MAX_PAGE);
+
+        let hits = sender
+            .store()
+            .search_messages(my_id as i64, &query, &filter, offset, amount)
+            .await;
+        let mut msg_array: Vec<DataValue> = Vec::new();
+        for m in hits.members() {
+            let external_user = m["external_user"].as_i64().unwrap_or(0);
+            let sent_by_self = m["sent_by_self"].as_bool().unwrap_or(false);
+            let sender_id = if sent_by_self {
+                my_id as i64
+            } else {
+                external_user
+            };
+
+            let container = vec![
+                (
+                    DataTypes::message_id,
+                    DataValue::Number(m["id"].as_i64().unwrap_or(0)),
+                ),
+                (DataTypes::user_id, DataValue::Number(external_user)),
+                (
+                    DataTypes::send_time,
+                    DataValue::Number(m["message_time"].as_i64().unwrap_or(0)),
+                ),
+                (DataTypes::sender_id, DataValue::Number(sender_id)),
+                (DataTypes::sent_by_self, DataValue::Bool(sent_by_self)),
+                (
+                    DataTypes::snippet,
+                    DataValue::Str(m["snippet"].as_str().unwrap_or("").to_string()),
+                ),
+            ];
+            msg_array.push(DataValue::Container(container));
+        }
+
+        let resp = CommunicationValue::new(CommunicationType::messages_search)
+            .with_id(cv.get_id())
+            .with_receiver(my_id)
+            .add_data(DataTypes::messages, DataValue::Array(msg_array));
+
+        sender.send_message(&resp).await;
+    }
+}
+
+/// A partner's Iota reporting disappearing messages that expired there.
+pub struct MessageExpireHandler;
+
+#[async_trait]
+impl OmikronHandler for MessageExpireHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::message_expire
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let partner = cv.get_sender() as i64;
+        let owner = cv.get_receiver() as i64;
+        if partner == 0 {
+            return;
+        }
+        let message_ids: Vec<u32> = match cv.get_data(DataTypes::message_ids) {
+            DataValue::Array(ids) => ids
+                .iter()
+                .filter_map(|id| id.as_number())
+                .map(|id| id as u32)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        match retention_util::expire_relayed(owner, partner, &message_ids) {
+            Ok(expired) if !expired.is_empty() => {
+                let row_ids = expired
+                    .iter()
+                    .map(|m| DataValue::Number(m.row_id))
+                    .collect();
+                sender
+                    .send_message(
+                        &CommunicationValue::new(CommunicationType::message_expire)
+                            .with_id(cv.get_id())
+                            .with_receiver(owner as u64)
+                            .add_data(DataTypes::user_id, DataValue::Number(partner))
+                            .add_data(DataTypes::message_ids, DataValue::Array(row_ids)),
+                    )
+                    .await;
+            }
+            Ok(_) => {}
+            Err(e) => log!("Failed to expire relayed messages: {}", e),
+        }
+    }
+}
+
+/// Sets the sender's retention for one conversation (`user_id`) or for all
+/// of them. Missing limits inherit; `0` keeps messages without limit.
+pub struct RetentionSetHandler;
+
+#[async_trait]
+impl OmikronHandler for RetentionSetHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::retention_set
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let partner_id = cv.get_data(DataTypes::user_id).as_number().unwrap_or(0);
+        let policy = RetentionPolicy {
+            max_age_ms: cv.get_data(DataTypes::max_age).as_number(),
+            max_count: cv.get_data(DataTypes::max_count).as_number(),
+        };
+        if policy.max_age_ms.unwrap_or(0) < 0 || policy.max_count.unwrap_or(0) < 0 {
+            reject(&sender, &cv, "limits must not be negative").await;
+            return;
+        }
+
+        if let Err(e) = retention_util::set_policy(my_id as i64, partner_id, policy) {
+            log!("Failed to save retention policy: {}", e);
+            reject(&sender, &cv, "storage error").await;
+            return;
+        }
+        sender
+            .send_message(
+                &CommunicationValue::new(CommunicationType::retention_set)
+                    .with_id(cv.get_id())
+                    .with_receiver(my_id),
+            )
+            .await;
+    }
+}
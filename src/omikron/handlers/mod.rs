@@ -0,0 +1,238 @@
+//! Table-driven dispatch for messages routed to this Iota by Omikron.
+//!
+//! Every `CommunicationType` that carries user traffic (messages, chats,
+//! communities, settings) is served by an `OmikronHandler`. Handlers are
+//! registered once at startup via `load_handlers()` and looked up by
+//! `OmikronConnection::handle_message`. Handlers only talk back, and reach
+//! their store, through the `OmikronSender` trait, so they can be exercised
+//! against a fake sender and an in-memory DB without a live socket.
+
+pub mod blobs;
+pub mod chats;
+pub mod communities;
+pub mod history;
+pub mod messages;
+pub mod settings;
+
+use crate::users::user_manager;
+use crate::util::repository::SqliteStore;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+/// The outbound half of a connection as seen by a handler.
+#[async_trait]
+pub trait OmikronSender: Send + Sync {
+    async fn send_message(&self, cv: &CommunicationValue);
+    async fn await_response(
+        &self,
+        cv: &CommunicationValue,
+        timeout_duration: Option<Duration>,
+    ) -> Result<CommunicationValue, String>;
+    /// The store handlers keep conversations in.
+    fn store(&self) -> &SqliteStore;
+}
+
+#[async_trait]
+pub trait OmikronHandler: Send + Sync {
+    fn communication_type(&self) -> CommunicationType;
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue);
+}
+
+pub static HANDLER_REGISTRY: Lazy<RwLock<HashMap<String, Arc<dyn OmikronHandler>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub fn load_handlers() {
+    register_handler(Arc::new(messages::MessageStateHandler));
+    register_handler(Arc::new(messages::MessageSendHandler));
+    register_handler(Arc::new(messages::MessageOtherIotaHandler));
+    register_handler(Arc::new(messages::MessageEditHandler));
+    register_handler(Arc::new(messages::MessageDeleteHandler));
+    register_handler(Arc::new(messages::MessagesGetHandler));
+    register_handler(Arc::new(messages::MessagesSearchHandler));
+    register_handler(Arc::new(messages::MessageExpireHandler));
+    register_handler(Arc::new(messages::RetentionSetHandler));
+
+    register_handler(Arc::new(blobs::BlobUploadStartHandler));
+    register_handler(Arc::new(blobs::BlobUploadChunkHandler));
+    register_handler(Arc::new(blobs::BlobDownloadHandler));
+
+    register_handler(Arc::new(chats::GetChatsHandler));
+    register_handler(Arc::new(chats::AddConversationHandler));
+    register_handler(Arc::new(chats::ContactFlagsHandler));
+
+    register_handler(Arc::new(communities::AddCommunityHandler));
+    register_handler(Arc::new(communities::GetCommunitiesHandler));
+    register_handler(Arc::new(communities::RemoveCommunityHandler));
+
+    register_handler(Arc::new(history::HistoryExportHandler));
+    register_handler(Arc::new(history::HistoryImportHandler));
+
+    register_handler(Arc::new(settings::SettingsSaveHandler));
+    register_handler(Arc::new(settings::SettingsLoadHandler));
+    register_handler(Arc::new(settings::SettingsListHandler));
+}
+
+/// Registers `handler` for its `CommunicationType`, replacing any previous one.
+pub fn register_handler(handler: Arc<dyn OmikronHandler>) {
+    HANDLER_REGISTRY
+        .write()
+        .unwrap()
+        .insert(handler.communication_type().to_string(), handler);
+}
+
+pub fn get_handler(cv: &CommunicationValue) -> Option<Arc<dyn OmikronHandler>> {
+    HANDLER_REGISTRY
+        .read()
+        .unwrap()
+        .get(&cv.get_type().to_string())
+        .cloned()
+}
+
+// ============================================================================
+// Shared parsing helpers
+// ============================================================================
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Reads an id or timestamp that clients send either as a number or a string.
+pub fn data_i64(cv: &CommunicationValue, key: DataTypes, default: i64) -> i64 {
+    let value = cv.get_data(key);
+    if let Some(n) = value.as_number() {
+        n as i64
+    } else if let Some(s) = value.as_str() {
+        s.parse::<i64>().unwrap_or(default)
+    } else {
+        default
+    }
+}
+
+/// Reads a flag that clients send either as a boolean or as "true"/"false".
+/// `None` if the key is missing.
+pub fn data_bool(cv: &CommunicationValue, key: DataTypes) -> Option<bool> {
+    match cv.get_data(key) {
+        DataValue::Bool(b) => Some(*b),
+        DataValue::BoolTrue => Some(true),
+        DataValue::BoolFalse => Some(false),
+        value => value.as_str().and_then(|s| s.parse::<bool>().ok()),
+    }
+}
+
+/// The local user `cv` is from or for if they moved to another Iota. Their
+/// traffic is no longer served here.
+pub fn migrated_user(cv: &CommunicationValue) -> Option<i64> {
+    [cv.get_sender() as i64, cv.get_receiver() as i64]
+        .into_iter()
+        .find(|id| user_manager::get_user(*id).is_some_and(|u| u.moved_at.is_some()))
+}
+
+/// Answers `cv` with an error naming `reason`.
+pub async fn reject(sender: &Arc<dyn OmikronSender>, cv: &CommunicationValue, reason: &str) {
+    sender
+        .send_message(
+            &CommunicationValue::new(CommunicationType::error)
+                .with_id(cv.get_id())
+                .with_receiver(cv.get_sender())
+                .add_data(DataTypes::message, DataValue::Str(reason.to_string())),
+        )
+        .await;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tokio::sync::Mutex;
+
+    /// Records everything a handler sends and answers `await_response` with
+    /// a scripted reply (or an error when none is set). Handlers store into
+    /// a fresh in-memory DB.
+    pub struct FakeSender {
+        pub sent: Mutex<Vec<CommunicationValue>>,
+        pub reply: Option<CommunicationValue>,
+        pub store: SqliteStore,
+    }
+
+    impl FakeSender {
+        pub fn new(reply: Option<CommunicationValue>) -> Arc<Self> {
+            Arc::new(FakeSender {
+                sent: Mutex::new(Vec::new()),
+                reply,
+                store: SqliteStore::in_memory(),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl OmikronSender for FakeSender {
+        async fn send_message(&self, cv: &CommunicationValue) {
+            self.sent.lock().await.push(cv.clone());
+        }
+
+        async fn await_response(
+            &self,
+            cv: &CommunicationValue,
+            _timeout_duration: Option<Duration>,
+        ) -> Result<CommunicationValue, String> {
+            self.sent.lock().await.push(cv.clone());
+            self.reply.clone().ok_or("no reply scripted".to_string())
+        }
+
+        fn store(&self) -> &SqliteStore {
+            &self.store
+        }
+    }
+
+    #[test]
+    fn built_in_handlers_are_registered_by_type() {
+        load_handlers();
+        for ct in [
+            CommunicationType::message_send,
+            CommunicationType::messages_get,
+            CommunicationType::get_chats,
+            CommunicationType::settings_list,
+        ] {
+            let cv = CommunicationValue::new(ct);
+            let handler = get_handler(&cv).expect("handler missing");
+            assert_eq!(
+                handler.communication_type().to_string(),
+                cv.get_type().to_string()
+            );
+        }
+        let cv = CommunicationValue::new(CommunicationType::pong);
+        assert!(get_handler(&cv).is_none());
+    }
+
+    #[test]
+    fn data_i64_accepts_numbers_and_strings() {
+        let cv = CommunicationValue::new(CommunicationType::message_send)
+            .add_data(DataTypes::sender_id, ttp_core::DataValue::Number(7))
+            .add_data(
+                DataTypes::receiver_id,
+                ttp_core::DataValue::Str("9".to_string()),
+            );
+        assert_eq!(data_i64(&cv, DataTypes::sender_id, 0), 7);
+        assert_eq!(data_i64(&cv, DataTypes::receiver_id, 0), 9);
+        assert_eq!(data_i64(&cv, DataTypes::send_time, 42), 42);
+    }
+
+    #[test]
+    fn data_bool_accepts_booleans_and_strings() {
+        let cv = CommunicationValue::new(CommunicationType::contact_flags)
+            .add_data(DataTypes::blocked, ttp_core::DataValue::Bool(true))
+            .add_data(
+                DataTypes::muted,
+                ttp_core::DataValue::Str("false".to_string()),
+            );
+        assert_eq!(data_bool(&cv, DataTypes::blocked), Some(true));
+        assert_eq!(data_bool(&cv, DataTypes::muted), Some(false));
+        assert_eq!(data_bool(&cv, DataTypes::pinned), None);
+    }
+}
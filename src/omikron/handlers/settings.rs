@@ -0,0 +1,85 @@
+use crate::omikron::handlers::{OmikronHandler, OmikronSender};
+use crate::util::file_util::{get_children, load_file, save_file};
+use async_trait::async_trait;
+use std::sync::Arc;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+pub struct SettingsSaveHandler;
+
+#[async_trait]
+impl OmikronHandler for SettingsSaveHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::settings_save
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let settings_name = cv.get_data(DataTypes::settings_name).as_str().unwrap();
+        let settings_value = cv.get_data(DataTypes::payload).as_str().unwrap();
+
+        save_file(
+            &format!("users/{}/settings/", my_id),
+            &format!("{}.settings", settings_name),
+            &settings_value,
+        );
+
+        let response = CommunicationValue::new(CommunicationType::settings_save)
+            .with_receiver(my_id)
+            .with_id(cv.get_id());
+
+        sender.send_message(&response).await;
+    }
+}
+
+pub struct SettingsLoadHandler;
+
+#[async_trait]
+impl OmikronHandler for SettingsLoadHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::settings_load
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let settings_name = cv.get_data(DataTypes::settings_name).as_string().unwrap();
+        let settings_value_str = load_file(
+            &format!("users/{}/settings/", my_id),
+            &format!("{}.settings", settings_name),
+        );
+        let response = CommunicationValue::new(CommunicationType::settings_load)
+            .with_id(cv.get_id())
+            .with_receiver(my_id)
+            .add_data(DataTypes::payload, DataValue::Str(settings_value_str))
+            .add_data(DataTypes::settings_name, DataValue::Str(settings_name));
+
+        sender.send_message(&response).await;
+    }
+}
+
+pub struct SettingsListHandler;
+
+#[async_trait]
+impl OmikronHandler for SettingsListHandler {
+    fn communication_type(&self) -> CommunicationType {
+        CommunicationType::settings_list
+    }
+
+    async fn handle(&self, sender: Arc<dyn OmikronSender>, cv: CommunicationValue) {
+        let my_id = cv.get_sender();
+        let settings = get_children(&format!("users/{}/settings/", my_id));
+        let mut settings_json = Vec::new();
+        for s in settings {
+            let s = s.replace(".settings", "");
+            if s.is_empty() {
+                continue;
+            }
+            let _ = settings_json.push(DataValue::Str(s));
+        }
+        let response = CommunicationValue::new(CommunicationType::settings_list)
+            .with_id(cv.get_id())
+            .with_receiver(my_id)
+            .add_data(DataTypes::settings, DataValue::Array(settings_json));
+
+        sender.send_message(&response).await;
+    }
+}
@@ -0,0 +1,369 @@
+//! In-process Omikron stand-in for tests.
+//!
+//! `MockOmikron::attach` runs a real `OmikronConnection` session over an
+//! in-memory transport. The handshake (`register_iota`, `identification`,
+//! `challenge`, `identification_response`) and heartbeats are answered
+//! automatically, with Omikron advertising the Iota's own protocol version.
+//! Everything else the Iota sends is queued for the test to inspect with
+//! `expect`, and scripted traffic is pushed with `send`.
+
+use crate::omikron::omikron_connection::OmikronConnection;
+use crate::omikron::protocol;
+use crate::omikron::transport::{OmikronReceiver, OmikronTransport};
+use crate::util::crypto_helper::{self, KeyPair};
+use crate::util::crypto_util::{DataFormat, SecurePayload};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc};
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue, rand_u32};
+use x448::Secret;
+
+struct ChannelTransport {
+    tx: mpsc::UnboundedSender<CommunicationValue>,
+    open: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl OmikronTransport for ChannelTransport {
+    async fn send(&self, cv: &CommunicationValue) -> Result<(), String> {
+        if !self.is_open() {
+            return Err("mock transport closed".to_string());
+        }
+        self.tx
+            .send(cv.clone())
+            .map_err(|_| "mock Omikron gone".to_string())
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        self.open.store(false, Ordering::SeqCst);
+    }
+}
+
+struct ChannelReceiver {
+    rx: mpsc::UnboundedReceiver<CommunicationValue>,
+    open: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl OmikronReceiver for ChannelReceiver {
+    async fn receive(&mut self) -> Result<CommunicationValue, String> {
+        self.rx
+            .recv()
+            .await
+            .ok_or("mock Omikron closed".to_string())
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+pub struct MockOmikron {
+    to_iota: mpsc::UnboundedSender<CommunicationValue>,
+    inbox: Mutex<mpsc::UnboundedReceiver<CommunicationValue>>,
+    open: Arc<AtomicBool>,
+}
+
+impl MockOmikron {
+    /// Start a session for `conn`. `iota_public_key` is the base64 key the
+    /// Iota will prove possession of during the challenge; `iota_id` is
+    /// handed out when the Iota registers.
+    pub fn attach(conn: Arc<OmikronConnection>, iota_public_key: String, iota_id: i64) -> Self {
+        let open = Arc::new(AtomicBool::new(true));
+        let (to_iota, iota_rx) = mpsc::unbounded_channel();
+        let (iota_tx, mut from_iota) = mpsc::unbounded_channel();
+        let (inbox_tx, inbox) = mpsc::unbounded_channel();
+
+        let transport = Arc::new(ChannelTransport {
+            tx: iota_tx,
+            open: open.clone(),
+        });
+        let receiver = Box::new(ChannelReceiver {
+            rx: iota_rx,
+            open: open.clone(),
+        });
+        tokio::spawn(conn.run_session(transport, receiver));
+
+        let reply = to_iota.clone();
+        tokio::spawn(async move {
+            let omikron_keys = crypto_helper::generate_keypair();
+            let mut pending_challenge: Option<String> = None;
+
+            while let Some(cv) = from_iota.recv().await {
+                if cv.is_type(CommunicationType::ping) {
+                    let _ = reply.send(
+                        CommunicationValue::new(CommunicationType::pong).with_id(cv.get_id()),
+                    );
+                } else if cv.is_type(CommunicationType::register_iota) {
+                    let _ = reply.send(
+                        CommunicationValue::new(CommunicationType::success)
+                            .with_id(cv.get_id())
+                            .add_data(DataTypes::register_id, DataValue::Number(iota_id)),
+                    );
+                } else if cv.is_type(CommunicationType::identification) {
+                    let plain = format!("challenge-{}", rand_u32());
+                    let challenge = encrypt_challenge(&omikron_keys, &iota_public_key, &plain);
+                    pending_challenge = Some(plain);
+                    let _ = reply.send(
+                        CommunicationValue::new(CommunicationType::challenge)
+                            .add_data(
+                                DataTypes::public_key,
+                                DataValue::Str(crypto_helper::public_key_to_base64(
+                                    &omikron_keys.public,
+                                )),
+                            )
+                            .add_data(DataTypes::challenge, DataValue::Str(challenge)),
+                    );
+                } else if cv.is_type(CommunicationType::challenge_response) {
+                    let solved = cv.get_data(DataTypes::challenge).as_string();
+                    let accepted = solved.is_some() && solved == pending_challenge.take();
+                    let _ = reply.send(
+                        CommunicationValue::new(CommunicationType::identification_response)
+                            .add_data(DataTypes::accepted, DataValue::Bool(accepted))
+                            .add_data(
+                                DataTypes::payload,
+                                DataValue::Str(protocol::advertisement()),
+                            ),
+                    );
+                } else if inbox_tx.send(cv).is_err() {
+                    break;
+                }
+            }
+        });
+
+        MockOmikron {
+            to_iota,
+            inbox: Mutex::new(inbox),
+            open,
+        }
+    }
+
+    /// Deliver `cv` to the Iota as if Omikron had routed it.
+    pub fn send(&self, cv: CommunicationValue) {
+        let _ = self.to_iota.send(cv);
+    }
+
+    /// Next non-handshake message from the Iota.
+    pub async fn next(&self) -> CommunicationValue {
+        tokio::time::timeout(Duration::from_secs(5), self.inbox.lock().await.recv())
+            .await
+            .expect("timed out waiting for the Iota")
+            .expect("Iota session ended")
+    }
+
+    /// Next non-handshake message from the Iota, which must be of type `ct`.
+    pub async fn expect(&self, ct: CommunicationType) -> CommunicationValue {
+        let cv = self.next().await;
+        let received = cv.get_type().to_string();
+        assert!(cv.is_type(ct), "unexpected {} from the Iota", received);
+        cv
+    }
+
+    /// Send `cv` and wait for the Iota's answer carrying the same id.
+    pub async fn request(&self, cv: CommunicationValue) -> CommunicationValue {
+        let id = cv.get_id();
+        self.send(cv);
+        let answer = self.next().await;
+        assert_eq!(answer.get_id(), id, "Iota answered a different request");
+        answer
+    }
+
+    /// Drop the socket from the Omikron side.
+    pub fn disconnect(&self) {
+        self.open.store(false, Ordering::SeqCst);
+    }
+}
+
+fn encrypt_challenge(omikron_keys: &KeyPair, iota_public_key: &str, plain: &str) -> String {
+    let secret = Secret::from_bytes(omikron_keys.secret.as_bytes()).unwrap();
+    SecurePayload::new(plain.as_bytes(), DataFormat::Raw, secret)
+        .unwrap()
+        .encrypt_x448(crypto_helper::load_public_key(iota_public_key).unwrap())
+        .unwrap()
+        .export(DataFormat::Base64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockOmikron;
+    use crate::omikron::handlers::{self, OmikronSender};
+    use crate::omikron::omikron_connection::OmikronConnection;
+    use crate::util::chat_files::MessageCursor;
+    use crate::util::keystore;
+    use crate::util::repository::{ContactStore, MessageStore, SqliteStore};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue, rand_u32};
+
+    async fn identified(conn: &OmikronConnection) {
+        let mut status_rx = conn.subscribe();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            status_rx.wait_for(|status| status.state.is_identified()),
+        )
+        .await
+        .expect("Iota never identified against the mock Omikron")
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn session_stores_and_serves_direct_messages() {
+        handlers::load_handlers();
+        let public_key = keystore::test_identity().public_key;
+
+        // Conversations go to a DB of their own; files (settings, the
+        // keystore) to the temporary data directory of the test run.
+        let conn = Arc::new(OmikronConnection::new().with_store(SqliteStore::in_memory()));
+        let omikron = MockOmikron::attach(conn.clone(), public_key, 4242);
+        identified(&conn).await;
+
+        // Two users that only exist in this run.
+        let alice = 1_000_000 + rand_u32() as i64;
+        let bob = alice + 1;
+        let send_time = 1_700_000_000_000_i64;
+
+        let id = rand_u32();
+        omikron.send(
+            CommunicationValue::new(CommunicationType::message_send)
+                .with_id(id)
+                .add_data(DataTypes::sender_id, DataValue::Number(alice))
+                .add_data(DataTypes::receiver_id, DataValue::Number(bob))
+                .add_data(DataTypes::send_time, DataValue::Number(send_time))
+                .add_data(DataTypes::content, DataValue::Str("hi bob".to_string())),
+        );
+        let confirmation = omikron.expect(CommunicationType::message_send).await;
+        assert_eq!(confirmation.get_id(), id);
+
+        let live = omikron.expect(CommunicationType::message_live).await;
+        assert_eq!(live.get_receiver(), bob as u64);
+        omikron.send(
+            CommunicationValue::new(CommunicationType::message_live)
+                .with_id(live.get_id())
+                .add_data(DataTypes::message_state, DataValue::Str("read".to_string())),
+        );
+        let state = omikron.expect(CommunicationType::message_state).await;
+        assert_eq!(
+            state.get_data(DataTypes::message_state).as_str(),
+            Some("read")
+        );
+
+        // Both copies are stored and the conversation shows up for both sides.
+        // Omikron retrying the same delivery is acknowledged but not stored again.
+        omikron.send(
+            CommunicationValue::new(CommunicationType::message_send)
+                .with_id(id)
+                .add_data(DataTypes::sender_id, DataValue::Number(alice))
+                .add_data(DataTypes::receiver_id, DataValue::Number(bob))
+                .add_data(DataTypes::send_time, DataValue::Number(send_time))
+                .add_data(DataTypes::content, DataValue::Str("hi bob".to_string())),
+        );
+        let confirmation = omikron.expect(CommunicationType::message_send).await;
+        assert_eq!(confirmation.get_id(), id);
+        let state = omikron.expect(CommunicationType::message_state).await;
+        assert_eq!(
+            state.get_data(DataTypes::message_state).as_str(),
+            Some("read")
+        );
+
+        let bobs_copy = conn
+            .store()
+            .get_messages(bob, alice, MessageCursor::Offset(0), 10)
+            .await
+            .unwrap();
+        assert_eq!(bobs_copy.len(), 1);
+        assert_eq!(bobs_copy[0]["content"].as_str(), Some("hi bob"));
+        assert_eq!(bobs_copy[0]["message_state"].as_str(), Some("read"));
+        assert_eq!(bobs_copy[0]["sent_by_self"].as_bool(), Some(false));
+        let alices_copy = conn
+            .store()
+            .get_messages(alice, bob, MessageCursor::Offset(0), 10)
+            .await
+            .unwrap();
+        assert_eq!(alices_copy[0]["sent_by_self"].as_bool(), Some(true));
+        assert!(conn.store().get_user(bob, alice).await.is_some());
+        assert!(conn.store().get_user(alice, bob).await.is_some());
+
+        let history = omikron
+            .request(
+                CommunicationValue::new(CommunicationType::messages_get)
+                    .with_sender(bob as u64)
+                    .add_data(DataTypes::user_id, DataValue::Number(alice))
+                    .add_data(DataTypes::offset, DataValue::Number(0))
+                    .add_data(DataTypes::amount, DataValue::Number(10)),
+            )
+            .await;
+        assert_eq!(history.get_receiver(), bob as u64);
+
+        omikron
+            .request(
+                CommunicationValue::new(CommunicationType::settings_save)
+                    .with_sender(bob as u64)
+                    .add_data(
+                        DataTypes::settings_name,
+                        DataValue::Str("theme".to_string()),
+                    )
+                    .add_data(DataTypes::payload, DataValue::Str("dark".to_string())),
+            )
+            .await;
+        let loaded = omikron
+            .request(
+                CommunicationValue::new(CommunicationType::settings_load)
+                    .with_sender(bob as u64)
+                    .add_data(
+                        DataTypes::settings_name,
+                        DataValue::Str("theme".to_string()),
+                    ),
+            )
+            .await;
+        assert_eq!(loaded.get_data(DataTypes::payload).as_str(), Some("dark"));
+
+        // Once Bob blocks Alice her messages are refused with a distinct state.
+        let flags = omikron
+            .request(
+                CommunicationValue::new(CommunicationType::contact_flags)
+                    .with_sender(bob as u64)
+                    .add_data(DataTypes::user_id, DataValue::Number(alice))
+                    .add_data(DataTypes::blocked, DataValue::Bool(true)),
+            )
+            .await;
+        assert_eq!(
+            flags.get_type().to_string(),
+            CommunicationType::contact_flags.to_string()
+        );
+        assert!(conn.store().is_blocked(bob, alice).await);
+
+        let blocked_id = rand_u32();
+        omikron.send(
+            CommunicationValue::new(CommunicationType::message_send)
+                .with_id(blocked_id)
+                .add_data(DataTypes::sender_id, DataValue::Number(alice))
+                .add_data(DataTypes::receiver_id, DataValue::Number(bob))
+                .add_data(DataTypes::send_time, DataValue::Number(send_time + 1))
+                .add_data(
+                    DataTypes::content,
+                    DataValue::Str("still there?".to_string()),
+                ),
+        );
+        omikron.expect(CommunicationType::message_send).await;
+        let state = omikron.expect(CommunicationType::message_state).await;
+        assert_eq!(state.get_id(), blocked_id);
+        assert_eq!(
+            state.get_data(DataTypes::message_state).as_str(),
+            Some("blocked")
+        );
+        let bobs_copy = conn
+            .store()
+            .get_messages(bob, alice, MessageCursor::Offset(0), 10)
+            .await
+            .unwrap();
+        assert_eq!(bobs_copy.len(), 1);
+
+        omikron.disconnect();
+    }
+}
@@ -1,2 +1,12 @@
+pub mod conversation_order;
+pub mod handlers;
+#[cfg(test)]
+pub mod mock_omikron;
 pub mod omikron_connection;
+pub mod outbox;
 pub mod ping_pong_task;
+pub mod protocol;
+pub mod rate_limit;
+pub mod requests;
+pub mod retention_task;
+pub mod transport;
@@ -1,22 +1,24 @@
-use crate::users::contact::Contact;
-use crate::util::chat_files::{MessageState, change_message_state};
-use crate::util::chats_util::{get_user, mod_user};
-use crate::util::communities_util::CommunitiesUtil;
+use crate::omikron::conversation_order::Turn;
+use crate::omikron::handlers::{self, OmikronSender};
+use crate::omikron::protocol::{self, Negotiated};
+use crate::omikron::rate_limit;
+use crate::omikron::requests::RequestError;
+use crate::omikron::transport::{OmikronReceiver, OmikronTransport};
 use crate::util::crypto_util::{DataFormat, SecurePayload};
-use crate::util::file_util::{get_children, load_file, save_file};
-use crate::util::{chat_files, chats_util};
-use crate::util::{config_util::CONFIG, crypto_helper};
+use crate::util::repository::{STORE, SqliteStore};
+use crate::util::{config_util::CONFIG, crypto_helper, keystore};
 use crate::{ACTIVE_TASKS, SHUTDOWN, log, log_cv_in, log_cv_out, log_t};
+use async_trait::async_trait;
 use dashmap::DashMap;
 use json::JsonValue;
+use rand::Rng;
 use std::collections::HashMap;
 use std::sync::{Arc, LazyLock};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, RwLock, mpsc, watch};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
-use ttp_native::{Receiver, Sender};
 use uuid::Uuid;
 
 // ============================================================================
@@ -31,6 +33,60 @@ const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const TASK_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
 const TASK_MAX_AGE: Duration = Duration::from_secs(60);
+const FAILOVER_AFTER_FAILURES: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OmikronEndpoint {
+    pub host: String,
+    pub port: u16,
+}
+
+impl OmikronEndpoint {
+    pub fn new(host: &str, port: u16) -> Self {
+        OmikronEndpoint {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    /// Parses `host` or `host:port`, falling back to the default port.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return None;
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() => {
+                Some(Self::new(host, port.parse::<u16>().ok()?))
+            }
+            Some(_) => None,
+            None => Some(Self::new(s, OMIKRON_PORT_DEFAULT)),
+        }
+    }
+
+    /// Primary endpoint from `omikron_host`/`omikron_port` followed by `omikron_fallbacks`.
+    pub async fn from_config() -> Vec<Self> {
+        let conf = CONFIG.read().await;
+        let mut endpoints = vec![Self::new(
+            &conf
+                .get_omikron_host()
+                .unwrap_or(OMIKRON_HOST_DEFAULT.to_string()),
+            conf.get_omikron_port().unwrap_or(OMIKRON_PORT_DEFAULT),
+        )];
+        for fallback in conf.get_omikron_fallbacks() {
+            match Self::parse(&fallback) {
+                Some(endpoint) if !endpoints.contains(&endpoint) => endpoints.push(endpoint),
+                Some(_) => {}
+                None => log!("Ignoring invalid Omikron fallback '{}'", fallback),
+            }
+        }
+        endpoints
+    }
+
+    pub fn url(&self) -> String {
+        format!("https://{}:{}/ws/iota/", self.host, self.port)
+    }
+}
 
 // ============================================================================
 // Waiting Task System
@@ -38,6 +94,9 @@ const TASK_MAX_AGE: Duration = Duration::from_secs(60);
 
 pub struct WaitingTask {
     pub task: Box<dyn Fn(Arc<OmikronConnection>, CommunicationValue) -> bool + Send + Sync>,
+    /// Called instead of `task` when the connection drops before an answer
+    /// arrives. Without it, `task` receives a synthetic `error` message.
+    pub on_disconnect: Option<Box<dyn Fn(String) + Send + Sync>>,
     pub inserted_at: Instant,
 }
 
@@ -73,47 +132,128 @@ impl ConnectionState {
     }
 }
 
+/// Snapshot published on every connection state transition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    /// Connection attempts since the last successful identification.
+    pub attempt: u32,
+    pub last_error: Option<String>,
+    pub endpoint: Option<OmikronEndpoint>,
+}
+
+impl ConnectionStatus {
+    fn new() -> Self {
+        ConnectionStatus {
+            state: ConnectionState::Disconnected,
+            attempt: 0,
+            last_error: None,
+            endpoint: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.state {
+            ConnectionState::Connected { identified: true } => write!(f, "online")?,
+            ConnectionState::Connected { identified: false } => write!(f, "authenticating")?,
+            ConnectionState::Connecting => write!(f, "connecting #{}", self.attempt)?,
+            ConnectionState::Disconnected => write!(f, "offline")?,
+        }
+        if let Some(endpoint) = &self.endpoint {
+            write!(f, " {}:{}", endpoint.host, endpoint.port)?;
+        }
+        if !self.state.is_identified() {
+            if let Some(e) = &self.last_error {
+                write!(f, " ({})", e)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Picks a delay in `[delay / 2, delay]` so Iotas that lost Omikron at the
+/// same moment don't all reconnect in lockstep.
+fn with_jitter(delay: Duration) -> Duration {
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+}
+
+/// Logs every state transition of `conn`.
+fn start_status_logger(conn: &OmikronConnection) {
+    let mut status_rx = conn.subscribe();
+    tokio::spawn(async move {
+        let mut last_state = status_rx.borrow_and_update().state;
+        while status_rx.changed().await.is_ok() {
+            let status = status_rx.borrow_and_update().clone();
+            if status.state != last_state {
+                last_state = status.state;
+                log!("Omikron: {}", status);
+            }
+        }
+    });
+}
+
 // ============================================================================
 // Omikron Connection (Client-side with auto-reconnect)
 // ============================================================================
 
 pub struct OmikronConnection {
-    state: Arc<RwLock<ConnectionState>>,
-    sender: Arc<RwLock<Option<Arc<Sender>>>>,
+    status: watch::Sender<ConnectionStatus>,
+    sender: Arc<RwLock<Option<Arc<dyn OmikronTransport>>>>,
     connection_loop_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
-    host: String,
-    port: u16,
+    /// Fixed endpoints; when empty they are read from the config on every (re)start.
+    endpoints: Vec<OmikronEndpoint>,
     pub last_ping: Arc<Mutex<i64>>,
     heartbeat_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
     message_send_times: Arc<Mutex<HashMap<Uuid, Instant>>>,
     pub connection_id: Uuid,
     shutdown_tx: Arc<Mutex<Option<watch::Sender<bool>>>>,
     reconnect_on_close: Arc<RwLock<bool>>,
+    /// Version and features agreed on in the current session.
+    negotiated: Arc<RwLock<Option<Negotiated>>>,
+    /// Where handlers store; `STORE` unless a test brings its own.
+    store: Option<SqliteStore>,
 }
 
 impl OmikronConnection {
     pub fn new() -> Self {
-        Self::with_host(OMIKRON_HOST_DEFAULT, OMIKRON_PORT_DEFAULT)
+        Self::with_endpoints(Vec::new())
     }
 
+    #[allow(dead_code)]
     pub fn with_host(host: &str, port: u16) -> Self {
+        Self::with_endpoints(vec![OmikronEndpoint::new(host, port)])
+    }
+
+    pub fn with_endpoints(endpoints: Vec<OmikronEndpoint>) -> Self {
         let (shutdown_tx, _) = watch::channel(false);
+        let (status, _) = watch::channel(ConnectionStatus::new());
 
         OmikronConnection {
-            state: Arc::new(RwLock::new(ConnectionState::Disconnected)),
+            status,
             sender: Arc::new(RwLock::new(None)),
             connection_loop_handle: Arc::new(Mutex::new(None)),
-            host: host.to_string(),
-            port,
+            endpoints,
             last_ping: Arc::new(Mutex::new(-1)),
             heartbeat_handle: Arc::new(Mutex::new(None)),
             message_send_times: Arc::new(Mutex::new(HashMap::new())),
             connection_id: Uuid::new_v4(),
             shutdown_tx: Arc::new(Mutex::new(Some(shutdown_tx))),
             reconnect_on_close: Arc::new(RwLock::new(true)),
+            negotiated: Arc::new(RwLock::new(None)),
+            store: None,
         }
     }
 
+    /// Has the handlers of this connection use `store` instead of `STORE`.
+    #[cfg(test)]
+    pub fn with_store(mut self, store: SqliteStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
     // -------------------------------------------------------------------------
     // Connection Management
     // -------------------------------------------------------------------------
@@ -158,15 +298,51 @@ impl OmikronConnection {
             sender.close();
         }
 
-        *self.state.write().await = ConnectionState::Disconnected;
+        self.set_state(ConnectionState::Disconnected);
         *self.sender.write().await = None;
     }
 
+    /// Receiver for connection state transitions, starting at the current status.
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.borrow().clone()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        self.status.send_if_modified(|status| {
+            if status.state == state {
+                return false;
+            }
+            status.state = state;
+            if state.is_identified() {
+                status.attempt = 0;
+            }
+            true
+        });
+    }
+
+    fn set_last_error(&self, error: String) {
+        self.status
+            .send_modify(|status| status.last_error = Some(error));
+    }
+
     async fn connection_loop(self: Arc<Self>) {
         let mut reconnect_delay = RECONNECT_DELAY;
         let shutdown_rx = self.shutdown_tx.lock().await.as_ref().unwrap().subscribe();
         let mut shutdown_rx = shutdown_rx;
 
+        let endpoints = if self.endpoints.is_empty() {
+            OmikronEndpoint::from_config().await
+        } else {
+            self.endpoints.clone()
+        };
+        let mut endpoint_index = 0;
+        let mut failures = 0;
+        let mut attempt = 0;
+
         loop {
             if *shutdown_rx.borrow() || *SHUTDOWN.read().await {
                 log_t!("omikron_connection_loop_shutdown");
@@ -177,25 +353,55 @@ impl OmikronConnection {
                 break;
             }
 
-            match self.clone().connect_once().await {
+            let endpoint = &endpoints[endpoint_index];
+            attempt += 1;
+            self.status.send_modify(|status| {
+                status.attempt = attempt;
+                status.endpoint = Some(endpoint.clone());
+            });
+
+            let delay;
+            match self.clone().connect_once(endpoint).await {
                 Ok(()) => {
+                    failures = 0;
+                    attempt = 0;
+                    reconnect_delay = RECONNECT_DELAY;
+                    delay = with_jitter(reconnect_delay);
                     if *self.reconnect_on_close.read().await {
-                        log!("Connection lost, reconnecting in {:?}...", reconnect_delay);
+                        log!("Connection lost, reconnecting in {:?}...", delay);
                     } else {
                         break;
                     }
                 }
                 Err(e) => {
+                    self.set_last_error(e.clone());
+                    failures += 1;
+                    if failures >= FAILOVER_AFTER_FAILURES && endpoints.len() > 1 {
+                        failures = 0;
+                        reconnect_delay = RECONNECT_DELAY;
+                        endpoint_index = (endpoint_index + 1) % endpoints.len();
+                        log!(
+                            "Connection to {}:{} failed: {}, failing over to {}:{}...",
+                            endpoint.host,
+                            endpoint.port,
+                            e,
+                            endpoints[endpoint_index].host,
+                            endpoints[endpoint_index].port
+                        );
+                        continue;
+                    }
+                    delay = with_jitter(reconnect_delay);
                     log!(
-                        "Connection failed: {}, retrying in {:?}...",
+                        "Connection failed: {}, retrying in {:?} (attempt {})...",
                         e,
-                        reconnect_delay
+                        delay,
+                        attempt
                     );
                 }
             }
 
             tokio::select! {
-                _ = sleep(reconnect_delay) => {}
+                _ = sleep(delay) => {}
                 _ = shutdown_rx.changed() => {
                     if *shutdown_rx.borrow() {
                         break;
@@ -207,21 +413,40 @@ impl OmikronConnection {
         }
     }
 
-    async fn connect_once(self: Arc<Self>) -> Result<(), String> {
-        *self.state.write().await = ConnectionState::Connecting;
+    /// Runs one session against `endpoint`. Returns `Ok` once an identified
+    /// session ends and `Err` when no session could be established or it
+    /// ended before Omikron identified the Iota, so a flapping endpoint
+    /// keeps backing off and is failed over like an unreachable one.
+    async fn connect_once(self: Arc<Self>, endpoint: &OmikronEndpoint) -> Result<(), String> {
+        self.set_state(ConnectionState::Connecting);
         log_t!("omikron_connecting");
 
-        let addr_str = format!("https://{}:{}/ws/iota/", self.host, self.port);
-
-        let (sender, mut receiver) = ttp_native::client::connect(&addr_str, None)
-            .await
-            .map_err(|e| format!("Connection failed: {}", e))?;
+        let (sender, receiver) = match ttp_native::client::connect(&endpoint.url(), None).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                self.set_state(ConnectionState::Disconnected);
+                return Err(format!("Connection failed: {}", e));
+            }
+        };
 
         log_t!("omikron_connection_success");
 
-        let sender_arc = Arc::new(sender);
-        *self.sender.write().await = Some(sender_arc.clone());
-        *self.state.write().await = ConnectionState::Connected { identified: false };
+        if self.run_session(Arc::new(sender), Box::new(receiver)).await {
+            Ok(())
+        } else {
+            Err("Session ended before identification".to_string())
+        }
+    }
+
+    /// Authenticates over an established transport and serves it until it
+    /// closes. Returns whether the Iota was identified in the session.
+    pub(crate) async fn run_session(
+        self: Arc<Self>,
+        sender: Arc<dyn OmikronTransport>,
+        mut receiver: Box<dyn OmikronReceiver>,
+    ) -> bool {
+        *self.sender.write().await = Some(sender);
+        self.set_state(ConnectionState::Connected { identified: false });
 
         // Handle registration/identification
         self.handle_authentication().await;
@@ -229,7 +454,7 @@ impl OmikronConnection {
         // Start read loop
         let read_self = self.clone();
         let read_handle = tokio::spawn(async move {
-            read_self.read_loop(&mut receiver).await;
+            read_self.read_loop(receiver.as_mut()).await;
         });
 
         // Start heartbeat
@@ -246,7 +471,8 @@ impl OmikronConnection {
         // Wait for read loop to complete
         let result = read_handle.await;
         *self.sender.write().await = None;
-        *self.state.write().await = ConnectionState::Disconnected;
+        let identified = self.negotiated.write().await.take().is_some();
+        self.set_state(ConnectionState::Disconnected);
         {
             ACTIVE_TASKS.remove("Omikron Listener");
         }
@@ -255,16 +481,10 @@ impl OmikronConnection {
             handle.abort();
         }
 
-        match result {
-            Ok(()) => {
-                if *self.reconnect_on_close.read().await {
-                    Err("Connection closed, will reconnect".to_string())
-                } else {
-                    Ok(())
-                }
-            }
-            Err(e) => Err(format!("Read loop error: {}", e)),
+        if let Err(e) = result {
+            log!("Read loop error: {}", e);
         }
+        identified
     }
 
     // -------------------------------------------------------------------------
@@ -272,32 +492,42 @@ impl OmikronConnection {
     // -------------------------------------------------------------------------
 
     async fn handle_authentication(&self) {
-        let conf = CONFIG.read().await;
-        let iota_id = conf.get_iota_id();
-        let public_key = conf.get_public_key();
-        let private_key = conf.get_private_key();
-        drop(conf);
+        let iota_id = CONFIG.read().await.get_iota_id();
 
-        if iota_id == 0 || public_key.is_none() || private_key.is_none() {
-            log_t!("iota_register_new");
+        let (public_key_base64, generated) = match keystore::get_keys() {
+            Some(keys) => (keys.public_key, false),
+            None if keystore::has_keystore() => {
+                // A keystore that failed to load must never be replaced silently.
+                log!("Keystore present but not loaded, refusing to register a new Iota");
+                return;
+            }
+            None if iota_id != 0 => {
+                log!(
+                    "Iota {} has no keystore, refusing to register a new Iota",
+                    iota_id
+                );
+                return;
+            }
+            None => {
+                let key_pair = crypto_helper::generate_keypair();
+                if let Err(e) = keystore::store(&key_pair) {
+                    log!("Failed to store Iota keypair: {}", e);
+                    return;
+                }
+                (crypto_helper::public_key_to_base64(&key_pair.public), true)
+            }
+        };
 
-            let key_pair = crypto_helper::generate_keypair();
-            let public_key_base64 = crypto_helper::public_key_to_base64(&key_pair.public);
-            let _private_key_base64 = crypto_helper::secret_key_to_base64(&key_pair.secret);
-
-            let mut conf_write = CONFIG.write().await;
-            // NOTE:
-            // Intentionally not storing the generated private/public keys directly into the
-            // config file here to avoid persisting sensitive material in plaintext. If you
-            // want to persist them, uncomment the two lines below and accept the security
-            // implications (they will be saved by `conf_write.update()`).
-            // conf_write.change("public_key", DataValue::Str(public_key_base64.clone()));
-            // conf_write.change("private_key", DataValue::Str(private_key_base64));
-            conf_write.update();
-            drop(conf_write);
+        // Fresh keys are only made for an unregistered Iota.
+        if iota_id == 0 || generated {
+            log_t!("iota_register_new");
 
             let register_msg = CommunicationValue::new(CommunicationType::register_iota)
-                .add_data(DataTypes::public_key, DataValue::Str(public_key_base64));
+                .add_data(DataTypes::public_key, DataValue::Str(public_key_base64))
+                .add_data(
+                    DataTypes::payload,
+                    DataValue::Str(protocol::advertisement()),
+                );
 
             let msg_id = register_msg.get_id();
 
@@ -321,25 +551,21 @@ impl OmikronConnection {
                                 log!("Registered with Iota-ID: {}", iota_id);
 
                                 // Send identification after registration
-                                let identify_msg =
-                                    CommunicationValue::new(CommunicationType::identification)
-                                        .add_data(DataTypes::iota_id, DataValue::Number(iota_id));
-                                selfc.send_message(&identify_msg).await;
+                                selfc.send_message(&identification(iota_id)).await;
                             });
                         } else {
                             log!("Iota registration failed.");
                         }
                         true
                     }),
+                    on_disconnect: None,
                     inserted_at: Instant::now(),
                 },
             );
 
             self.send_message(&register_msg).await;
         } else {
-            let identify_msg = CommunicationValue::new(CommunicationType::identification)
-                .add_data(DataTypes::iota_id, DataValue::Number(iota_id));
-            self.send_message(&identify_msg).await;
+            self.send_message(&identification(iota_id)).await;
         }
     }
 
@@ -347,7 +573,7 @@ impl OmikronConnection {
     // Read Loop & Heartbeat
     // -------------------------------------------------------------------------
 
-    async fn read_loop(self: Arc<Self>, receiver: &mut Receiver) {
+    async fn read_loop(self: Arc<Self>, receiver: &mut dyn OmikronReceiver) {
         loop {
             let result = receiver.receive().await;
             match result {
@@ -355,6 +581,7 @@ impl OmikronConnection {
                     self.clone().handle_message(cv).await;
                 }
                 Err(e) => {
+                    self.set_last_error(e.clone());
                     self.fail_all_waiting_tasks(format!(
                         "Connection receive error: {} (connection_id={})",
                         e, self.connection_id
@@ -378,7 +605,7 @@ impl OmikronConnection {
         loop {
             sleep(HEARTBEAT_INTERVAL).await;
 
-            if !self.state.read().await.is_connected() {
+            if !self.status.borrow().state.is_connected() {
                 break;
             }
 
@@ -395,7 +622,7 @@ impl OmikronConnection {
     }
 
     // -------------------------------------------------------------------------
-    // Message Handling (Preserved from original)
+    // Message Handling
     // -------------------------------------------------------------------------
 
     pub async fn handle_message(self: Arc<Self>, cv: CommunicationValue) {
@@ -424,528 +651,79 @@ impl OmikronConnection {
 
         if cv.is_type(CommunicationType::identification_response) {
             if let Some(_accepted) = cv.get_data(DataTypes::accepted).as_bool() {
-                let mut state = self.state.write().await;
-                if let ConnectionState::Connected { identified: _ } = *state {
-                    *state = ConnectionState::Connected { identified: true };
-                }
-            }
-            return;
-        }
-
-        // ************************************************ //
-        // Direct messages                                  //
-        // ************************************************ //
-
-        if cv.is_type(CommunicationType::message_state) {
-            let sender_id = &cv.get_sender();
-            let receiver_id = &cv.get_receiver();
-
-            // Parse send_time robustly: accept numeric or string, fallback to current time
-            let send_time_val = cv.get_data(DataTypes::send_time);
-            let now_i64 = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as i64;
-            let timestamp_i64 = if let Some(n) = send_time_val.as_number() {
-                n as i64
-            } else if let Some(s) = send_time_val.as_str() {
-                s.parse::<i64>().unwrap_or(now_i64)
-            } else {
-                now_i64
-            };
-
-            let _ = chat_files::change_message_state(
-                timestamp_i64,
-                *receiver_id as i64,
-                *sender_id as i64,
-                MessageState::from_str(
-                    cv.get_data(DataTypes::message_state).as_str().unwrap_or(""),
-                ),
-            );
-        }
-
-        // Incoming stored message: store for the recipient, attempt local delivery, notify sender.
-        if cv.is_type(CommunicationType::message_send) {
-            let sender_id: i64 = if let Some(n) = cv.get_data(DataTypes::sender_id).as_number() {
-                n as i64
-            } else if let Some(s) = cv.get_data(DataTypes::sender_id).as_str() {
-                s.parse::<i64>().unwrap_or(0)
-            } else {
-                0
-            };
-
-            // parse receiver_id (the storage owner for this incoming message)
-            let receiver_id: i64 = if let Some(n) = cv.get_data(DataTypes::receiver_id).as_number()
-            {
-                n as i64
-            } else if let Some(s) = cv.get_data(DataTypes::receiver_id).as_str() {
-                s.parse::<i64>().unwrap_or(0)
-            } else {
-                0
-            };
-
-            // parse send_time robustly (number or string), fallback to now
-            let send_time_val = cv.get_data(DataTypes::send_time);
-            let now_i64 = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as i64;
-            let timestamp_i64 = if let Some(n) = send_time_val.as_number() {
-                n as i64
-            } else if let Some(s) = send_time_val.as_str() {
-                s.parse::<i64>().unwrap_or(now_i64)
-            } else {
-                now_i64
-            };
-            let timestamp_u128 = timestamp_i64 as u128;
-
-            // content may be missing; default to empty string
-            let content = cv
-                .get_data(DataTypes::content)
-                .as_str()
-                .unwrap_or("")
-                .to_string();
-
-            let height = cv.get_data(DataTypes::height).as_number().unwrap_or(0) as i64;
-
-            // persist message for the receiver (storage_owner = receiver_id)
-            chat_files::add_message(
-                timestamp_u128,
-                false,
-                receiver_id as i64,
-                sender_id as i64,
-                &content,
-                height,
-            );
-
-            // persist message for the sender (storage_owner = sender_id)
-            chat_files::add_message(
-                timestamp_u128,
-                true,
-                sender_id as i64,
-                receiver_id as i64,
-                &content,
-                height,
-            );
-
-            // send confirmation back to sender
-            let conf_msg = CommunicationValue::new(CommunicationType::message_send)
-                .with_id(cv.get_id())
-                .with_receiver(sender_id as u64);
-            self.send_message(&conf_msg).await;
-
-            // Build a live-delivery message for the local client (recipient)
-            let user_forward = CommunicationValue::new(CommunicationType::message_live)
-                .with_id(cv.get_id())
-                .with_receiver(receiver_id as u64)
-                .add_data(DataTypes::send_time, DataValue::Number(timestamp_i64))
-                .add_data(DataTypes::content, DataValue::Str(content.clone()))
-                .add_data(DataTypes::sender_id, DataValue::Number(sender_id as i64))
-                .add_data(DataTypes::height, DataValue::Number(height));
-
-            // Attempt delivery and await a response from the local client
-            let user_resp = self
-                .clone()
-                .await_response(&user_forward, Some(Duration::from_secs(10)))
-                .await;
-
-            if let Ok(user_resp) = user_resp {
-                let ms_raw = user_resp
-                    .get_data(DataTypes::message_state)
-                    .as_string()
-                    .unwrap_or_else(|| "".to_string());
-                let ms = MessageState::from_str(&ms_raw).upgrade(MessageState::Received);
-
-                // update stored message state for receiver
-                let _ = chat_files::change_message_state(
-                    timestamp_i64,
-                    receiver_id as i64,
-                    sender_id as i64,
-                    ms.clone(),
-                );
-
-                // update stored message state for sender
-                let _ = chat_files::change_message_state(
-                    timestamp_i64,
-                    sender_id as i64,
-                    receiver_id as i64,
-                    ms.clone(),
-                );
-
-                // notify original sender about the delivered/read state
-                self.send_message(
-                    &CommunicationValue::new(CommunicationType::message_state)
-                        .with_id(cv.get_id())
-                        .with_receiver(sender_id as u64)
-                        .with_sender(receiver_id as u64)
-                        .add_data(DataTypes::send_time, DataValue::Number(timestamp_i64))
-                        .add_data(
-                            DataTypes::message_state,
-                            DataValue::Str(ms.as_str().to_string()),
-                        ),
-                )
-                .await;
-            } else {
-                // Delivery failed or timed out; mark as Sent
-                let _ = chat_files::change_message_state(
-                    timestamp_i64,
-                    receiver_id as i64,
-                    sender_id as i64,
-                    MessageState::Sent,
-                );
-
-                let _ = chat_files::change_message_state(
-                    timestamp_i64,
-                    sender_id as i64,
-                    receiver_id as i64,
-                    MessageState::Sent,
-                );
-
-                // notify sender
-                self.send_message(
-                    &CommunicationValue::new(CommunicationType::message_state)
-                        .with_id(cv.get_id())
-                        .with_receiver(sender_id as u64)
-                        .with_sender(receiver_id as u64)
-                        .add_data(DataTypes::send_time, DataValue::Number(timestamp_i64))
-                        .add_data(
-                            DataTypes::message_state,
-                            DataValue::Str(MessageState::Sent.as_str().to_string()),
-                        ),
-                )
-                .await;
-            }
-            return;
-        }
-
-        if cv.is_type(CommunicationType::message_other_iota) {
-            let sender_id = &cv.get_sender();
-            let receiver_id = &cv.get_receiver();
-
-            // parse send_time safely (number or string), fallback to now
-            let send_time_val = cv.get_data(DataTypes::send_time);
-            let now_i64 = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as i64;
-            let timestamp = if let Some(n) = send_time_val.as_number() {
-                n as i64
-            } else if let Some(s) = send_time_val.as_str() {
-                s.parse::<i64>().unwrap_or(now_i64)
-            } else {
-                now_i64
-            };
-
-            // content may be missing or non-string; default to empty string
-            let content = cv
-                .get_data(DataTypes::content)
-                .as_str()
-                .unwrap_or("")
-                .to_string();
-
-            let height = cv.get_data(DataTypes::height).as_number().unwrap_or(0) as i64;
-
-            chat_files::add_message(
-                timestamp as u128,
-                false,
-                *receiver_id as i64,
-                *sender_id as i64,
-                &content,
-                height,
-            );
-
-            // Build user_forward using the parsed numeric timestamp and safe content string
-            let user_forward = CommunicationValue::new(CommunicationType::message_live)
-                .with_id(cv.get_id())
-                .with_receiver(*receiver_id)
-                .add_data(DataTypes::send_time, DataValue::Number(timestamp))
-                .add_data(DataTypes::content, DataValue::Str(content.clone()))
-                .add_data(DataTypes::sender_id, DataValue::Number(*sender_id as i64))
-                .add_data(DataTypes::height, DataValue::Number(height));
-
-            let user_resp = self
-                .clone()
-                .await_response(&user_forward, Some(Duration::from_secs(10)))
-                .await;
-
-            if let Ok(user_resp) = user_resp {
-                let ms_raw = user_resp
-                    .get_data(DataTypes::message_state)
-                    .as_string()
-                    .unwrap_or_else(|| "".to_string());
-                let ms = MessageState::from_str(&ms_raw).upgrade(MessageState::Received);
-
-                let _ = change_message_state(
-                    timestamp,
-                    *receiver_id as i64,
-                    *sender_id as i64,
-                    ms.clone(),
-                );
-
-                self.send_message(
-                    &CommunicationValue::new(CommunicationType::message_state)
-                        .with_id(cv.get_id())
-                        .with_receiver(*sender_id)
-                        .with_sender(*receiver_id)
-                        .add_data(DataTypes::send_time, DataValue::Number(timestamp))
-                        .add_data(
-                            DataTypes::message_state,
-                            DataValue::Str(ms.as_str().to_string()),
-                        ),
-                )
-                .await;
-            } else {
-                // Delivery timed out/failed — update stored state and notify sender with numeric timestamp
-                let _ = chat_files::change_message_state(
-                    timestamp,
-                    *receiver_id as i64,
-                    *sender_id as i64,
-                    MessageState::Sent,
-                );
-
-                self.send_message(
-                    &CommunicationValue::new(CommunicationType::message_state)
-                        .with_id(cv.get_id())
-                        .with_receiver(*sender_id)
-                        .with_sender(*receiver_id)
-                        .add_data(DataTypes::send_time, DataValue::Number(timestamp))
-                        .add_data(
-                            DataTypes::message_state,
-                            DataValue::Str(MessageState::Sent.as_str().to_string()),
-                        ),
-                )
-                .await;
-            }
-            return;
-        }
-
-        if cv.is_type(CommunicationType::messages_get) {
-            let my_id = cv.get_sender();
-            let partner_id = cv.get_data(DataTypes::user_id).as_number().unwrap_or(0);
-            let offset = cv.get_data(DataTypes::offset).as_number().unwrap_or(0);
-            let amount = cv.get_data(DataTypes::amount).as_number().unwrap_or(0);
-            let messages = chat_files::get_messages(my_id as i64, partner_id, offset, amount);
-            let mut msg_array: Vec<DataValue> = Vec::new();
-            for m in messages.members() {
-                let message_time: i64 = m["message_time"].as_i64().unwrap_or(0);
-                let content: String = m["content"].as_str().unwrap_or("").to_string();
-                let sent_by_self: bool = m["sent_by_self"].as_bool().unwrap_or(false);
-                let height: i64 = m["height"].as_i64().unwrap_or(0);
-                let sender_id: i64 = if sent_by_self {
-                    my_id as i64
-                } else {
-                    if let Some(n) = cv.get_data(DataTypes::chat_partner_id).as_number() {
-                        n as i64
-                    } else if let Some(s) = cv.get_data(DataTypes::chat_partner_id).as_str() {
-                        s.parse::<i64>().unwrap_or(partner_id as i64)
-                    } else {
-                        partner_id as i64
+                let negotiated = match protocol::negotiate(&cv) {
+                    Ok(negotiated) => negotiated,
+                    Err(e) => {
+                        self.refuse(e).await;
+                        return;
                     }
                 };
-                let message_state: String = m["message_state"].as_str().unwrap_or("").to_string();
-
-                let mut container = Vec::new();
-                container.push((DataTypes::send_time, DataValue::Number(message_time)));
-                container.push((DataTypes::content, DataValue::Str(content)));
-                container.push((DataTypes::sender_id, DataValue::Number(sender_id)));
-                container.push((DataTypes::message_state, DataValue::Str(message_state)));
-                container.push((DataTypes::height, DataValue::Number(height)));
-                container.push((DataTypes::sent_by_self, DataValue::Bool(sent_by_self)));
-                msg_array.push(DataValue::Container(container));
-            }
-
-            let resp = CommunicationValue::new(CommunicationType::messages_get)
-                .with_id(cv.get_id())
-                .with_receiver(my_id)
-                .add_data(DataTypes::messages, DataValue::Array(msg_array));
+                let missing = negotiated.missing();
+                if !missing.is_empty() {
+                    log!(
+                        "Omikron protocol {} lacks {}, continuing without",
+                        negotiated.version,
+                        missing.join(", ")
+                    );
+                }
+                *self.negotiated.write().await = Some(negotiated);
 
-            self.send_message(&resp).await;
-            return;
-        }
+                self.status.send_if_modified(|status| {
+                    if status.state != (ConnectionState::Connected { identified: false }) {
+                        return false;
+                    }
+                    status.state = ConnectionState::Connected { identified: true };
+                    status.attempt = 0;
+                    true
+                });
 
-        if cv.is_type(CommunicationType::get_chats) {
-            let user_id = cv.get_sender();
-            let users = chats_util::get_users(user_id as i64);
-            let mut user_array = Vec::new();
-            for user in users {
-                let mut container = Vec::new();
-                container.push((DataTypes::user_id, DataValue::Number(user.user_id)));
-                if let Some(name) = user.user_name {
-                    container.push((DataTypes::username, DataValue::Str(name)));
-                }
-                if let Some(ts) = user.last_message_at {
-                    container.push((DataTypes::last_message_at, DataValue::Number(ts)));
-                }
-                user_array.push(DataValue::Container(container));
+                // Authentication is complete; replay whatever piled up while offline.
+                self.flush_outbox().await;
             }
-            let resp = CommunicationValue::new(CommunicationType::get_chats)
-                .with_id(cv.get_id())
-                .with_receiver(user_id)
-                .add_data(DataTypes::user_ids, DataValue::Array(user_array));
-            self.send_message(&resp).await;
             return;
         }
 
-        if cv.is_type(CommunicationType::add_conversation) {
-            let user_id = cv.get_sender();
-            let other_id = match cv.get_data(DataTypes::chat_partner_id).as_number() {
-                Some(n) => n as i64,
-                None => cv
-                    .get_data(DataTypes::chat_partner_id)
-                    .as_str()
-                    .unwrap_or("0")
-                    .parse()
-                    .unwrap_or(0),
-            };
-            let mut contact = get_user(user_id as i64, other_id).unwrap_or(Contact::new(other_id));
-
-            if let Some(name) = cv.get_data(DataTypes::chat_partner_name).as_str() {
-                contact.user_name = Some(name.to_string());
+        // Handlers run detached: they may await a client reply, which can only
+        // arrive once the read loop is free to receive it. Messages of one
+        // conversation still run in the order they arrived.
+        if let Some(handler) = handlers::get_handler(&cv) {
+            if !rate_limit::allow(&cv).await {
+                self.send_message(&rate_limit::rejection(&cv)).await;
+                return;
             }
-
-            contact.set_last_message_at(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis() as i64,
-            );
-            mod_user(user_id as i64, &contact);
-            let resp = CommunicationValue::new(CommunicationType::add_conversation)
-                .with_id(cv.get_id())
-                .with_receiver(user_id);
-            self.send_message(&resp).await;
-            return;
-        }
-
-        if cv.is_type(CommunicationType::add_community) {
-            CommunitiesUtil::add_community(
-                cv.get_sender() as i64,
-                cv.get_data(DataTypes::community_address)
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-                cv.get_data(DataTypes::community_title)
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-                cv.get_data(DataTypes::position)
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-            );
-            let resp = CommunicationValue::new(CommunicationType::add_community)
-                .with_id(cv.get_id())
-                .with_receiver(cv.get_sender());
-            self.send_message(&resp).await;
-            return;
-        }
-
-        if cv.is_type(CommunicationType::get_communities) {
-            let mut comm_array = Vec::new();
-            for c in CommunitiesUtil::get_communities(cv.get_sender() as i64) {
-                let mut container: Vec<(DataTypes, DataValue)> = Vec::new();
-                if let Some(address) = c["address"].as_str() {
-                    container.push((
-                        DataTypes::community_address,
-                        DataValue::Str(address.to_string()),
-                    ));
+            let mut turn = Turn::take(&cv);
+            let sender: Arc<dyn OmikronSender> = self.clone();
+            tokio::spawn(async move {
+                if let Some(turn) = turn.as_mut() {
+                    turn.wait().await;
                 }
-                if let Some(title) = c["title"].as_str() {
-                    container.push((
-                        DataTypes::community_title,
-                        DataValue::Str(title.to_string()),
-                    ));
+                if let Some(user_id) = handlers::migrated_user(&cv) {
+                    let reason = format!("user {} has moved to another Iota", user_id);
+                    handlers::reject(&sender, &cv, &reason).await;
+                    return;
                 }
-                if let Some(position) = c["position"].as_str() {
-                    container.push((DataTypes::position, DataValue::Str(position.to_string())));
-                }
-                comm_array.push(DataValue::Container(container));
-            }
-
-            let resp = CommunicationValue::new(CommunicationType::get_communities)
-                .with_id(cv.get_id())
-                .with_receiver(cv.get_sender())
-                .add_data(DataTypes::communities, DataValue::Array(comm_array));
-            self.send_message(&resp).await;
-            return;
-        }
-
-        if cv.is_type(CommunicationType::remove_community) {
-            CommunitiesUtil::remove_community(
-                cv.get_sender() as i64,
-                cv.get_data(DataTypes::community_address)
-                    .as_str()
-                    .unwrap()
-                    .to_string(),
-            );
-            let resp = CommunicationValue::new(CommunicationType::remove_community)
-                .with_id(cv.get_id())
-                .with_receiver(cv.get_sender());
-            self.send_message(&resp).await;
-            return;
+                handler.handle(sender, cv).await;
+            });
         }
+    }
 
-        if cv.is_type(CommunicationType::settings_save) {
-            let my_id = cv.get_sender();
-            let settings_name = cv.get_data(DataTypes::settings_name).as_str().unwrap();
-            let settings_value = cv.get_data(DataTypes::payload).as_str().unwrap();
-
-            save_file(
-                &format!("users/{}/settings/", my_id),
-                &format!("{}.settings", settings_name),
-                &settings_value,
-            );
-
-            let response = CommunicationValue::new(CommunicationType::settings_save)
-                .with_receiver(my_id)
-                .with_id(cv.get_id());
-
-            self.send_message(&response).await;
-            return;
-        }
-
-        if cv.is_type(CommunicationType::settings_load) {
-            let my_id = cv.get_sender();
-            let settings_name = cv.get_data(DataTypes::settings_name).as_string().unwrap();
-            let settings_value_str = load_file(
-                &format!("users/{}/settings/", my_id),
-                &format!("{}.settings", settings_name),
-            );
-            let response = CommunicationValue::new(CommunicationType::settings_load)
-                .with_id(cv.get_id())
-                .with_receiver(my_id)
-                .add_data(DataTypes::payload, DataValue::Str(settings_value_str))
-                .add_data(DataTypes::settings_name, DataValue::Str(settings_name));
-
-            self.send_message(&response).await;
-            return;
-        }
-
-        if cv.is_type(CommunicationType::settings_list) {
-            let my_id = cv.get_sender();
-            let settings = get_children(&format!("users/{}/settings/", my_id));
-            let mut settings_json = Vec::new();
-            for s in settings {
-                let s = s.replace(".settings", "");
-                if s.is_empty() {
-                    continue;
-                }
-                let _ = settings_json.push(DataValue::Str(s));
-            }
-            let response = CommunicationValue::new(CommunicationType::settings_list)
-                .with_id(cv.get_id())
-                .with_receiver(my_id)
-                .add_data(DataTypes::settings, DataValue::Array(settings_json));
-
-            self.send_message(&response).await;
-            return;
+    /// Ends the session for good: reconnecting to an incompatible Omikron
+    /// would only fail the same way again.
+    async fn refuse(&self, reason: String) {
+        log!("Incompatible Omikron, disconnecting: {}", reason);
+        self.set_last_error(reason);
+        *self.reconnect_on_close.write().await = false;
+        if let Some(sender) = self.sender.read().await.as_ref() {
+            sender.close();
         }
     }
 
     async fn handle_challenge(&self, cv: &CommunicationValue) {
-        let conf = CONFIG.read().await;
-        let private_key = conf.get_private_key().unwrap();
-        drop(conf);
+        let Some(keys) = keystore::get_keys() else {
+            log!("Received challenge without a loaded keypair");
+            return;
+        };
+        let private_key = keys.private_key;
 
         let omikron_public_key = cv.get_data(DataTypes::public_key).as_str().unwrap();
         let encrypted_challenge = cv.get_data(DataTypes::challenge).as_str().unwrap();
@@ -986,10 +764,11 @@ impl OmikronConnection {
     pub async fn send_message(&self, cv: &CommunicationValue) {
         if let Err(err) = self.send_message_result(cv).await {
             log_t!("send_message_failed", err);
+            self.queue_message(cv).await;
         }
     }
 
-    async fn send_message_result(&self, cv: &CommunicationValue) -> Result<(), String> {
+    pub(crate) async fn send_message_result(&self, cv: &CommunicationValue) -> Result<(), String> {
         let sender_guard = self.sender.read().await;
         if let Some(sender) = sender_guard.as_ref() {
             if !sender.is_open() {
@@ -1032,6 +811,10 @@ impl OmikronConnection {
 
         for key in keys {
             if let Some((_, waiting_task)) = WAITING_TASKS.remove(&key) {
+                if let Some(on_disconnect) = &waiting_task.on_disconnect {
+                    on_disconnect(reason.clone());
+                    continue;
+                }
                 let response = CommunicationValue::new(CommunicationType::error)
                     .with_id(key)
                     .add_data(DataTypes::message, DataValue::Str(reason.clone()));
@@ -1041,11 +824,20 @@ impl OmikronConnection {
     }
 
     pub async fn is_connected(&self) -> bool {
-        self.state.read().await.is_connected()
+        self.status.borrow().state.is_connected()
     }
 
     pub async fn is_identified(&self) -> bool {
-        self.state.read().await.is_identified()
+        self.status.borrow().state.is_identified()
+    }
+
+    /// Whether the connected Omikron agreed on `feature`.
+    pub async fn omikron_supports(&self, feature: &str) -> bool {
+        self.negotiated
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|n| n.supports(feature))
     }
 
     pub async fn await_response(
@@ -1053,92 +845,120 @@ impl OmikronConnection {
         cv: &CommunicationValue,
         timeout_duration: Option<Duration>,
     ) -> Result<CommunicationValue, String> {
-        let (tx, mut rx) = mpsc::channel(1);
+        self.exchange(cv, timeout_duration)
+            .await
+            .map_err(|e| format!("{} (msg_id={})", e, cv.get_id()))
+    }
+
+    /// Sends `cv` and waits for the message carrying the same id. An `error`
+    /// reply from Omikron is returned as `RequestError::Remote`.
+    pub async fn exchange(
+        &self,
+        cv: &CommunicationValue,
+        timeout_duration: Option<Duration>,
+    ) -> Result<CommunicationValue, RequestError> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let disconnect_tx = tx.clone();
         let msg_id = cv.get_id();
 
         WAITING_TASKS.insert(
             msg_id,
             WaitingTask {
                 task: Box::new(move |_, response_cv| {
-                    let inner_tx = tx.clone();
-                    tokio::spawn(async move {
-                        let _ = inner_tx.send(response_cv).await;
-                    });
+                    let _ = tx.send(Ok(response_cv));
                     true
                 }),
+                on_disconnect: Some(Box::new(move |reason| {
+                    let _ = disconnect_tx.send(Err(RequestError::Disconnected(reason)));
+                })),
                 inserted_at: Instant::now(),
             },
         );
 
         if let Err(send_err) = self.send_message_result(cv).await {
             WAITING_TASKS.remove(&msg_id);
-            return Err(format!(
-                "Request send failed (msg_id={}, reason={})",
-                msg_id, send_err
-            ));
+            return Err(RequestError::Disconnected(send_err));
         }
 
         let timeout = timeout_duration.unwrap_or(Duration::from_secs(10));
 
         match tokio::time::timeout(timeout, rx.recv()).await {
-            Ok(Some(response_cv)) => {
+            Ok(Some(Ok(response_cv))) => {
                 if response_cv.is_type(CommunicationType::error) {
-                    let reason = response_cv
-                        .get_data(DataTypes::message)
-                        .as_str()
-                        .unwrap_or("connection error")
-                        .to_string();
-                    Err(format!(
-                        "Request failed due to disconnect (msg_id={}, reason={})",
-                        msg_id, reason
+                    Err(RequestError::Remote(
+                        response_cv
+                            .get_data(DataTypes::message)
+                            .as_str()
+                            .unwrap_or("error")
+                            .to_string(),
                     ))
                 } else {
                     Ok(response_cv)
                 }
             }
-            Ok(_) => {
+            Ok(Some(Err(e))) => Err(e),
+            Ok(None) => {
                 WAITING_TASKS.remove(&msg_id);
-                Err("Channel closed while awaiting response".to_string())
+                Err(RequestError::Disconnected(
+                    "channel closed while awaiting response".to_string(),
+                ))
             }
             Err(_) => {
-                let waiting_tasks_len = WAITING_TASKS.len();
                 WAITING_TASKS.remove(&msg_id);
-                Err(format!(
-                    "Request timed out (msg_id={}, timeout={}s, connected={}, waiting_tasks={})",
-                    msg_id,
-                    timeout.as_secs(),
-                    self.is_connected().await,
-                    waiting_tasks_len
-                ))
+                Err(RequestError::Timeout(timeout))
             }
         }
     }
 
     pub async fn await_connection(&self, timeout_duration: Option<Duration>) -> Result<(), String> {
-        if self.state.read().await.is_connected() {
-            return Ok(());
-        }
-
         let timeout = timeout_duration.unwrap_or(CONNECTION_TIMEOUT);
-        let start = Instant::now();
+        let mut status_rx = self.subscribe();
 
-        loop {
-            if self.state.read().await.is_connected() {
-                return Ok(());
-            }
+        match tokio::time::timeout(
+            timeout,
+            status_rx.wait_for(|status| status.state.is_connected()),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(_)) => Err("Connection was dropped".to_string()),
+            Err(_) => Err(format!(
+                "Connection not established within {} seconds ({})",
+                timeout.as_secs(),
+                self.status()
+            )),
+        }
+    }
+}
 
-            if start.elapsed() >= timeout {
-                return Err(format!(
-                    "Connection not established within {} seconds",
-                    timeout.as_secs()
-                ));
-            }
+#[async_trait]
+impl OmikronSender for OmikronConnection {
+    async fn send_message(&self, cv: &CommunicationValue) {
+        OmikronConnection::send_message(self, cv).await
+    }
 
-            sleep(Duration::from_millis(100)).await;
-        }
+    async fn await_response(
+        &self,
+        cv: &CommunicationValue,
+        timeout_duration: Option<Duration>,
+    ) -> Result<CommunicationValue, String> {
+        OmikronConnection::await_response(self, cv, timeout_duration).await
+    }
+
+    fn store(&self) -> &SqliteStore {
+        self.store.as_ref().unwrap_or(&*STORE)
     }
 }
 
+fn identification(iota_id: i64) -> CommunicationValue {
+    CommunicationValue::new(CommunicationType::identification)
+        .add_data(DataTypes::iota_id, DataValue::Number(iota_id))
+        .add_data(
+            DataTypes::payload,
+            DataValue::Str(protocol::advertisement()),
+        )
+}
+
 // ============================================================================
 // Global Instance
 // ============================================================================
@@ -1147,6 +967,7 @@ pub static OMIKRON_CONNECTION: LazyLock<Arc<OmikronConnection>> = LazyLock::new(
     let conn = Arc::new(OmikronConnection::new());
 
     start_task_cleanup_loop();
+    start_status_logger(&conn);
 
     conn
 });
@@ -1157,3 +978,35 @@ pub async fn get_omikron_connection() -> Arc<OmikronConnection> {
     conn.connect().await;
     conn
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{OMIKRON_PORT_DEFAULT, OmikronEndpoint, with_jitter};
+    use std::time::Duration;
+
+    #[test]
+    fn endpoint_parse_accepts_host_with_optional_port() {
+        assert_eq!(
+            OmikronEndpoint::parse("staging.example.net:1959"),
+            Some(OmikronEndpoint::new("staging.example.net", 1959))
+        );
+        assert_eq!(
+            OmikronEndpoint::parse(" staging.example.net "),
+            Some(OmikronEndpoint::new(
+                "staging.example.net",
+                OMIKRON_PORT_DEFAULT
+            ))
+        );
+        assert_eq!(OmikronEndpoint::parse("host:notaport"), None);
+        assert_eq!(OmikronEndpoint::parse(":959"), None);
+        assert_eq!(OmikronEndpoint::parse(""), None);
+    }
+
+    #[test]
+    fn jitter_stays_within_half_and_full_delay() {
+        for _ in 0..100 {
+            let delay = with_jitter(Duration::from_secs(8));
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::log;
+use crate::omikron::omikron_connection::OmikronConnection;
+use crate::omikron::protocol::FEATURE_OUTBOX_REPLAY;
+use crate::util::config_util::CONFIG;
+use crate::util::outbox_util;
+use json::{JsonValue, object};
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+/// The only messages the outbox keeps: state changes and send
+/// confirmations. Everything else either belongs to one connection or may
+/// carry message content, which must not end up on disk in plaintext.
+const QUEUEABLE_TYPES: [CommunicationType; 2] = [
+    CommunicationType::message_state,
+    CommunicationType::message_send,
+];
+
+/// The fields a queued message may carry.
+const QUEUEABLE_DATA: [DataTypes; 2] = [DataTypes::send_time, DataTypes::message_state];
+
+/// How long a queued message stays valid unless `outbox_expiry` says otherwise.
+const DEFAULT_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+/// Whether an unsent message is worth replaying later.
+fn is_queueable(cv: &CommunicationValue) -> bool {
+    QUEUEABLE_TYPES.into_iter().any(|ct| cv.is_type(ct))
+        && cv
+            .get_data_container()
+            .keys()
+            .all(|key| QUEUEABLE_DATA.contains(key))
+}
+
+impl OmikronConnection {
+    /// Persist `cv` in the outbox so it survives a reconnect (or a restart).
+    pub async fn queue_message(&self, cv: &CommunicationValue) {
+        if !is_queueable(cv) {
+            return;
+        }
+        let communication_type = cv.get_type().to_string();
+        let ttl_secs = CONFIG
+            .read()
+            .await
+            .get_outbox_expiry(&communication_type)
+            .unwrap_or(DEFAULT_EXPIRY_SECS);
+        if ttl_secs == 0 {
+            return;
+        }
+        if let Err(e) = outbox_util::enqueue(&communication_type, &cv_to_json(cv).dump(), ttl_secs)
+        {
+            log!("Failed to queue {} in outbox: {}", communication_type, e);
+        }
+    }
+
+    /// Replay queued messages in order. Stops at the first failed send so the
+    /// remaining entries keep their position for the next flush.
+    pub async fn flush_outbox(&self) {
+        let entries = match outbox_util::pending() {
+            Ok(entries) => entries,
+            Err(e) => {
+                log!("Failed to read outbox: {}", e);
+                return;
+            }
+        };
+        if entries.is_empty() {
+            return;
+        }
+        // An Omikron without replay support would treat old messages as new;
+        // keep them until it does, or until they expire.
+        if !self.omikron_supports(FEATURE_OUTBOX_REPLAY).await {
+            log!(
+                "Omikron does not accept replays, keeping {} queued messages",
+                entries.len()
+            );
+            return;
+        }
+
+        let mut flushed = 0;
+        for entry in &entries {
+            let Some(cv) = json::parse(&entry.payload)
+                .ok()
+                .and_then(|j| cv_from_json(&j))
+            else {
+                log!(
+                    "Dropping undecodable {} from outbox",
+                    entry.communication_type
+                );
+                let _ = outbox_util::remove(entry.id);
+                continue;
+            };
+            if self.send_message_result(&cv).await.is_err() {
+                break;
+            }
+            let _ = outbox_util::remove(entry.id);
+            flushed += 1;
+        }
+        log!("Flushed {}/{} queued messages", flushed, entries.len());
+    }
+}
+
+// ============================================================================
+// Serialization
+// ============================================================================
+
+pub fn cv_to_json(cv: &CommunicationValue) -> JsonValue {
+    let mut data = JsonValue::new_object();
+    for (key, value) in cv.get_data_container().iter() {
+        data[key.to_string()] = value_to_json(value);
+    }
+    object! {
+        "type" => cv.get_type().to_string(),
+        "id" => cv.get_id(),
+        "sender" => cv.get_sender(),
+        "receiver" => cv.get_receiver(),
+        "data" => data,
+    }
+}
+
+pub fn cv_from_json(j: &JsonValue) -> Option<CommunicationValue> {
+    let communication_type = j["type"].as_str()?.parse::<CommunicationType>().ok()?;
+    let mut cv = CommunicationValue::new(communication_type)
+        .with_id(j["id"].as_u32()?)
+        .with_sender(j["sender"].as_u64().unwrap_or(0))
+        .with_receiver(j["receiver"].as_u64().unwrap_or(0));
+    for (key, value) in j["data"].entries() {
+        cv = cv.add_data(key.parse::<DataTypes>().ok()?, value_from_json(value)?);
+    }
+    Some(cv)
+}
+
+fn value_to_json(value: &DataValue) -> JsonValue {
+    match value {
+        DataValue::Str(s) => JsonValue::from(s.as_str()),
+        DataValue::Number(n) => JsonValue::from(*n),
+        DataValue::Bool(b) => JsonValue::from(*b),
+        DataValue::BoolTrue => JsonValue::from(true),
+        DataValue::BoolFalse => JsonValue::from(false),
+        DataValue::Array(arr) => JsonValue::Array(arr.iter().map(value_to_json).collect()),
+        DataValue::Container(inner) => {
+            let mut obj = JsonValue::new_object();
+            for (key, value) in inner {
+                obj[key.to_string()] = value_to_json(value);
+            }
+            obj
+        }
+        _ => JsonValue::Null,
+    }
+}
+
+fn value_from_json(j: &JsonValue) -> Option<DataValue> {
+    match j {
+        JsonValue::Short(_) | JsonValue::String(_) => Some(DataValue::Str(j.as_str()?.to_string())),
+        JsonValue::Number(_) => Some(DataValue::Number(j.as_i64()?)),
+        JsonValue::Boolean(b) => Some(DataValue::Bool(*b)),
+        JsonValue::Array(arr) => Some(DataValue::Array(
+            arr.iter()
+                .map(value_from_json)
+                .collect::<Option<Vec<_>>>()?,
+        )),
+        JsonValue::Object(_) => {
+            let mut inner = Vec::new();
+            for (key, value) in j.entries() {
+                inner.push((key.parse::<DataTypes>().ok()?, value_from_json(value)?));
+            }
+            Some(DataValue::Container(inner))
+        }
+        JsonValue::Null => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn communication_value_survives_json_round_trip() {
+        let cv = CommunicationValue::new(CommunicationType::message_state)
+            .with_id(17)
+            .with_sender(3)
+            .with_receiver(5)
+            .add_data(DataTypes::send_time, DataValue::Number(1_700_000_000_000))
+            .add_data(DataTypes::message_state, DataValue::Str("read".to_string()))
+            .add_data(
+                DataTypes::messages,
+                DataValue::Array(vec![DataValue::Container(vec![(
+                    DataTypes::sent_by_self,
+                    DataValue::Bool(true),
+                )])]),
+            );
+
+        let decoded = cv_from_json(&json::parse(&cv_to_json(&cv).dump()).unwrap()).unwrap();
+        assert_eq!(cv_to_json(&decoded), cv_to_json(&cv));
+        assert!(decoded.is_type(CommunicationType::message_state));
+        assert_eq!(decoded.get_id(), 17);
+    }
+
+    #[test]
+    fn only_states_and_confirmations_are_queued() {
+        assert!(!is_queueable(&CommunicationValue::new(
+            CommunicationType::ping
+        )));
+        assert!(!is_queueable(&CommunicationValue::new(
+            CommunicationType::identification
+        )));
+        assert!(!is_queueable(&CommunicationValue::new(
+            CommunicationType::message_live
+        )));
+        assert!(!is_queueable(
+            &CommunicationValue::new(CommunicationType::message_send)
+                .add_data(DataTypes::content, DataValue::Str("hi".to_string()))
+        ));
+        assert!(is_queueable(&CommunicationValue::new(
+            CommunicationType::message_send
+        )));
+        assert!(is_queueable(
+            &CommunicationValue::new(CommunicationType::message_state)
+                .add_data(DataTypes::send_time, DataValue::Number(1))
+                .add_data(DataTypes::message_state, DataValue::Str("read".to_string()))
+        ));
+    }
+}
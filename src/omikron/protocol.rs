@@ -0,0 +1,122 @@
+//! Protocol version and feature negotiation with Omikron.
+//!
+//! The Iota advertises its version and features as a JSON `payload` on
+//! `register_iota` and `identification`. Omikron answers with its own in
+//! `identification_response`. An Omikron that sends nothing is treated as a
+//! version 0 peer without optional features.
+
+use json::object;
+use std::collections::HashSet;
+use ttp_core::{CommunicationValue, DataTypes};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest Omikron protocol this Iota still works with. Every version is
+/// accepted while this is 0; raising it needs a check in `negotiate`.
+pub const MIN_OMIKRON_VERSION: u32 = 0;
+
+pub const FEATURE_MESSAGE_DEDUP: &str = "message_dedup";
+pub const FEATURE_OUTBOX_REPLAY: &str = "outbox_replay";
+
+pub const FEATURES: &[&str] = &[FEATURE_MESSAGE_DEDUP, FEATURE_OUTBOX_REPLAY];
+
+/// What was agreed with the connected Omikron.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub features: HashSet<String>,
+}
+
+impl Negotiated {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+
+    /// Features this Iota offers but has to do without.
+    pub fn missing(&self) -> Vec<&'static str> {
+        FEATURES
+            .iter()
+            .copied()
+            .filter(|f| !self.supports(f))
+            .collect()
+    }
+}
+
+pub fn advertisement() -> String {
+    object! {
+        "protocol_version" => PROTOCOL_VERSION,
+        "min_version" => MIN_OMIKRON_VERSION,
+        "features" => FEATURES,
+    }
+    .dump()
+}
+
+/// Reads Omikron's side from `identification_response`. `Err` means the two
+/// cannot talk to each other and the session must not continue.
+pub fn negotiate(cv: &CommunicationValue) -> Result<Negotiated, String> {
+    let remote = cv
+        .get_data(DataTypes::payload)
+        .as_str()
+        .and_then(|p| json::parse(p).ok())
+        .unwrap_or(json::JsonValue::new_object());
+
+    let version = remote["protocol_version"].as_u32().unwrap_or(0);
+    let min_version = remote["min_version"].as_u32().unwrap_or(0);
+
+    if PROTOCOL_VERSION < min_version {
+        return Err(format!(
+            "Omikron requires protocol {}, this Iota speaks {}",
+            min_version, PROTOCOL_VERSION
+        ));
+    }
+
+    let offered: HashSet<String> = remote["features"]
+        .members()
+        .filter_map(|f| f.as_str().map(String::from))
+        .collect();
+    Ok(Negotiated {
+        version: version.min(PROTOCOL_VERSION),
+        features: FEATURES
+            .iter()
+            .filter(|f| offered.contains(**f))
+            .map(|f| f.to_string())
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttp_core::{CommunicationType, DataValue};
+
+    fn response(payload: &str) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::identification_response)
+            .add_data(DataTypes::accepted, DataValue::Bool(true))
+            .add_data(DataTypes::payload, DataValue::Str(payload.to_string()))
+    }
+
+    #[test]
+    fn legacy_omikron_is_accepted_without_features() {
+        let cv = CommunicationValue::new(CommunicationType::identification_response)
+            .add_data(DataTypes::accepted, DataValue::Bool(true));
+        let negotiated = negotiate(&cv).unwrap();
+        assert_eq!(negotiated.version, 0);
+        assert_eq!(negotiated.missing(), FEATURES.to_vec());
+    }
+
+    #[test]
+    fn only_shared_features_are_enabled() {
+        let negotiated = negotiate(&response(
+            r#"{"protocol_version":3,"min_version":1,"features":["message_dedup","voice"]}"#,
+        ))
+        .unwrap();
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert!(negotiated.supports(FEATURE_MESSAGE_DEDUP));
+        assert!(!negotiated.supports("voice"));
+        assert_eq!(negotiated.missing(), vec![FEATURE_OUTBOX_REPLAY]);
+    }
+
+    #[test]
+    fn too_old_iota_is_refused() {
+        assert!(negotiate(&response(r#"{"protocol_version":5,"min_version":4}"#)).is_err());
+    }
+}
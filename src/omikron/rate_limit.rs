@@ -0,0 +1,164 @@
+//! Per-user, per-`CommunicationType` token buckets for the requests a client
+//! makes of this Iota. Only the types in `LIMITED_TYPES` are limited;
+//! messages relayed between users are not. Limits come from `rate_limits`
+//! in the config; rejections are counted per type for the TUI and the
+//! `limits` console command.
+
+use crate::APP_STATE;
+use crate::util::config_util::CONFIG;
+use dashmap::DashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+/// Requests a client makes of its own Iota, each doing DB or filesystem work.
+const LIMITED_TYPES: [CommunicationType; 17] = [
+    CommunicationType::messages_get,
+    CommunicationType::messages_search,
+    CommunicationType::get_chats,
+    CommunicationType::add_conversation,
+    CommunicationType::contact_flags,
+    CommunicationType::get_communities,
+    CommunicationType::add_community,
+    CommunicationType::remove_community,
+    CommunicationType::settings_save,
+    CommunicationType::settings_load,
+    CommunicationType::settings_list,
+    CommunicationType::history_export,
+    CommunicationType::history_import,
+    CommunicationType::retention_set,
+    CommunicationType::blob_upload_start,
+    CommunicationType::blob_upload_chunk,
+    CommunicationType::blob_download,
+];
+
+/// How often buckets that have refilled are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    /// When the bucket is full again; from then on it is no different
+    /// from a new one.
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn full(burst: u32) -> Self {
+        let now = Instant::now();
+        Bucket {
+            tokens: burst as f64,
+            refilled_at: now,
+            full_at: now,
+        }
+    }
+
+    fn try_take(&mut self, burst: u32, per_second: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(burst as f64);
+        self.refilled_at = now;
+        let allowed = if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        };
+        let missing = (burst as f64 - self.tokens) / per_second;
+        self.full_at = Duration::try_from_secs_f64(missing)
+            .ok()
+            .and_then(|missing| now.checked_add(missing))
+            .unwrap_or(now + SWEEP_INTERVAL);
+        allowed
+    }
+}
+
+static BUCKETS: LazyLock<DashMap<(u64, String), Bucket>> = LazyLock::new(|| DashMap::new());
+static REJECTED: LazyLock<DashMap<String, u64>> = LazyLock::new(|| DashMap::new());
+static LAST_SWEEP: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
+
+/// Drops the buckets that have refilled, at most once per `SWEEP_INTERVAL`,
+/// so users who stopped sending requests do not keep one forever.
+fn sweep(now: Instant) {
+    let Ok(mut last_sweep) = LAST_SWEEP.try_lock() else {
+        return;
+    };
+    if now.duration_since(*last_sweep) < SWEEP_INTERVAL {
+        return;
+    }
+    *last_sweep = now;
+    BUCKETS.retain(|_, bucket| bucket.full_at > now);
+}
+
+/// Takes a token for the user that sent `cv`. Only client requests in
+/// `LIMITED_TYPES` are limited; messages without a user sender come from
+/// Omikron itself and are never limited.
+pub async fn allow(cv: &CommunicationValue) -> bool {
+    let user_id = cv.get_sender();
+    if user_id == 0 || !LIMITED_TYPES.into_iter().any(|ct| cv.is_type(ct)) {
+        return true;
+    }
+    let communication_type = cv.get_type().to_string();
+    let (burst, per_second) = CONFIG.read().await.get_rate_limit(&communication_type);
+    if per_second <= 0.0 {
+        return true;
+    }
+
+    let now = Instant::now();
+    sweep(now);
+    let allowed = BUCKETS
+        .entry((user_id, communication_type.clone()))
+        .or_insert_with(|| Bucket::full(burst))
+        .try_take(burst, per_second, now);
+    if !allowed {
+        *REJECTED.entry(communication_type).or_insert(0) += 1;
+        APP_STATE.lock().unwrap().rate_limited += 1;
+    }
+    allowed
+}
+
+/// Reply sent instead of running the handler.
+pub fn rejection(cv: &CommunicationValue) -> CommunicationValue {
+    CommunicationValue::new(CommunicationType::error)
+        .with_id(cv.get_id())
+        .with_receiver(cv.get_sender())
+        .add_data(
+            DataTypes::message,
+            DataValue::Str(format!("rate limited: {}", cv.get_type().to_string())),
+        )
+}
+
+/// Rejected requests per `CommunicationType` since startup.
+pub fn rejected() -> Vec<(String, u64)> {
+    let mut counts: Vec<(String, u64)> = REJECTED
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect();
+    counts.sort();
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(2);
+        assert!(bucket.try_take(2, 1.0, start));
+        assert!(bucket.try_take(2, 1.0, start));
+        assert!(!bucket.try_take(2, 1.0, start));
+        assert!(bucket.try_take(2, 1.0, start + Duration::from_secs(1)));
+        assert!(!bucket.try_take(2, 1.0, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn bucket_knows_when_it_is_full_again() {
+        let start = Instant::now();
+        let mut bucket = Bucket::full(2);
+        assert!(bucket.try_take(2, 1.0, start));
+        assert!(bucket.try_take(2, 1.0, start));
+        assert_eq!(bucket.full_at, start + Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,295 @@
+//! Typed wrappers for the request/response exchanges the Iota starts itself.
+//!
+//! Each request knows how to build its `CommunicationValue` and how to decode
+//! the answer, so a missing field surfaces as `RequestError::Decode` instead
+//! of silently defaulting.
+
+use crate::omikron::omikron_connection::OmikronConnection;
+use std::time::Duration;
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum RequestError {
+    Timeout(Duration),
+    Disconnected(String),
+    /// Omikron answered, but with an error or an unexpected message type.
+    Remote(String),
+    Decode(String),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout(t) => write!(f, "request timed out after {}s", t.as_secs()),
+            RequestError::Disconnected(e) => write!(f, "connection lost: {}", e),
+            RequestError::Remote(e) => write!(f, "Omikron rejected the request: {}", e),
+            RequestError::Decode(e) => write!(f, "malformed response: {}", e),
+        }
+    }
+}
+
+pub trait OmikronRequest {
+    type Response;
+
+    fn timeout(&self) -> Duration {
+        DEFAULT_TIMEOUT
+    }
+
+    fn to_cv(&self) -> CommunicationValue;
+
+    fn decode(cv: &CommunicationValue) -> Result<Self::Response, RequestError>;
+}
+
+impl OmikronConnection {
+    pub async fn request<R: OmikronRequest + Sync>(
+        &self,
+        request: &R,
+    ) -> Result<R::Response, RequestError> {
+        let response = self
+            .exchange(&request.to_cv(), Some(request.timeout()))
+            .await?;
+        R::decode(&response)
+    }
+}
+
+fn expect_type(cv: &CommunicationValue, ct: CommunicationType) -> Result<(), RequestError> {
+    if cv.is_type(ct) {
+        return Ok(());
+    }
+    let message = cv.get_data(DataTypes::message).as_str().map(String::from);
+    Err(RequestError::Remote(
+        message.unwrap_or(cv.get_type().to_string()),
+    ))
+}
+
+fn require_number(cv: &CommunicationValue, key: DataTypes) -> Result<i64, RequestError> {
+    let name = key.to_string();
+    cv.get_data(key)
+        .as_number()
+        .ok_or(RequestError::Decode(format!("{} missing", name)))
+}
+
+// ============================================================================
+// Exchanges
+// ============================================================================
+
+/// Reserves a fresh user id at Omikron.
+pub struct GetRegister;
+
+impl OmikronRequest for GetRegister {
+    type Response = i64;
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(20)
+    }
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::get_register)
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<i64, RequestError> {
+        require_number(cv, DataTypes::user_id)
+    }
+}
+
+/// Binds a reserved user id to its username and public key, on the Iota
+/// with `iota_id`.
+pub struct CompleteRegisterUser {
+    pub user_id: i64,
+    pub username: String,
+    pub public_key: String,
+    pub iota_id: i64,
+    pub reset_token: String,
+}
+
+impl OmikronRequest for CompleteRegisterUser {
+    type Response = ();
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(20)
+    }
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::complete_register_user)
+            .add_data(DataTypes::user_id, DataValue::Number(self.user_id))
+            .add_data(DataTypes::username, DataValue::Str(self.username.clone()))
+            .add_data(
+                DataTypes::public_key,
+                DataValue::Str(self.public_key.clone()),
+            )
+            .add_data(DataTypes::iota_id, DataValue::Number(self.iota_id))
+            .add_data(
+                DataTypes::reset_token,
+                DataValue::Str(self.reset_token.clone()),
+            )
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<(), RequestError> {
+        expect_type(cv, CommunicationType::success)
+    }
+}
+
+/// Replaces the public key of a user after their key was re-issued. The
+/// current reset token authorises the change; `new_reset_token` replaces it.
+/// Sending the same reset again after it was applied also succeeds, so an
+/// unconfirmed reset can be retried.
+pub struct ResetUserKey {
+    pub user_id: i64,
+    pub public_key: String,
+    pub reset_token: String,
+    pub new_reset_token: String,
+}
+
+impl OmikronRequest for ResetUserKey {
+    type Response = ();
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::reset_user_key)
+            .add_data(DataTypes::user_id, DataValue::Number(self.user_id))
+            .add_data(
+                DataTypes::public_key,
+                DataValue::Str(self.public_key.clone()),
+            )
+            .add_data(
+                DataTypes::reset_token,
+                DataValue::Str(self.reset_token.clone()),
+            )
+            .add_data(
+                DataTypes::new_reset_token,
+                DataValue::Str(self.new_reset_token.clone()),
+            )
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<(), RequestError> {
+        expect_type(cv, CommunicationType::success)
+    }
+}
+
+/// De-registers a user who is purged from this Iota. A user Omikron does not
+/// know (anymore) counts as removed, so an interrupted purge can be retried.
+pub struct DeleteUser {
+    pub user_id: i64,
+    pub reset_token: String,
+}
+
+impl OmikronRequest for DeleteUser {
+    type Response = ();
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::delete_user)
+            .add_data(DataTypes::user_id, DataValue::Number(self.user_id))
+            .add_data(
+                DataTypes::reset_token,
+                DataValue::Str(self.reset_token.clone()),
+            )
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<(), RequestError> {
+        if cv.is_type(CommunicationType::error_invalid_user_id) {
+            return Ok(());
+        }
+        expect_type(cv, CommunicationType::success)
+    }
+}
+
+/// Points a user who moved here from another Iota at this Iota. The reset
+/// token the user was registered with authorises the move. Sending it again
+/// once it was applied succeeds, so an unanswered request can be repeated.
+pub struct MigrateUser {
+    pub user_id: i64,
+    pub iota_id: i64,
+    pub reset_token: String,
+}
+
+impl OmikronRequest for MigrateUser {
+    type Response = ();
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::migrate_user)
+            .add_data(DataTypes::user_id, DataValue::Number(self.user_id))
+            .add_data(DataTypes::iota_id, DataValue::Number(self.iota_id))
+            .add_data(
+                DataTypes::reset_token,
+                DataValue::Str(self.reset_token.clone()),
+            )
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<(), RequestError> {
+        expect_type(cv, CommunicationType::success)
+    }
+}
+
+/// A single round trip; the heartbeat keeps using `send_ping`.
+pub struct Ping {
+    pub timeout: Duration,
+}
+
+impl OmikronRequest for Ping {
+    type Response = ();
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn to_cv(&self) -> CommunicationValue {
+        CommunicationValue::new(CommunicationType::ping)
+    }
+
+    fn decode(cv: &CommunicationValue) -> Result<(), RequestError> {
+        expect_type(cv, CommunicationType::pong)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_user_id_is_a_decode_error() {
+        let response = CommunicationValue::new(CommunicationType::success);
+        assert!(matches!(
+            GetRegister::decode(&response),
+            Err(RequestError::Decode(_))
+        ));
+
+        let response = CommunicationValue::new(CommunicationType::success)
+            .add_data(DataTypes::user_id, DataValue::Number(12));
+        assert_eq!(GetRegister::decode(&response).unwrap(), 12);
+    }
+
+    #[test]
+    fn unexpected_reply_type_is_a_remote_error() {
+        let response = CommunicationValue::new(CommunicationType::error_invalid_user_id);
+        assert!(matches!(
+            CompleteRegisterUser::decode(&response),
+            Err(RequestError::Remote(_))
+        ));
+        assert!(DeleteUser::decode(&response).is_ok());
+    }
+
+    #[test]
+    fn registration_names_the_iota_not_the_user() {
+        let cv = CompleteRegisterUser {
+            user_id: 12,
+            username: "alice".to_string(),
+            public_key: "key".to_string(),
+            iota_id: 7,
+            reset_token: "token".to_string(),
+        }
+        .to_cv();
+        assert_eq!(
+            cv.get_data(DataTypes::iota_id)
+                .as_number()
+                .map(|n| n as i64),
+            Some(7)
+        );
+        assert_eq!(
+            cv.get_data(DataTypes::user_id)
+                .as_number()
+                .map(|n| n as i64),
+            Some(12)
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Background sweep that enforces retention and tells everyone involved.
+//! It also runs the user purges whose grace period is over.
+//!
+//! Each owner's client gets a `message_expire` with the row ids that are
+//! gone. Disappearing messages are also announced to the partner with their
+//! wire ids, so the partner's Iota drops its copy too.
+
+use crate::omikron::handlers::{OmikronSender, now_millis};
+use crate::omikron::omikron_connection::OMIKRON_CONNECTION;
+use crate::users::user_purge;
+use crate::util::blob_store;
+use crate::util::config_util::CONFIG;
+use crate::util::retention_util::{self, ExpiredMessage, RetentionPolicy};
+use crate::{ACTIVE_TASKS, SHUTDOWN, log};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{Duration, sleep};
+use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
+
+const TASK_NAME: &str = "Retention purge";
+
+pub fn start() {
+    ACTIVE_TASKS.insert(TASK_NAME.to_string());
+    tokio::spawn(async move {
+        loop {
+            let (max_age_ms, max_count, interval) = {
+                let config = CONFIG.read().await;
+                let (max_age_ms, max_count) = config.get_retention();
+                (max_age_ms, max_count, config.get_retention_interval())
+            };
+            let global = RetentionPolicy {
+                max_age_ms,
+                max_count,
+            };
+
+            match retention_util::purge(now_millis(), global) {
+                Ok(expired) if !expired.is_empty() => {
+                    log!("Retention removed {} messages", expired.len());
+                    let sender: Arc<dyn OmikronSender> = OMIKRON_CONNECTION.clone();
+                    notify(&sender, &expired).await;
+                    blob_store::collect_garbage();
+                }
+                Ok(_) => {}
+                Err(e) => log!("Retention purge failed: {}", e),
+            }
+            user_purge::run_due().await;
+            blob_store::expire_uploads();
+
+            // Wake up every second so shutdown is not held up by the interval.
+            for _ in 0..interval {
+                if *SHUTDOWN.read().await {
+                    ACTIVE_TASKS.remove(TASK_NAME);
+                    return;
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    });
+}
+
+/// Sends one `message_expire` per conversation to the owner and, for
+/// disappearing messages, one to the partner.
+pub async fn notify(sender: &Arc<dyn OmikronSender>, expired: &[ExpiredMessage]) {
+    let mut conversations: BTreeMap<(i64, i64), Vec<&ExpiredMessage>> = BTreeMap::new();
+    for message in expired {
+        conversations
+            .entry((message.storage_owner, message.external_user))
+            .or_default()
+            .push(message);
+    }
+
+    for ((storage_owner, external_user), messages) in conversations {
+        let row_ids = messages
+            .iter()
+            .map(|m| DataValue::Number(m.row_id))
+            .collect();
+        sender
+            .send_message(
+                &CommunicationValue::new(CommunicationType::message_expire)
+                    .with_receiver(storage_owner as u64)
+                    .add_data(DataTypes::user_id, DataValue::Number(external_user))
+                    .add_data(DataTypes::message_ids, DataValue::Array(row_ids)),
+            )
+            .await;
+
+        let wire_ids: Vec<DataValue> = messages
+            .iter()
+            .filter(|m| m.disappearing)
+            .filter_map(|m| m.message_id)
+            .map(|id| DataValue::Number(id as i64))
+            .collect();
+        if !wire_ids.is_empty() {
+            sender
+                .send_message(
+                    &CommunicationValue::new(CommunicationType::message_expire)
+                        .with_sender(storage_owner as u64)
+                        .with_receiver(external_user as u64)
+                        .add_data(DataTypes::message_ids, DataValue::Array(wire_ids)),
+                )
+                .await;
+        }
+    }
+}
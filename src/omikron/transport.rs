@@ -0,0 +1,48 @@
+//! The two halves of an Omikron socket as seen by `OmikronConnection`.
+//!
+//! Production sessions use the `ttp_native` client; tests plug in the
+//! channel-backed `MockOmikron` instead.
+
+use async_trait::async_trait;
+use ttp_core::CommunicationValue;
+use ttp_native::{Receiver, Sender};
+
+#[async_trait]
+pub trait OmikronTransport: Send + Sync {
+    async fn send(&self, cv: &CommunicationValue) -> Result<(), String>;
+    fn is_open(&self) -> bool;
+    fn close(&self);
+}
+
+#[async_trait]
+pub trait OmikronReceiver: Send {
+    async fn receive(&mut self) -> Result<CommunicationValue, String>;
+    fn is_open(&self) -> bool;
+}
+
+#[async_trait]
+impl OmikronTransport for Sender {
+    async fn send(&self, cv: &CommunicationValue) -> Result<(), String> {
+        Sender::send(self, cv).await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        Sender::is_open(self)
+    }
+
+    fn close(&self) {
+        Sender::close(self);
+    }
+}
+
+#[async_trait]
+impl OmikronReceiver for Receiver {
+    async fn receive(&mut self) -> Result<CommunicationValue, String> {
+        Receiver::receive(self).await.map_err(|e| e.to_string())
+    }
+
+    fn is_open(&self) -> bool {
+        Receiver::is_open(self)
+    }
+}
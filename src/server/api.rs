@@ -1,5 +1,9 @@
+use crate::log;
 use crate::server::server::is_local_network;
+use crate::users::key_bundle::{self, Protection};
+use crate::users::{user_migration, user_purge};
 use crate::util::config_util::CONFIG;
+use crate::util::export_util;
 use actix_web::{HttpRequest, HttpResponse, Responder, web};
 use serde_json::{Value, json};
 use std::net::SocketAddr;
@@ -12,7 +16,12 @@ pub fn api_config(cfg: &mut web::ServiceConfig) {
             .route("/reload/", web::post().to(reload))
             .route("/users/add/", web::post().to(users_add))
             .route("/users/remove/", web::post().to(users_remove))
+            .route("/users/restore/", web::post().to(users_restore))
             .route("/users/get/", web::get().to(users_get))
+            .route("/users/key/", web::post().to(users_key))
+            .route("/users/reissue/", web::post().to(users_reissue))
+            .route("/users/migrate/", web::post().to(users_migrate))
+            .route("/users/receive/", web::post().to(users_receive))
             .route("/communities/add/", web::post().to(communities_add))
             .route("/communities/get/", web::get().to(communities_get))
             .route("/settings/set/", web::post().to(settings_set))
@@ -93,11 +102,18 @@ async fn users_get(req: HttpRequest, ssl: web::Data<bool>) -> impl Responder {
     }
 
     let users = crate::users::user_manager::get_users();
+    let purges = match user_purge::schedules().await {
+        Ok(purges) => purges,
+        Err(e) => {
+            log!("Loading scheduled removals failed: {}", e);
+            return error();
+        }
+    };
 
     let list: Vec<_> = users
         .into_iter()
         .map(|u| {
-            let val = u.frontend();
+            let val = u.frontend(purges.get(&u.user_id).copied());
             serde_json::to_value(val.to_string()).unwrap()
         })
         .collect();
@@ -105,6 +121,9 @@ async fn users_get(req: HttpRequest, ssl: web::Data<bool>) -> impl Responder {
     HttpResponse::Ok().json(list)
 }
 
+/// Removes a user now, or after `grace_days`. With `export` the user's
+/// conversations are returned as a JSON Lines download, taken before
+/// anything is deleted; the Iota keeps no copy.
 async fn users_remove(
     req: HttpRequest,
     ssl: web::Data<bool>,
@@ -115,11 +134,74 @@ async fn users_remove(
     }
 
     let uuid = payload.get("uuid").and_then(|v| v.as_i64()).unwrap_or(0);
+    let export = payload
+        .get("export")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    // A malformed grace period must not turn into an immediate purge.
+    let grace = match payload.get("grace_days") {
+        None | Some(Value::Null) => None,
+        Some(days) => match days.as_u64().map(user_purge::grace_period) {
+            Some(Some(grace)) if !grace.is_zero() => Some(grace),
+            Some(Some(_)) => None,
+            _ => {
+                log!("Invalid grace period for user {}: {}", uuid, days);
+                return error();
+            }
+        },
+    };
 
-    crate::users::user_manager::remove_user(uuid);
-    crate::users::user_manager::save_users();
+    let jsonl = if export {
+        match export_util::export(uuid).await {
+            Ok(jsonl) => Some(jsonl),
+            Err(e) => {
+                log!("Exporting user {} failed: {}", uuid, e);
+                return error();
+            }
+        }
+    } else {
+        None
+    };
 
-    success()
+    let res = match grace {
+        Some(grace) => user_purge::schedule(uuid, grace).await.map(|_| ()),
+        None => user_purge::purge(uuid).await,
+    };
+    match (res, jsonl) {
+        (Ok(()), Some(jsonl)) => HttpResponse::Ok()
+            .content_type("application/jsonl")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.jsonl\"", uuid),
+            ))
+            .body(jsonl),
+        (Ok(()), None) => success(),
+        (Err(e), _) => {
+            log!("Removing user {} failed: {}", uuid, e);
+            error()
+        }
+    }
+}
+
+/// Cancels a scheduled removal.
+async fn users_restore(
+    req: HttpRequest,
+    ssl: web::Data<bool>,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    if !is_allowed_req(&req, *ssl.get_ref()) {
+        return forbidden();
+    }
+
+    let uuid = payload.get("uuid").and_then(|v| v.as_i64()).unwrap_or(0);
+    match user_purge::cancel(uuid).await {
+        Ok(true) => success(),
+        Ok(false) => error(),
+        Err(e) => {
+            log!("Restoring user {} failed: {}", uuid, e);
+            error()
+        }
+    }
 }
 
 async fn users_add(
@@ -136,8 +218,10 @@ async fn users_add(
         _ => return error(),
     };
 
-    if let (Some(user), Some(_)) = crate::users::user_manager::create_user(username).await {
-        let val = user.frontend();
+    if let Some(user) =
+        crate::users::user_manager::create_user(username, &protection(&payload)).await
+    {
+        let val = user.frontend(None);
         let s_val: Value = serde_json::to_value(val.to_string()).unwrap();
         HttpResponse::Ok().json(s_val)
     } else {
@@ -145,6 +229,126 @@ async fn users_add(
     }
 }
 
+/// A non-empty `passphrase` in `payload`, or the Iota identity.
+fn protection(payload: &Value) -> Protection {
+    match payload.get("passphrase").and_then(|v| v.as_str()) {
+        Some(p) if !p.is_empty() => Protection::Passphrase(p.to_string()),
+        _ => Protection::Iota,
+    }
+}
+
+/// Downloads the pending key bundle of a user. It is deleted here, so this
+/// works once per issued key.
+async fn users_key(
+    req: HttpRequest,
+    ssl: web::Data<bool>,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    if !is_allowed_req(&req, *ssl.get_ref()) {
+        return forbidden();
+    }
+
+    let uuid = payload.get("uuid").and_then(|v| v.as_i64()).unwrap_or(0);
+    match key_bundle::take(uuid) {
+        Ok(Some(bundle)) => {
+            let name = bundle["username"].as_str().unwrap_or("user").to_string();
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .insert_header((
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}.tu\"", name),
+                ))
+                .body(bundle.dump())
+        }
+        Ok(None) => error(),
+        Err(e) => {
+            log!("Handing out the key of {} failed: {}", uuid, e);
+            error()
+        }
+    }
+}
+
+async fn users_reissue(
+    req: HttpRequest,
+    ssl: web::Data<bool>,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    if !is_allowed_req(&req, *ssl.get_ref()) {
+        return forbidden();
+    }
+
+    let uuid = payload.get("uuid").and_then(|v| v.as_i64()).unwrap_or(0);
+    match crate::users::user_manager::reissue_key(uuid, &protection(&payload)).await {
+        Ok(()) => success(),
+        Err(e) => {
+            log!("Re-issuing the key of {} failed: {}", uuid, e);
+            error()
+        }
+    }
+}
+
+/// Downloads a migration bundle of a user for the Iota with
+/// `target_public_key`. The user is refused here from then on.
+async fn users_migrate(
+    req: HttpRequest,
+    ssl: web::Data<bool>,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    if !is_allowed_req(&req, *ssl.get_ref()) {
+        return forbidden();
+    }
+
+    let uuid = payload.get("uuid").and_then(|v| v.as_i64()).unwrap_or(0);
+    let Some(target) = payload.get("target_public_key").and_then(|v| v.as_str()) else {
+        return error();
+    };
+    match user_migration::export(uuid, target).await {
+        Ok(bundle) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.migration\"", uuid),
+            ))
+            .body(bundle),
+        Err(e) => {
+            log!("Migrating user {} failed: {}", uuid, e);
+            error()
+        }
+    }
+}
+
+/// Takes over a user from the migration bundle in `bundle`, sealed by the
+/// Iota with `source_public_key`.
+async fn users_receive(
+    req: HttpRequest,
+    ssl: web::Data<bool>,
+    payload: web::Json<Value>,
+) -> impl Responder {
+    if !is_allowed_req(&req, *ssl.get_ref()) {
+        return forbidden();
+    }
+
+    let bundle = match payload.get("bundle") {
+        Some(Value::String(s)) => s.clone(),
+        Some(v @ Value::Object(_)) => v.to_string(),
+        _ => return error(),
+    };
+    let Some(source) = payload.get("source_public_key").and_then(|v| v.as_str()) else {
+        return error();
+    };
+    match user_migration::import(&bundle, source).await {
+        Ok(user) => {
+            let val = user.frontend(None);
+            let s_val: Value = serde_json::to_value(val.to_string()).unwrap();
+            HttpResponse::Ok().json(s_val)
+        }
+        Err(e) => {
+            log!("Receiving a user failed: {}", e);
+            error()
+        }
+    }
+}
+
 async fn shutdown(req: HttpRequest, ssl: web::Data<bool>) -> impl Responder {
     if !is_allowed_req(&req, *ssl.get_ref()) {
         return forbidden();
@@ -6,6 +6,14 @@ pub struct Contact {
     pub user_id: i64,
     pub user_name: Option<String>,
     pub last_message_at: Option<i64>,
+    /// Row id of the newest message the owner has read in this conversation.
+    pub last_read_id: Option<i64>,
+    pub unread_count: i64,
+    /// Messages from a blocked contact are refused, not stored.
+    pub blocked: bool,
+    pub muted: bool,
+    pub archived: bool,
+    pub pinned: bool,
 }
 
 impl Default for Contact {
@@ -18,6 +26,12 @@ impl Default for Contact {
             user_id: 0,
             user_name: None,
             last_message_at: Some(now),
+            last_read_id: None,
+            unread_count: 0,
+            blocked: false,
+            muted: false,
+            archived: false,
+            pinned: false,
         }
     }
 }
@@ -28,6 +42,12 @@ impl Contact {
             user_id: user_id,
             user_name: None,
             last_message_at: None,
+            last_read_id: None,
+            unread_count: 0,
+            blocked: false,
+            muted: false,
+            archived: false,
+            pinned: false,
         }
     }
     pub fn set_last_message_at(&mut self, p0: i64) {
@@ -43,6 +63,14 @@ impl Contact {
         if let Some(ts) = &self.last_message_at {
             obj["last_message_at"] = JsonValue::Number(Number::from(*ts));
         }
+        if let Some(id) = &self.last_read_id {
+            obj["last_read_id"] = JsonValue::Number(Number::from(*id));
+        }
+        obj["unread_count"] = JsonValue::Number(Number::from(self.unread_count));
+        obj["blocked"] = JsonValue::Boolean(self.blocked);
+        obj["muted"] = JsonValue::Boolean(self.muted);
+        obj["archived"] = JsonValue::Boolean(self.archived);
+        obj["pinned"] = JsonValue::Boolean(self.pinned);
         obj
     }
     pub fn from_json(o: &JsonValue) -> Contact {
@@ -52,10 +80,20 @@ impl Contact {
 
         let last_message_at = o["last_message_at"].as_i64();
 
+        let last_read_id = o["last_read_id"].as_i64();
+
+        let unread_count = o["unread_count"].as_i64().unwrap_or(0);
+
         Contact {
             user_id,
             user_name,
             last_message_at,
+            last_read_id,
+            unread_count,
+            blocked: o["blocked"].as_bool().unwrap_or(false),
+            muted: o["muted"].as_bool().unwrap_or(false),
+            archived: o["archived"].as_bool().unwrap_or(false),
+            pinned: o["pinned"].as_bool().unwrap_or(false),
         }
     }
 }
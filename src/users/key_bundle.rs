@@ -0,0 +1,321 @@
+//! One-time key bundles for users created on this Iota.
+//!
+//! The Iota generates a user's private key but must not keep it readable.
+//! `store` seals the `.tu` line (`user_id::private_key`) into
+//! `users/<id>/key.tu`, either under a passphrase the user chose, so only
+//! they can open it, or under the Iota identity from the keystore. `take`
+//! hands the bundle out once and deletes it; a lost key is replaced with
+//! `user_manager::reissue_key`, which seals the new key to
+//! `users/<id>/key.tu.pending` with `stage` before anything else learns of
+//! it and `promote`s it once the re-issue is stored.
+
+use crate::log;
+use crate::users::user_manager;
+use crate::util::file_util::{get_directory, has_file, load_file, write_file};
+use crate::util::keystore::{self, KeystoreError, PBKDF2_ROUNDS, decode_field};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use json::{JsonValue, object};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+
+const BUNDLE_FILE: &str = "key.tu";
+const STAGED_FILE: &str = "key.tu.pending";
+
+/// What a bundle is sealed with.
+pub enum Protection {
+    Passphrase(String),
+    /// The Iota identity; the Iota opens the bundle when handing it out.
+    Iota,
+}
+
+fn bundle_dir(user_id: i64) -> String {
+    format!("users/{}", user_id)
+}
+
+pub fn has_bundle(user_id: i64) -> bool {
+    has_file(&bundle_dir(user_id), BUNDLE_FILE)
+}
+
+/// Seals `private_key` for `user_id`, replacing a bundle not yet taken.
+pub fn store(
+    user_id: i64,
+    username: &str,
+    private_key: &str,
+    protection: &Protection,
+) -> Result<(), KeystoreError> {
+    let bundle = seal_bundle(user_id, username, private_key, protection, PBKDF2_ROUNDS)?;
+    write(user_id, BUNDLE_FILE, &bundle)
+}
+
+fn write(user_id: i64, name: &str, bundle: &JsonValue) -> Result<(), KeystoreError> {
+    write_file(&bundle_dir(user_id), name, bundle.pretty(2).as_bytes())
+        .map_err(|e| KeystoreError::Io(e.to_string()))
+}
+
+fn read(user_id: i64, name: &str) -> Result<JsonValue, KeystoreError> {
+    json::parse(&load_file(&bundle_dir(user_id), name))
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))
+}
+
+/// The profile fields that go with a staged key.
+pub struct Staged {
+    pub public_key: String,
+    pub private_key_hash: String,
+    pub reset_token: String,
+}
+
+/// Seals `private_key` as the staged key of `user_id`, next to a bundle not
+/// yet taken. The reset token is sealed under the Iota identity.
+pub fn stage(
+    user_id: i64,
+    username: &str,
+    private_key: &str,
+    protection: &Protection,
+    staged: &Staged,
+) -> Result<(), KeystoreError> {
+    let mut bundle = seal_bundle(user_id, username, private_key, protection, PBKDF2_ROUNDS)?;
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let reset_token = keystore::seal(&iota_key(&salt)?, staged.reset_token.as_bytes())?;
+    bundle["staged"] = object! {
+        "public_key" => staged.public_key.clone(),
+        "private_key_hash" => staged.private_key_hash.clone(),
+        "salt" => STANDARD.encode(salt),
+        "reset_token" => STANDARD.encode(reset_token),
+    };
+    write(user_id, STAGED_FILE, &bundle)
+}
+
+/// The staged key of `user_id`, if a re-issue was started and not finished.
+pub fn staged(user_id: i64) -> Result<Option<Staged>, KeystoreError> {
+    if !has_file(&bundle_dir(user_id), STAGED_FILE) {
+        return Ok(None);
+    }
+    let bundle = read(user_id, STAGED_FILE)?;
+    let staged = &bundle["staged"];
+    let field = |name: &str| {
+        staged[name]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or(KeystoreError::Corrupt(format!("staged {} missing", name)))
+    };
+    let salt = decode_field(&staged["salt"], "salt")?;
+    let sealed = decode_field(&staged["reset_token"], "reset_token")?;
+    let reset_token = String::from_utf8(keystore::open(&iota_key(&salt)?, &sealed)?)
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+    Ok(Some(Staged {
+        public_key: field("public_key")?,
+        private_key_hash: field("private_key_hash")?,
+        reset_token,
+    }))
+}
+
+/// Makes the staged key the bundle of `user_id`, replacing one not yet taken.
+pub fn promote(user_id: i64) -> Result<(), KeystoreError> {
+    let mut bundle = read(user_id, STAGED_FILE)?;
+    bundle.remove("staged");
+    write(user_id, BUNDLE_FILE, &bundle)?;
+    discard_staged(user_id)
+}
+
+pub fn discard_staged(user_id: i64) -> Result<(), KeystoreError> {
+    let path = Path::new(&get_directory())
+        .join(bundle_dir(user_id))
+        .join(STAGED_FILE);
+    fs::remove_file(&path).map_err(|e| KeystoreError::Io(e.to_string()))
+}
+
+fn seal_bundle(
+    user_id: i64,
+    username: &str,
+    private_key: &str,
+    protection: &Protection,
+    rounds: u32,
+) -> Result<JsonValue, KeystoreError> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let (kdf, key) = match protection {
+        Protection::Passphrase(passphrase) => {
+            ("passphrase", passphrase_key(passphrase, &salt, rounds))
+        }
+        Protection::Iota => ("iota", iota_key(&salt)?),
+    };
+    let tu = format!("{}::{}", user_id, private_key);
+    Ok(object! {
+        "version" => 1,
+        "user_id" => user_id,
+        "username" => username,
+        "kdf" => kdf,
+        "rounds" => rounds,
+        "salt" => STANDARD.encode(salt),
+        "sealed" => STANDARD.encode(keystore::seal(&key, tu.as_bytes())?),
+    })
+}
+
+/// Hands out the pending bundle of `user_id` and deletes it; `None` if
+/// there is none. Iota-sealed bundles are opened first and come back with
+/// the `.tu` line in `tu`. Passphrase bundles stay sealed, for `open` on
+/// the user's side.
+pub fn take(user_id: i64) -> Result<Option<JsonValue>, KeystoreError> {
+    let Some(bundle) = peek(user_id)? else {
+        return Ok(None);
+    };
+    let path = Path::new(&get_directory())
+        .join(bundle_dir(user_id))
+        .join(BUNDLE_FILE);
+    fs::remove_file(&path).map_err(|e| KeystoreError::Io(e.to_string()))?;
+    Ok(Some(bundle))
+}
+
+/// What `take` would hand out, without deleting it.
+pub fn peek(user_id: i64) -> Result<Option<JsonValue>, KeystoreError> {
+    if !has_bundle(user_id) {
+        return Ok(None);
+    }
+    let stored = read(user_id, BUNDLE_FILE)?;
+
+    if stored["kdf"].as_str() != Some("iota") {
+        return Ok(Some(stored));
+    }
+    Ok(Some(object! {
+        "version" => 1,
+        "user_id" => stored["user_id"].clone(),
+        "username" => stored["username"].clone(),
+        "tu" => open(&stored, None)?,
+    }))
+}
+
+/// Makes `bundle`, as returned by `peek`, the pending bundle of `user_id`
+/// again. An opened bundle is sealed under this Iota's identity.
+pub fn restore(user_id: i64, bundle: &JsonValue) -> Result<(), KeystoreError> {
+    let Some(tu) = bundle["tu"].as_str() else {
+        return write(user_id, BUNDLE_FILE, bundle);
+    };
+    let (_, private_key) = tu
+        .split_once("::")
+        .ok_or(KeystoreError::Corrupt("tu".to_string()))?;
+    let username = bundle["username"].as_str().unwrap_or_default();
+    store(user_id, username, private_key, &Protection::Iota)
+}
+
+/// The `.tu` line inside a bundle as returned by `take`.
+pub fn open(bundle: &JsonValue, passphrase: Option<&str>) -> Result<String, KeystoreError> {
+    if let Some(tu) = bundle["tu"].as_str() {
+        return Ok(tu.to_string());
+    }
+    let salt = decode_field(&bundle["salt"], "salt")?;
+    let sealed = decode_field(&bundle["sealed"], "sealed")?;
+    let key = match bundle["kdf"].as_str() {
+        Some("passphrase") => {
+            let rounds = bundle["rounds"].as_u32().unwrap_or(PBKDF2_ROUNDS);
+            passphrase_key(passphrase.ok_or(KeystoreError::WrongKey)?, &salt, rounds)
+        }
+        Some("iota") => iota_key(&salt)?,
+        _ => return Err(KeystoreError::Corrupt("unknown kdf".to_string())),
+    };
+    String::from_utf8(keystore::open(&key, &sealed)?)
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, rounds, &mut key);
+    key
+}
+
+fn iota_key(salt: &[u8]) -> Result<[u8; 32], KeystoreError> {
+    let iota = keystore::get_keys().ok_or(KeystoreError::NotLoaded)?;
+    let secret = STANDARD
+        .decode(&iota.private_key)
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(&secret, salt, 1, &mut key);
+    Ok(key)
+}
+
+/// Seals the plaintext `<username>.tu` files older versions left in the
+/// data directory, then deletes them. Returns how many were sealed.
+pub fn seal_legacy_files() -> usize {
+    let mut sealed = 0;
+    for user in user_manager::get_users() {
+        let name = format!("{}.tu", user.username);
+        if !has_file("", &name) {
+            continue;
+        }
+        let content = load_file("", &name);
+        let Some((_, private_key)) = content.trim().split_once("::") else {
+            continue;
+        };
+        if !has_bundle(user.user_id) {
+            if let Err(e) = store(user.user_id, &user.username, private_key, &Protection::Iota) {
+                log!("Sealing the key of {} failed: {}", user.username, e);
+                continue;
+            }
+            sealed += 1;
+        }
+        if let Err(e) = fs::remove_file(Path::new(&get_directory()).join(&name)) {
+            log!("[IMPORTANT] Couldn't delete {}: {}", name, e);
+        }
+    }
+    sealed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::file_util::delete_user_directory;
+    use ttp_core::rand_u32;
+
+    #[test]
+    fn iota_bundles_are_handed_out_once() {
+        keystore::test_identity();
+        let user_id = 2_000_000 + rand_u32() as i64;
+
+        store(user_id, "kim", "secret", &Protection::Iota).unwrap();
+        assert!(!load_file(&bundle_dir(user_id), BUNDLE_FILE).contains("secret"));
+
+        let bundle = take(user_id).unwrap().unwrap();
+        assert_eq!(open(&bundle, None).unwrap(), format!("{}::secret", user_id));
+        assert!(!has_bundle(user_id));
+        assert!(take(user_id).unwrap().is_none());
+
+        restore(user_id, &bundle).unwrap();
+        assert_eq!(peek(user_id).unwrap().unwrap()["tu"], bundle["tu"]);
+        delete_user_directory(user_id);
+    }
+
+    #[test]
+    fn staged_keys_become_the_bundle_once_promoted() {
+        keystore::test_identity();
+        let user_id = 2_000_000 + rand_u32() as i64;
+        let fields = Staged {
+            public_key: "public".to_string(),
+            private_key_hash: "hash".to_string(),
+            reset_token: "token".to_string(),
+        };
+
+        stage(user_id, "kim", "secret", &Protection::Iota, &fields).unwrap();
+        assert!(!has_bundle(user_id));
+        assert!(!load_file(&bundle_dir(user_id), STAGED_FILE).contains("token"));
+        assert_eq!(staged(user_id).unwrap().unwrap().reset_token, "token");
+
+        promote(user_id).unwrap();
+        assert!(staged(user_id).unwrap().is_none());
+        let bundle = take(user_id).unwrap().unwrap();
+        assert!(bundle["staged"].is_null());
+        assert_eq!(open(&bundle, None).unwrap(), format!("{}::secret", user_id));
+        delete_user_directory(user_id);
+    }
+
+    #[test]
+    fn passphrase_bundles_need_the_passphrase() {
+        let protection = Protection::Passphrase("correct horse".to_string());
+        let bundle = seal_bundle(7, "kim", "secret", &protection, 1).unwrap();
+        assert!(bundle["tu"].is_null());
+        assert!(matches!(open(&bundle, None), Err(KeystoreError::WrongKey)));
+        assert!(open(&bundle, Some("battery staple")).is_err());
+        assert_eq!(open(&bundle, Some("correct horse")).unwrap(), "7::secret");
+    }
+}
@@ -1,4 +1,7 @@
 pub mod contact;
+pub mod key_bundle;
 pub mod user_community_util;
 pub mod user_manager;
+pub mod user_migration;
 pub mod user_profile;
+pub mod user_purge;
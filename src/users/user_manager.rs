@@ -1,10 +1,15 @@
+use crate::log;
 use crate::omikron::omikron_connection::OMIKRON_CONNECTION;
+use crate::omikron::requests::{CompleteRegisterUser, GetRegister, RequestError, ResetUserKey};
+use crate::users::key_bundle::{self, Protection, Staged};
 use crate::users::user_profile::UserProfile;
+use crate::util::config_util::CONFIG;
 use crate::util::crypto_helper::{self, public_key_to_base64};
-use crate::util::file_util::{load_file, save_file};
-use crate::util::logger::PrintType;
+use crate::util::db::MESSAGES_DB;
+use crate::util::file_util::{delete_user_directory, has_file, load_file, rename_file};
+use crate::util::message_crypto::{self, UserKeys, keys_for};
+use crate::util::{keystore, users_util};
 use crate::{RELOAD, SHUTDOWN};
-use crate::{log, log_cv};
 use base64::{Engine as _, engine::general_purpose::STANDARD};
 use hex::{self};
 use json::JsonValue;
@@ -15,12 +20,11 @@ use rand_core::RngCore;
 use sha2::{Digest, Sha256};
 use std::io::{self};
 use std::sync::Mutex;
-use std::time::Duration;
-use ttp_core::{CommunicationType, CommunicationValue, DataTypes, DataValue};
 use x448::{PublicKey, Secret};
 
+/// Cache of the `users` table. Every change is written to the DB first and
+/// only mirrored here once it committed.
 static USERS: Lazy<Mutex<Vec<UserProfile>>> = Lazy::new(|| Mutex::new(Vec::new()));
-static UNIQUE: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
 
 #[allow(dead_code)]
 pub async fn load_from_tu(username: &str) -> Result<(), ()> {
@@ -44,27 +48,24 @@ pub async fn load_from_tu(username: &str) -> Result<(), ()> {
         crypto_helper::hex_hash(b64_private_key),
         reset_token,
     );
-    USERS.lock().unwrap().push(user_profile);
-    Ok(())
+    add_user(user_profile)
+        .await
+        .map_err(|e| log!("Storing {} failed: {}", username, e))
 }
 
-pub async fn create_user(username: &str) -> (Option<UserProfile>, Option<String>) {
-    let register_cv = CommunicationValue::new(CommunicationType::get_register);
-
+/// Registers a new user at Omikron and stores the profile. The private key
+/// only leaves the Iota as a key bundle sealed with `protection`. Bundle and
+/// profile are stored before the registration is completed, and removed
+/// again if Omikron does not complete it.
+pub async fn create_user(username: &str, protection: &Protection) -> Option<UserProfile> {
     let conn = OMIKRON_CONNECTION.clone();
 
-    let response_cv = match conn
-        .await_response(&register_cv, Some(Duration::from_secs(20)))
-        .await
-    {
-        Ok(cv) => cv,
-        Err(_) => return (None, None),
-    };
-    log_cv!(PrintType::Omega, response_cv);
-
-    let user_id = match response_cv.get_data(DataTypes::user_id).as_number() {
-        Some(id) => id,
-        None => return (None, None),
+    let user_id = match conn.request(&GetRegister).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            log!("Reserving a user id failed: {}", e);
+            return None;
+        }
     };
     let mut buf = [0u8; 56];
     let mut rng = OsRng;
@@ -90,40 +91,120 @@ pub async fn create_user(username: &str) -> (Option<UserProfile>, Option<String>
         reset_token.clone(),
     );
 
-    let cv = CommunicationValue::new(CommunicationType::complete_register_user)
-        .add_data(DataTypes::user_id, DataValue::Number(user_id))
-        .add_data(DataTypes::username, DataValue::Str(username.to_string()))
-        .add_data(
-            DataTypes::public_key,
-            DataValue::Str(public_key_to_base64(&public_key)),
-        )
-        .add_data(DataTypes::iota_id, DataValue::Number(user_id))
-        .add_data(DataTypes::reset_token, DataValue::Str(reset_token));
-
-    let response_cv = conn
-        .await_response(&cv, Some(Duration::from_secs(20)))
-        .await;
-
-    if let Ok(resp) = response_cv {
-        log_cv!(PrintType::Omega, resp);
-        if !resp.is_type(CommunicationType::success) {
-            return (None, None);
+    // Until the bundle is written the private key only exists here.
+    if let Err(e) = key_bundle::store(
+        user_id,
+        username,
+        &STANDARD.encode(&private_key.as_bytes()),
+        protection,
+    ) {
+        log!("Sealing the key of {} failed: {}", username, e);
+        delete_user_directory(user_id);
+        return None;
+    }
+    if let Err(e) = add_user(up.clone()).await {
+        log!("Storing {} failed: {}", username, e);
+        delete_user_directory(user_id);
+        return None;
+    }
+
+    let complete = CompleteRegisterUser {
+        user_id,
+        username: username.to_string(),
+        public_key: public_key_to_base64(&public_key),
+        iota_id: CONFIG.read().await.get_iota_id(),
+        reset_token,
+    };
+    if let Err(e) = conn.request(&complete).await {
+        log!("Completing the registration of {} failed: {}", username, e);
+        if let Err(e) = remove_user(user_id).await {
+            log!("[IMPORTANT] Couldn't roll back {}: {}", username, e);
         }
-    } else {
-        return (None, None);
+        delete_user_directory(user_id);
+        return None;
     }
     *SHUTDOWN.write().await = true;
     *RELOAD.write().await = true;
     log!("Created User");
-    save_file(
-        "",
-        &format!("{}.tu", username),
-        &format!("{}::{}", user_id, STANDARD.encode(&private_key.as_bytes())),
-    );
+    Some(up)
+}
+
+/// Gives `user_id` a new keypair and reset token, for users who lost their
+/// key bundle. The new key is staged with `key_bundle::stage` before
+/// Omikron is asked to accept it against the old reset token, so no later
+/// failure loses it. The profile is then stored, with the messages resealed
+/// for the new key in the same transaction, and the staged key becomes the
+/// bundle.
+///
+/// A re-issue that failed after staging is resumed by the next call, with
+/// the staged key and the protection it was sealed with: Omikron accepts
+/// the same reset again, so it is re-sent even if the first one landed.
+pub async fn reissue_key(user_id: i64, protection: &Protection) -> Result<(), String> {
+    let mut user = get_user(user_id).ok_or(format!("user {} not found", user_id))?;
+    let old_keys = keys_for(user_id).ok_or("keystore is not loaded")?;
+
+    let resumed = key_bundle::staged(user_id).map_err(|e| e.to_string())?;
+    let resuming = resumed.is_some();
+    let staged = match resumed {
+        Some(staged) => {
+            log!("Resuming the unfinished key re-issue of {}", user.username);
+            staged
+        }
+        None => {
+            let key_pair = crypto_helper::generate_keypair();
+            let private_key = crypto_helper::secret_key_to_base64(&key_pair.secret);
+            let staged = Staged {
+                public_key: public_key_to_base64(&key_pair.public),
+                private_key_hash: crypto_helper::hex_hash(&private_key),
+                reset_token: user.clone().randomize_reset_token(),
+            };
+            key_bundle::stage(user_id, &user.username, &private_key, protection, &staged)
+                .map_err(|e| e.to_string())?;
+            staged
+        }
+    };
+
+    let reset = ResetUserKey {
+        user_id,
+        public_key: staged.public_key.clone(),
+        reset_token: user.reset_token.clone(),
+        new_reset_token: staged.reset_token.clone(),
+    };
+    match OMIKRON_CONNECTION.request(&reset).await {
+        Ok(()) => {}
+        // Omikron refused a key it never saw, so the old key stays valid. A
+        // resumed key is kept: Omikron may hold it already.
+        Err(e @ RequestError::Remote(_)) if !resuming => {
+            if let Err(e) = key_bundle::discard_staged(user_id) {
+                log!(
+                    "Discarding the staged key of {} failed: {}",
+                    user.username,
+                    e
+                );
+            }
+            return Err(e.to_string());
+        }
+        Err(e) => return Err(e.to_string()),
+    }
 
-    USERS.lock().unwrap().push(up.clone());
-    save_users();
-    (Some(up), Some(STANDARD.encode(&private_key.as_bytes())))
+    user.public_key = staged.public_key;
+    user.private_key_hash = staged.private_key_hash;
+    user.reset_token = staged.reset_token;
+    let iota = keystore::get_keys().ok_or("keystore is not loaded")?;
+    let new_keys =
+        UserKeys::new(&iota.private_key, &user.public_key).ok_or("new key could not be used")?;
+    let stored = user.clone();
+    MESSAGES_DB
+        .write(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            message_crypto::reseal(&tx, user_id, Some(&old_keys), &new_keys)?;
+            users_util::update_user(&tx, &stored)?;
+            tx.commit()
+        })
+        .await?;
+    cache(user);
+
+    key_bundle::promote(user_id).map_err(|e| e.to_string())
 }
 
 pub fn get_user_by_username(username: &str) -> Option<UserProfile> {
@@ -148,50 +229,88 @@ pub fn get_users() -> Vec<UserProfile> {
     USERS.lock().unwrap().clone()
 }
 
-pub fn remove_user(user_id: i64) {
-    let mut users = USERS.lock().unwrap();
-    users.retain(|u| u.user_id != user_id);
-    *UNIQUE.lock().unwrap() = true;
+/// Stores a new profile.
+pub async fn add_user(user: UserProfile) -> Result<(), String> {
+    let stored = user.clone();
+    MESSAGES_DB
+        .write(move |conn| users_util::insert_user(conn, &stored))
+        .await?;
+    USERS.lock().unwrap().push(user);
+    Ok(())
 }
 
-pub fn save_users() {
-    *UNIQUE.lock().unwrap() = false;
-    let users = USERS.lock().unwrap();
-    let arr: Vec<JsonValue> = users.iter().map(|u| u.to_json()).collect();
-    let json_str = JsonValue::Array(arr).dump();
+/// Replaces the stored profile with the same id.
+pub async fn update_user(user: &UserProfile) -> Result<(), String> {
+    let stored = user.clone();
+    if !MESSAGES_DB
+        .write(move |conn| users_util::update_user(conn, &stored))
+        .await?
+    {
+        return Err(format!("user {} not found", user.user_id));
+    }
+    cache(user.clone());
+    Ok(())
+}
+
+fn cache(user: UserProfile) {
+    let mut users = USERS.lock().unwrap();
+    if let Some(cached) = users.iter_mut().find(|u| u.user_id == user.user_id) {
+        *cached = user;
+    }
+}
 
-    save_file("", "users.json", &json_str);
+/// Deletes the profile and every DB row of the user in one transaction.
+/// Returns `false` if there was no such user. `user_purge::purge` is the
+/// complete removal.
+pub async fn remove_user(user_id: i64) -> Result<bool, String> {
+    let removed = MESSAGES_DB
+        .write(move |conn| users_util::delete_user(conn, user_id))
+        .await?;
+    USERS.lock().unwrap().retain(|u| u.user_id != user_id);
+    Ok(removed)
 }
 
+/// Drops the cache; the next `load_users` reads the table again.
 pub fn clear() {
-    let mut users = USERS.lock().unwrap();
-    users.clear();
-    *UNIQUE.lock().unwrap() = true;
+    USERS.lock().unwrap().clear();
 }
 
+/// Fills the cache from the `users` table, after importing `users.json`
+/// if one is left from an older version. The file is kept as
+/// `users.json.imported`.
 pub async fn load_users() -> io::Result<()> {
+    if has_file("", "users.json") {
+        let imported = import_users_json().await?;
+        log!("Imported {} users from users.json", imported);
+        rename_file("", "users.json", "users.json.imported");
+    }
+
+    let users = MESSAGES_DB
+        .read(users_util::get_users)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    *USERS.lock().unwrap() = users;
+    Ok(())
+}
+
+async fn import_users_json() -> io::Result<usize> {
     let content = load_file("", "users.json");
     if content.trim().is_empty() {
-        return Ok(());
+        return Ok(0);
     }
 
     let parsed =
         json::parse(&content).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let mut profiles = Vec::new();
     if let JsonValue::Array(arr) = parsed {
-        let mut users = USERS.lock().unwrap();
         for j in arr.iter() {
             if let Some(up) = UserProfile::from_json(j).await {
-                users.push(up);
+                profiles.push(up);
             }
         }
     }
-    if *UNIQUE.lock().unwrap() {
-        save_users();
-    }
-    Ok(())
-}
-
-#[allow(dead_code)]
-pub fn set_unique(val: bool) {
-    *UNIQUE.lock().unwrap() = val;
+    MESSAGES_DB
+        .write(move |conn| users_util::import_users(conn, &profiles))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
 }
@@ -0,0 +1,331 @@
+//! Moving a user to another Iota.
+//!
+//! `export` packs the profile, a pending key bundle, the stored
+//! conversations (as `export_util` JSON Lines) and the settings of a user
+//! into a bundle only the target Iota can open: the payload is encrypted
+//! between this Iota's identity and the target's public key. From then on
+//! the profile carries `moved_at` and this Iota refuses the user's traffic;
+//! `cancel` takes that back as long as the target has not taken over.
+//! `import` on the target only accepts a bundle sealed by the source key the
+//! operator names, stores everything and has Omikron route the user there.
+//! The source keeps its copy until it is purged.
+
+use crate::log;
+use crate::omikron::omikron_connection::OMIKRON_CONNECTION;
+use crate::omikron::requests::{MigrateUser, RequestError};
+use crate::users::key_bundle;
+use crate::users::user_manager;
+use crate::users::user_profile::UserProfile;
+use crate::util::config_util::CONFIG;
+use crate::util::crypto_helper;
+use crate::util::export_util;
+use crate::util::file_util::{delete_user_directory, get_children, load_file, save_file};
+use crate::util::keystore::{self, IotaKeys};
+use json::{JsonValue, object};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+pub const MIGRATION_VERSION: u32 = 1;
+
+/// Where the console leaves exported bundles.
+pub const MIGRATION_DIR: &str = "migrations";
+
+/// How often `MigrateUser` is sent before its outcome counts as unknown.
+const ROUTE_ATTEMPTS: u32 = 3;
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn settings_dir(user_id: i64) -> String {
+    format!("users/{}/settings/", user_id)
+}
+
+/// Exports `user_id` for the Iota with `target_public_key` and marks the
+/// user as moved. Exporting again, e.g. for a lost bundle, keeps the first
+/// `moved_at`.
+pub async fn export(user_id: i64, target_public_key: &str) -> Result<String, String> {
+    let mut user = user_manager::get_user(user_id).ok_or(format!("user {} not found", user_id))?;
+    let iota = keystore::get_keys().ok_or("keystore is not loaded")?;
+    crypto_helper::load_public_key(target_public_key).ok_or("invalid target public key")?;
+
+    // Refuse traffic before taking the snapshot, so nothing arrives here
+    // that the bundle misses.
+    let first_export = user.moved_at.is_none();
+    if first_export {
+        user.moved_at = Some(now_millis());
+        user_manager::update_user(&user).await?;
+    }
+
+    match pack(&user, &iota, target_public_key).await {
+        Ok(bundle) => Ok(bundle.pretty(2)),
+        Err(e) => {
+            if first_export {
+                user.moved_at = None;
+                if let Err(e) = user_manager::update_user(&user).await {
+                    log!("[IMPORTANT] Couldn't unmark {}: {}", user.username, e);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn pack(
+    user: &UserProfile,
+    iota: &IotaKeys,
+    target_public_key: &str,
+) -> Result<JsonValue, String> {
+    let key_bundle = key_bundle::peek(user.user_id)
+        .map_err(|e| e.to_string())?
+        .unwrap_or(JsonValue::Null);
+
+    let dir = settings_dir(user.user_id);
+    let mut settings = JsonValue::new_object();
+    for name in get_children(&dir) {
+        if let Some(setting) = name.strip_suffix(".settings") {
+            settings[setting] = load_file(&dir, &name).into();
+        }
+    }
+
+    let contents = object! {
+        "profile" => user.to_json(),
+        "key_bundle" => key_bundle,
+        "history" => export_util::export(user.user_id).await?,
+        "settings" => settings,
+    };
+    let source_iota_id = CONFIG.read().await.get_iota_id();
+    seal(
+        &contents,
+        user.user_id,
+        source_iota_id,
+        iota,
+        target_public_key,
+    )
+}
+
+fn seal(
+    contents: &JsonValue,
+    user_id: i64,
+    source_iota_id: i64,
+    source: &IotaKeys,
+    target_public_key: &str,
+) -> Result<JsonValue, String> {
+    let payload = crypto_helper::encrypt(&source.private_key, target_public_key, &contents.dump())
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(object! {
+        "kind" => "migration",
+        "version" => MIGRATION_VERSION,
+        "user_id" => user_id,
+        "source_iota_id" => source_iota_id,
+        "source_public_key" => source.public_key.clone(),
+        "payload" => payload,
+    })
+}
+
+/// Opens `bundle` if `source_public_key` sealed it for `target`.
+fn open(
+    bundle: &JsonValue,
+    target: &IotaKeys,
+    source_public_key: &str,
+) -> Result<JsonValue, String> {
+    if bundle["kind"] != "migration" {
+        return Err("not a migration bundle".to_string());
+    }
+    if bundle["version"].as_u32().unwrap_or(0) > MIGRATION_VERSION {
+        return Err(format!(
+            "migration version {} is newer than this Iota",
+            bundle["version"]
+        ));
+    }
+    if bundle["source_public_key"].as_str() != Some(source_public_key) {
+        return Err("bundle is not from the expected source Iota".to_string());
+    }
+    let payload = bundle["payload"]
+        .as_str()
+        .ok_or("bundle holds no payload")?;
+    let contents = crypto_helper::decrypt(&target.private_key, source_public_key, payload)
+        .map_err(|_| "bundle is not for this Iota or was altered".to_string())?;
+    json::parse(&contents).map_err(|e| e.to_string())
+}
+
+/// Keeps a user on this Iota after all. Returns `false` if they were not
+/// marked as moved. Only safe while the target has not imported them.
+pub async fn cancel(user_id: i64) -> Result<bool, String> {
+    let mut user = user_manager::get_user(user_id).ok_or(format!("user {} not found", user_id))?;
+    if user.moved_at.take().is_none() {
+        return Ok(false);
+    }
+    user_manager::update_user(&user).await?;
+    Ok(true)
+}
+
+/// Takes over the user in `bundle`, which the Iota with `source_public_key`
+/// must have sealed. Nothing is kept if storing fails or Omikron refuses to
+/// route the user here. If Omikron does not answer, the user is kept and
+/// importing the same bundle again resumes the move.
+pub async fn import(bundle: &str, source_public_key: &str) -> Result<UserProfile, String> {
+    let iota = keystore::get_keys().ok_or("keystore is not loaded")?;
+    let bundle = json::parse(bundle).map_err(|e| e.to_string())?;
+    let contents = open(&bundle, &iota, source_public_key)?;
+
+    let mut user = UserProfile::from_json(&contents["profile"])
+        .await
+        .ok_or("bundle holds no valid profile")?;
+    if bundle["user_id"].as_i64() != Some(user.user_id) {
+        return Err("bundle and profile name different users".to_string());
+    }
+    user.moved_at = None;
+
+    // Only an earlier import of this bundle leaves the same user here
+    // unmoved, with the token that authorises the move.
+    let resuming = match user_manager::get_user(user.user_id) {
+        Some(existing)
+            if existing.username == user.username
+                && existing.reset_token == user.reset_token
+                && existing.moved_at.is_none() =>
+        {
+            true
+        }
+        Some(_) => return Err(format!("{} already exists on this Iota", user.username)),
+        None if user_manager::get_user_by_username(&user.username).is_some() => {
+            return Err(format!("{} already exists on this Iota", user.username));
+        }
+        None => false,
+    };
+
+    if !resuming {
+        user_manager::add_user(user.clone()).await?;
+        if let Err(e) = take_over(&user, &contents).await {
+            roll_back(&user).await;
+            return Err(e);
+        }
+    }
+    match route_here(&user).await {
+        Ok(()) => {}
+        Err(e @ RequestError::Remote(_)) => {
+            roll_back(&user).await;
+            return Err(e.to_string());
+        }
+        Err(e) => {
+            log!(
+                "[IMPORTANT] Omikron did not confirm the move of {}: {}",
+                user.username,
+                e
+            );
+            return Err(format!(
+                "Omikron did not confirm the move ({}); {} is kept here, receive the bundle again to finish it",
+                e, user.username
+            ));
+        }
+    }
+    log!(
+        "Took over {} from Iota {}",
+        user.username,
+        bundle["source_iota_id"]
+    );
+    Ok(user)
+}
+
+async fn roll_back(user: &UserProfile) {
+    if let Err(e) = user_manager::remove_user(user.user_id).await {
+        log!("[IMPORTANT] Couldn't roll back {}: {}", user.username, e);
+    }
+    delete_user_directory(user.user_id);
+}
+
+async fn take_over(user: &UserProfile, contents: &JsonValue) -> Result<(), String> {
+    export_util::import(
+        user.user_id,
+        contents["history"].as_str().unwrap_or_default(),
+    )
+    .await?;
+
+    let dir = settings_dir(user.user_id);
+    for (name, value) in contents["settings"].entries() {
+        if name.contains('/') || name.contains("..") {
+            continue;
+        }
+        save_file(
+            &dir,
+            &format!("{}.settings", name),
+            value.as_str().unwrap_or_default(),
+        );
+    }
+
+    if !contents["key_bundle"].is_null() {
+        key_bundle::restore(user.user_id, &contents["key_bundle"]).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Has Omikron route `user` here. A timeout or a lost connection says
+/// nothing about whether Omikron applied the move, so the request is sent
+/// again until Omikron answers or `ROUTE_ATTEMPTS` run out.
+async fn route_here(user: &UserProfile) -> Result<(), RequestError> {
+    let migrate = MigrateUser {
+        user_id: user.user_id,
+        iota_id: CONFIG.read().await.get_iota_id(),
+        reset_token: user.reset_token.clone(),
+    };
+    let mut attempt = 1;
+    loop {
+        match OMIKRON_CONNECTION.request(&migrate).await {
+            Err(RequestError::Timeout(_) | RequestError::Disconnected(_))
+                if attempt < ROUTE_ATTEMPTS =>
+            {
+                attempt += 1;
+                sleep(Duration::from_secs(5)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iota_keys() -> IotaKeys {
+        let pair = crypto_helper::generate_keypair();
+        IotaKeys {
+            public_key: crypto_helper::public_key_to_base64(&pair.public),
+            private_key: crypto_helper::secret_key_to_base64(&pair.secret),
+        }
+    }
+
+    #[test]
+    fn only_the_target_opens_a_bundle() {
+        let (source, target, other) = (iota_keys(), iota_keys(), iota_keys());
+        let contents = object! { "history" => "{}", "settings" => object! { "theme" => "dark" } };
+
+        let bundle = seal(&contents, 7, 3, &source, &target.public_key).unwrap();
+        assert!(!bundle.dump().contains("dark"));
+        assert_eq!(
+            open(&bundle, &target, &source.public_key).unwrap(),
+            contents
+        );
+        assert!(open(&bundle, &other, &source.public_key).is_err());
+
+        let mut newer = bundle.clone();
+        newer["version"] = (MIGRATION_VERSION + 1).into();
+        assert!(open(&newer, &target, &source.public_key).is_err());
+    }
+
+    #[test]
+    fn bundles_from_an_unexpected_source_are_refused() {
+        let (source, target, forger) = (iota_keys(), iota_keys(), iota_keys());
+        let contents = object! { "history" => "{}" };
+
+        let forged = seal(&contents, 7, 3, &forger, &target.public_key).unwrap();
+        assert!(open(&forged, &target, &source.public_key).is_err());
+        assert!(open(&forged, &target, &forger.public_key).is_ok());
+
+        let mut relabelled = forged.clone();
+        relabelled["source_public_key"] = source.public_key.clone().into();
+        assert!(open(&relabelled, &target, &source.public_key).is_err());
+    }
+}
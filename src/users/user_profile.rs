@@ -1,6 +1,7 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::util::file_util::{has_file, load_file, used_dir_space};
+use crate::users::key_bundle;
+use crate::util::file_util::used_dir_space;
 use base64::{Engine as _, engine::general_purpose};
 use json::{JsonValue, object};
 use rand::Rng;
@@ -16,6 +17,9 @@ pub struct UserProfile {
     pub reset_token: String,
     pub created_at: i64,
     pub display_name: Option<String>,
+    /// Set once the user was exported to another Iota; from then on this
+    /// Iota refuses their traffic.
+    pub moved_at: Option<i64>,
 }
 
 impl UserProfile {
@@ -38,6 +42,7 @@ impl UserProfile {
                 .unwrap()
                 .as_millis() as i64,
             reset_token,
+            moved_at: None,
         }
     }
 
@@ -53,9 +58,14 @@ impl UserProfile {
         if let Some(d) = &self.display_name {
             obj["display_name"] = d.clone().into();
         }
+        if let Some(moved_at) = self.moved_at {
+            obj["moved_at"] = moved_at.into();
+        }
         obj
     }
-    pub fn frontend(&self) -> JsonValue {
+    /// What the web UI shows of the user. `purge_at` is their scheduled
+    /// purge, which the caller looks up; nothing here touches the DB.
+    pub fn frontend(&self, purge_at: Option<i64>) -> JsonValue {
         let mut obj = object! {
             "uuid" => self.user_id,
             "username" => self.username.clone(),
@@ -67,8 +77,13 @@ impl UserProfile {
         if let Some(d) = &self.display_name {
             obj["display_name"] = d.clone().into();
         }
-        if has_file("", &format!("{}.tu", self.username.clone())) {
-            obj["tu"] = load_file("", &format!("{}.tu", self.username.clone())).into();
+        // The key itself is only handed out once, through `key_bundle::take`.
+        obj["key_pending"] = key_bundle::has_bundle(self.user_id).into();
+        if let Some(purge_at) = purge_at {
+            obj["purge_at"] = purge_at.into();
+        }
+        if let Some(moved_at) = self.moved_at {
+            obj["moved_at"] = moved_at.into();
         }
 
         obj
@@ -81,7 +96,10 @@ impl UserProfile {
         let reset_token = j["reset_token"].as_str()?.to_string();
         let created_at = j["created_at"].as_i64()?;
         let display_name = j["display_name"].as_str().map(|s| s.to_string());
+        let moved_at = j["moved_at"].as_i64();
 
+        // Moving a user between Iotas is `user_migration`; a profile in its
+        // bundle keeps the `moved_at` of the Iota it left.
         let up = UserProfile {
             user_id,
             username,
@@ -90,25 +108,12 @@ impl UserProfile {
             private_key_hash,
             created_at,
             reset_token,
+            moved_at,
         };
 
-        // TODO: Migrate to Omikron / Wss
-        /* if j.has_key("migrate")
-            || j.has_key("migrating")
-            || j.has_key("changing")
-            || j.has_key("move")
-            || j.has_key("moving")
-        {
-            if auth_connector::migrate_user(&mut up).await {
-                log_message(format!("[INFO] Migration triggered for {}", up.username));
-                user_manager::set_unique(true);
-            }
-        } */
-
         Some(up)
     }
 
-    #[allow(dead_code)]
     pub fn randomize_reset_token(&mut self) -> String {
         let mut bytes = [0u8; 192];
         OsRng.fill(bytes.as_mut());
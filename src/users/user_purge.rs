@@ -0,0 +1,115 @@
+//! Removing a user and everything the Iota stores for them.
+//!
+//! `purge` de-registers the user at Omikron, then deletes the profile and
+//! all DB rows in one transaction and finally `users/<id>/`, which holds
+//! the settings and a pending key bundle. Nothing is kept: an
+//! export wanted before the removal is taken by the caller with
+//! `export_util::export` and handed to the operator. With a grace period the
+//! purge is only scheduled; the retention task runs it once due and
+//! `cancel` keeps the user.
+
+use crate::log;
+use crate::omikron::omikron_connection::OMIKRON_CONNECTION;
+use crate::omikron::requests::DeleteUser;
+use crate::users::user_manager;
+use crate::util::db::MESSAGES_DB;
+use crate::util::file_util::delete_user_directory;
+use crate::util::users_util;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Purges `user_id` now. Nothing is deleted if the Omikron de-registration
+/// fails. Users who moved to another Iota are only removed here.
+pub async fn purge(user_id: i64) -> Result<(), String> {
+    let user = user_manager::get_user(user_id).ok_or(format!("user {} not found", user_id))?;
+
+    // A user who moved away is registered to the other Iota now.
+    if user.moved_at.is_none() {
+        let delete = DeleteUser {
+            user_id,
+            reset_token: user.reset_token.clone(),
+        };
+        OMIKRON_CONNECTION
+            .request(&delete)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    user_manager::remove_user(user_id).await?;
+    delete_user_directory(user_id);
+    Ok(())
+}
+
+/// A grace period of `days`, or `None` if that many seconds overflow.
+pub fn grace_period(days: u64) -> Option<Duration> {
+    days.checked_mul(24 * 60 * 60).map(Duration::from_secs)
+}
+
+/// Purges `user_id` once `grace` has passed. Returns when, in milliseconds.
+pub async fn schedule(user_id: i64, grace: Duration) -> Result<i64, String> {
+    if user_manager::get_user(user_id).is_none() {
+        return Err(format!("user {} not found", user_id));
+    }
+    let purge_at = i64::try_from(grace.as_millis())
+        .ok()
+        .and_then(|grace| now_millis().checked_add(grace))
+        .ok_or("grace period is too long")?;
+    MESSAGES_DB
+        .write(move |conn| users_util::schedule_purge(conn, user_id, purge_at))
+        .await?;
+    Ok(purge_at)
+}
+
+/// Keeps a user whose purge is scheduled. Returns `false` if none was.
+pub async fn cancel(user_id: i64) -> Result<bool, String> {
+    MESSAGES_DB
+        .write(move |conn| users_util::cancel_purge(conn, user_id))
+        .await
+}
+
+pub async fn scheduled_at(user_id: i64) -> Option<i64> {
+    MESSAGES_DB
+        .read(move |conn| users_util::purge_at(conn, user_id))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// When each user with a scheduled purge is due, by user id.
+pub async fn schedules() -> Result<HashMap<i64, i64>, String> {
+    let purges = MESSAGES_DB.read(users_util::scheduled_purges).await?;
+    Ok(purges.into_iter().collect())
+}
+
+/// Runs every purge that is due. Failed ones stay scheduled and are retried
+/// on the next run; schedules of users that no longer exist are dropped.
+pub async fn run_due() {
+    let now = now_millis();
+    let due = match MESSAGES_DB
+        .read(move |conn| users_util::due_purges(conn, now))
+        .await
+    {
+        Ok(due) => due,
+        Err(e) => {
+            log!("Failed to query scheduled purges: {}", e);
+            return;
+        }
+    };
+    for user_id in due {
+        if user_manager::get_user(user_id).is_none() {
+            let _ = cancel(user_id).await;
+            continue;
+        }
+        match purge(user_id).await {
+            Ok(()) => log!("Purged user {}", user_id),
+            Err(e) => log!("Purging user {} failed: {}", user_id, e),
+        }
+    }
+}
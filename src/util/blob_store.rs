@@ -0,0 +1,496 @@
+//! Content-addressed store for message attachments.
+//!
+//! Blobs live under `blobs/<2 hex>/<sha256 hex>` in the data directory and
+//! are described by the `blobs` table. `messages.blob_hash` references them;
+//! triggers keep `blobs.refcount` equal to the number of referencing rows.
+//! Uploads arrive in chunks and are staged under `users/<id>/uploads`. An
+//! unfinished upload reserves its announced size against its user's quota
+//! until it completes or is abandoned for `UPLOAD_EXPIRY_MS`.
+
+use crate::log;
+use crate::util::crypto_helper::hex_hash;
+use crate::util::db::MESSAGES_DB;
+use crate::util::file_util::{get_directory, used_dir_space};
+use dashmap::DashMap;
+use rusqlite::params;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Largest chunk accepted on upload and returned on download.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Unreferenced blobs younger than this are kept so a client can upload
+/// first and send the message referencing it afterwards.
+const UNREFERENCED_GRACE_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Uploads without a chunk for this long are dropped with their staged part.
+const UPLOAD_EXPIRY_MS: i64 = 60 * 60 * 1000;
+
+#[derive(Debug, PartialEq)]
+pub enum BlobError {
+    InvalidHash,
+    QuotaExceeded {
+        used: u64,
+        quota: u64,
+    },
+    UnknownUpload,
+    /// Chunks must arrive in order; this is the offset that was expected.
+    OutOfOrder {
+        expected: u64,
+    },
+    TooLarge,
+    HashMismatch,
+    NotFound,
+    Io(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::InvalidHash => write!(f, "invalid blob hash"),
+            BlobError::QuotaExceeded { used, quota } => {
+                write!(f, "quota exceeded ({} of {} bytes used)", used, quota)
+            }
+            BlobError::UnknownUpload => write!(f, "unknown upload"),
+            BlobError::OutOfOrder { expected } => write!(f, "expected chunk at {}", expected),
+            BlobError::TooLarge => write!(f, "chunk exceeds the announced size"),
+            BlobError::HashMismatch => write!(f, "content does not match its hash"),
+            BlobError::NotFound => write!(f, "blob not found"),
+            BlobError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for BlobError {
+    fn from(e: std::io::Error) -> Self {
+        BlobError::Io(e.to_string())
+    }
+}
+
+impl From<String> for BlobError {
+    fn from(e: String) -> Self {
+        BlobError::Io(e)
+    }
+}
+
+struct Upload {
+    user_id: i64,
+    hash: String,
+    size: u64,
+    received: u64,
+    path: PathBuf,
+    /// When the last chunk (or the announcement) arrived.
+    touched_at: i64,
+}
+
+static UPLOADS: LazyLock<DashMap<String, Upload>> = LazyLock::new(|| DashMap::new());
+
+/// Held while checking a quota and reserving the new upload, so concurrent
+/// announcements cannot each fit into the same free space.
+static RESERVE: Mutex<()> = Mutex::new(());
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn uploads_dir(user_id: i64) -> PathBuf {
+    PathBuf::from(get_directory())
+        .join("users")
+        .join(user_id.to_string())
+        .join("uploads")
+}
+
+fn blob_path(hash: &str) -> PathBuf {
+    PathBuf::from(get_directory())
+        .join("blobs")
+        .join(&hash[..2])
+        .join(hash)
+}
+
+pub fn has_blob(hash: &str) -> bool {
+    valid_hash(hash)
+        && blob_path(hash).is_file()
+        && MESSAGES_DB
+            .read_blocking(|conn| {
+                conn.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM blobs WHERE hash = ?1)",
+                    params![hash],
+                    |row| row.get::<_, bool>(0),
+                )
+            })
+            .unwrap_or(false)
+}
+
+/// Bytes charged to `user_id`: their user directory (including staged
+/// uploads) plus every blob they uploaded. Parts of unfinished uploads are
+/// charged as they arrive; `reserved_space` covers the rest.
+pub fn used_space(user_id: i64) -> u64 {
+    let blobs: i64 = MESSAGES_DB
+        .read_blocking(|conn| {
+            conn.query_row(
+                "SELECT COALESCE(SUM(size), 0) FROM blobs WHERE uploaded_by = ?1",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+        .unwrap_or(0);
+    used_dir_space(&format!("users/{}", user_id)) + blobs as u64
+}
+
+/// Bytes announced by `user_id`'s unfinished uploads but not received yet.
+fn reserved_space(user_id: i64) -> u64 {
+    UPLOADS
+        .iter()
+        .filter(|upload| upload.user_id == user_id)
+        .map(|upload| upload.size - upload.received)
+        .sum()
+}
+
+/// Drops uploads that went quiet for `UPLOAD_EXPIRY_MS`, and staged parts no
+/// upload owns anymore, e.g. from before a restart. Returns how many parts
+/// were deleted.
+pub fn expire_uploads() -> usize {
+    let cutoff = now_millis() - UPLOAD_EXPIRY_MS;
+    // A part is created just before its upload is registered, under this lock.
+    let _reserve = RESERVE.lock().unwrap_or_else(|e| e.into_inner());
+    UPLOADS.retain(|_, upload| upload.touched_at >= cutoff);
+
+    let live: Vec<PathBuf> = UPLOADS.iter().map(|upload| upload.path.clone()).collect();
+    let users = PathBuf::from(get_directory()).join("users");
+    let Ok(entries) = fs::read_dir(users) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let Ok(parts) = fs::read_dir(entry.path().join("uploads")) else {
+            continue;
+        };
+        for part in parts.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if part.extension().is_some_and(|ext| ext == "part") && !live.contains(&part) {
+                match fs::remove_file(&part) {
+                    Ok(()) => removed += 1,
+                    Err(e) => log!("Couldn't delete upload {}: {}", part.display(), e),
+                }
+            }
+        }
+    }
+    removed
+}
+
+/// Announces an upload of `size` bytes hashing to `hash`. Returns `None`
+/// when the blob is already stored and nothing needs to be sent, otherwise
+/// the id to send chunks to.
+pub fn start_upload(
+    user_id: i64,
+    hash: &str,
+    size: u64,
+    quota: u64,
+) -> Result<Option<String>, BlobError> {
+    if !valid_hash(hash) {
+        return Err(BlobError::InvalidHash);
+    }
+    if has_blob(hash) {
+        return Ok(None);
+    }
+
+    // Starting uploads is rare enough to sweep unreferenced blobs here.
+    collect_garbage();
+    expire_uploads();
+
+    let _reserve = RESERVE.lock().unwrap_or_else(|e| e.into_inner());
+    let used = used_space(user_id).saturating_add(reserved_space(user_id));
+    if used.saturating_add(size) > quota {
+        return Err(BlobError::QuotaExceeded { used, quota });
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let dir = uploads_dir(user_id);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.part", upload_id));
+    fs::File::create(&path)?;
+
+    UPLOADS.insert(
+        upload_id.clone(),
+        Upload {
+            user_id,
+            hash: hash.to_string(),
+            size,
+            received: 0,
+            path,
+            touched_at: now_millis(),
+        },
+    );
+    Ok(Some(upload_id))
+}
+
+/// Appends the chunk at `offset`. Returns the blob hash once the upload is
+/// complete and verified, `None` while more chunks are expected.
+pub fn append_chunk(
+    user_id: i64,
+    upload_id: &str,
+    offset: u64,
+    data: &[u8],
+) -> Result<Option<String>, BlobError> {
+    let mut upload = match UPLOADS.get_mut(upload_id) {
+        Some(upload) if upload.user_id == user_id => upload,
+        _ => return Err(BlobError::UnknownUpload),
+    };
+    if offset != upload.received {
+        return Err(BlobError::OutOfOrder {
+            expected: upload.received,
+        });
+    }
+    if data.len() > CHUNK_SIZE || upload.received + data.len() as u64 > upload.size {
+        return Err(BlobError::TooLarge);
+    }
+
+    OpenOptions::new()
+        .append(true)
+        .open(&upload.path)?
+        .write_all(data)?;
+    upload.received += data.len() as u64;
+    upload.touched_at = now_millis();
+    if upload.received < upload.size {
+        return Ok(None);
+    }
+    drop(upload);
+
+    let (_, upload) = UPLOADS.remove(upload_id).ok_or(BlobError::UnknownUpload)?;
+    let result = commit(&upload);
+    let _ = fs::remove_file(&upload.path);
+    result.map(|_| Some(upload.hash))
+}
+
+fn commit(upload: &Upload) -> Result<(), BlobError> {
+    let content = fs::read(&upload.path)?;
+    if hex_hash(&content) != upload.hash {
+        return Err(BlobError::HashMismatch);
+    }
+
+    // The blob only appears under its final name once it is complete, so a
+    // file found there is whole; one of another size predates that.
+    let target = blob_path(&upload.hash);
+    if fs::metadata(&target).map_or(true, |m| m.len() != upload.size) {
+        fs::create_dir_all(target.parent().unwrap())?;
+        let tmp = target.with_file_name(format!("{}.{}.tmp", upload.hash, Uuid::new_v4()));
+        let written = fs::File::create(&tmp)
+            .and_then(|mut file| {
+                file.write_all(&content)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, &target));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+    }
+
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute(
+            r#"
+            INSERT INTO blobs (hash, size, uploaded_by, created_at, refcount)
+            VALUES (?1, ?2, ?3, ?4, (SELECT COUNT(*) FROM messages WHERE blob_hash = ?1))
+            ON CONFLICT (hash) DO NOTHING
+            "#,
+            params![
+                upload.hash,
+                upload.size as i64,
+                upload.user_id,
+                now_millis()
+            ],
+        )
+    })?;
+    Ok(())
+}
+
+/// Whether `user_id` may download `hash`: they uploaded it or one of their
+/// stored messages references it.
+fn can_read(user_id: i64, hash: &str) -> bool {
+    MESSAGES_DB
+        .read_blocking(|conn| {
+            conn.query_row(
+                r#"
+            SELECT EXISTS (SELECT 1 FROM blobs WHERE hash = ?2 AND uploaded_by = ?1)
+                OR EXISTS (SELECT 1 FROM messages WHERE storage_owner = ?1 AND blob_hash = ?2)
+            "#,
+                params![user_id, hash],
+                |row| row.get::<_, bool>(0),
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Up to `CHUNK_SIZE` bytes of `hash` starting at `offset`, and the blob's
+/// total size. An empty chunk means `offset` is at or past the end.
+pub fn read_chunk(user_id: i64, hash: &str, offset: u64) -> Result<(Vec<u8>, u64), BlobError> {
+    if !valid_hash(hash) {
+        return Err(BlobError::InvalidHash);
+    }
+    if !can_read(user_id, hash) {
+        return Err(BlobError::NotFound);
+    }
+    let mut file = fs::File::open(blob_path(hash)).map_err(|_| BlobError::NotFound)?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset.min(size)))?;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+    Ok((chunk, size))
+}
+
+/// References `hash` from `storage_owner`'s copy of message `message_id` from
+/// or to `external_user`. The blob does not have to be stored here; copies
+/// relayed from another Iota keep the reference so clients can fetch it from
+/// there.
+pub fn attach(
+    storage_owner: i64,
+    external_user: i64,
+    message_id: u32,
+    hash: &str,
+) -> Result<(), BlobError> {
+    if !valid_hash(hash) {
+        return Err(BlobError::InvalidHash);
+    }
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute(
+            r#"
+            UPDATE messages SET blob_hash = ?4
+            WHERE storage_owner = ?1 AND external_user = ?2 AND message_id = ?3
+            "#,
+            params![storage_owner, external_user, message_id as i64, hash],
+        )
+    })?;
+    Ok(())
+}
+
+/// Deletes blobs no message references anymore once their grace period is
+/// over. Returns how many were removed.
+pub fn collect_garbage() -> usize {
+    let cutoff = now_millis() - UNREFERENCED_GRACE_MS;
+    let res = MESSAGES_DB.write_blocking(|conn| {
+        let tx = conn.unchecked_transaction()?;
+        let hashes = {
+            let mut stmt =
+                tx.prepare("SELECT hash FROM blobs WHERE refcount <= 0 AND created_at < ?1")?;
+            let rows = stmt.query_map(params![cutoff], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        tx.execute(
+            "DELETE FROM blobs WHERE refcount <= 0 AND created_at < ?1",
+            params![cutoff],
+        )?;
+        tx.commit()?;
+        Ok(hashes)
+    });
+
+    match res {
+        Ok(hashes) => {
+            for hash in &hashes {
+                if let Err(e) = fs::remove_file(blob_path(hash)) {
+                    log!("Couldn't delete blob {}: {}", hash, e);
+                }
+            }
+            hashes.len()
+        }
+        Err(e) => {
+            log!("Failed to collect unreferenced blobs: {}", e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ttp_core::rand_u32;
+
+    #[test]
+    fn chunked_upload_is_verified_and_deduplicated() {
+        let user_id = 6_000_000 + rand_u32() as i64;
+        let content = format!("attachment {}", user_id).into_bytes();
+        let hash = hex_hash(&content);
+
+        let upload_id = start_upload(user_id, &hash, content.len() as u64, u64::MAX)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            append_chunk(user_id, &upload_id, 4, &content[4..]),
+            Err(BlobError::OutOfOrder { expected: 0 })
+        );
+        assert_eq!(
+            append_chunk(user_id, &upload_id, 0, &content[..4]),
+            Ok(None)
+        );
+        assert_eq!(
+            append_chunk(user_id, &upload_id, 4, &content[4..]),
+            Ok(Some(hash.clone()))
+        );
+
+        assert!(has_blob(&hash));
+        assert_eq!(start_upload(user_id, &hash, 1, u64::MAX), Ok(None));
+        assert_eq!(
+            read_chunk(user_id, &hash, 0),
+            Ok((content.clone(), content.len() as u64))
+        );
+        assert_eq!(read_chunk(user_id + 1, &hash, 0), Err(BlobError::NotFound));
+    }
+
+    #[test]
+    fn mismatching_content_and_quota_are_refused() {
+        let user_id = 7_000_000 + rand_u32() as i64;
+        let hash = hex_hash(format!("expected {}", user_id));
+
+        assert!(matches!(
+            start_upload(user_id, &hash, 100, 10),
+            Err(BlobError::QuotaExceeded { .. })
+        ));
+
+        let upload_id = start_upload(user_id, &hash, 5, u64::MAX).unwrap().unwrap();
+        assert_eq!(
+            append_chunk(user_id, &upload_id, 0, b"other"),
+            Err(BlobError::HashMismatch)
+        );
+        assert!(!has_blob(&hash));
+        assert_eq!(
+            append_chunk(user_id, &upload_id, 5, b""),
+            Err(BlobError::UnknownUpload)
+        );
+    }
+
+    #[test]
+    fn unfinished_uploads_reserve_quota_until_they_expire() {
+        let user_id = 8_000_000 + rand_u32() as i64;
+        let hash = |n: u32| hex_hash(format!("reserved {} {}", user_id, n));
+        let quota = used_space(user_id) + 150;
+
+        let first = start_upload(user_id, &hash(1), 100, quota)
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            start_upload(user_id, &hash(2), 100, quota),
+            Err(BlobError::QuotaExceeded { .. })
+        ));
+
+        let part = UPLOADS.get(&first).unwrap().path.clone();
+        UPLOADS.get_mut(&first).unwrap().touched_at -= UPLOAD_EXPIRY_MS + 1;
+        expire_uploads();
+        assert!(!UPLOADS.contains_key(&first));
+        assert!(!part.exists());
+        assert!(
+            start_upload(user_id, &hash(2), 100, quota)
+                .unwrap()
+                .is_some()
+        );
+    }
+}
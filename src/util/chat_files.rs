@@ -1,14 +1,23 @@
-use crate::log;
-use crate::util::db;
+//! Message rows of the messages DB.
+//!
+//! Everything here runs on a connection handed out by `db::Database`;
+//! `repository::SqliteStore` decides which one and exposes the async API.
+//! Content is sealed and opened with the storage owner's `UserKeys`, which
+//! callers look up before taking a connection.
+
+use crate::users::contact::Contact;
+use crate::util::chats_util;
+use crate::util::message_crypto::{self, UserKeys};
 use json::{JsonValue, array, object};
-use rusqlite::params;
-use std::io;
-use std::sync::{Arc, LazyLock, Mutex};
+use rusqlite::{Connection, OptionalExtension, params};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum MessageState {
     Read,
     Received,
+    /// The receiver has blocked the sender; the message was not stored or
+    /// delivered on their side.
+    Blocked,
     Sent,
     Sending,
 }
@@ -18,6 +27,7 @@ impl MessageState {
         match self {
             MessageState::Read => "read",
             MessageState::Received => "received",
+            MessageState::Blocked => "blocked",
             MessageState::Sent => "sent",
             MessageState::Sending => "sending",
         }
@@ -27,6 +37,7 @@ impl MessageState {
         match value.to_lowercase().as_str() {
             "read" => MessageState::Read,
             "received" => MessageState::Received,
+            "blocked" => MessageState::Blocked,
             "sent" => MessageState::Sent,
             _ => MessageState::Sending,
         }
@@ -37,6 +48,8 @@ impl MessageState {
             Self::Read
         } else if other == Self::Received || self == Self::Received {
             Self::Received
+        } else if other == Self::Blocked || self == Self::Blocked {
+            Self::Blocked
         } else if other == Self::Sent || self == Self::Sent {
             Self::Sent
         } else {
@@ -45,210 +58,613 @@ impl MessageState {
     }
 }
 
-// Shared DB created via helper.
-// The db helper constructs the messages sqlite file and ensures PRAGMAs and schema exist.
-static MESSAGES_DB: LazyLock<Arc<Mutex<rusqlite::Connection>>> = LazyLock::new(|| {
-    db::create_general_messages_db().expect("Failed to create or initialize general messages DB")
-});
+/// One side's copy of a message as it arrives.
+#[derive(Clone, Debug)]
+pub struct NewMessage {
+    pub message_id: u32,
+    pub send_time: u128,
+    /// Whether the storage owner is the sender.
+    pub sent_by_self: bool,
+    pub storage_owner: i64,
+    pub external_user: i64,
+    pub content: String,
+    pub height: i64,
+}
 
+/// Stores one side's copy of a message. Returns `true` if this owner already
+/// has a copy with `message_id` from the same partner (a redelivery); nothing
+/// is written then.
 pub fn add_message(
-    send_time: u128,
-    storage_owner_is_sender: bool,
-    storage_owner: i64,
-    external_user: i64,
-    message: &str,
-    height: i64,
-) {
-    let message_time = match i64::try_from(send_time) {
-        Ok(v) => v,
-        Err(_) => {
-            log!("Failed to store message: send_time out of range for i64 ({send_time})");
-            return;
-        }
-    };
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    message: &NewMessage,
+) -> rusqlite::Result<bool> {
+    let message_time = i64::try_from(message.send_time)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
 
-    // Insert the message into the DB
-    let insert_result = db::with_conn(&MESSAGES_DB, |conn| {
-        conn.execute(
-            r#"
-            INSERT INTO messages (
-                storage_owner,
-                external_user,
-                message_time,
-                content,
-                sent_by_self,
-                message_state,
-                height
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
-            "#,
-            params![
-                storage_owner,
-                external_user,
-                message_time,
-                message,
-                if storage_owner_is_sender {
-                    1_i64
-                } else {
-                    0_i64
-                },
-                MessageState::Sending.as_str(),
-                height,
-            ],
-        )?;
-        Ok(())
-    });
+    let (content, encrypted) = message_crypto::seal(keys, &message.content);
+    let search_tokens = message_crypto::search_tokens(keys, &message.content);
 
-    if let Err(e) = insert_result {
-        log!("Failed to insert message into sqlite: {}", e);
-        return;
+    let tx = conn.unchecked_transaction()?;
+    let inserted = tx.execute(
+        r#"
+        INSERT INTO messages (
+            storage_owner,
+            external_user,
+            message_time,
+            content,
+            sent_by_self,
+            message_state,
+            height,
+            message_id,
+            encrypted,
+            search_tokens
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        ON CONFLICT (storage_owner, external_user, message_id) DO NOTHING
+        "#,
+        params![
+            message.storage_owner,
+            message.external_user,
+            message_time,
+            content,
+            if message.sent_by_self { 1_i64 } else { 0_i64 },
+            MessageState::Sending.as_str(),
+            message.height,
+            message.message_id as i64,
+            encrypted,
+            search_tokens,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(true);
     }
 
     // Update contacts table to reflect that this conversation exists and has a recent message.
-    // Use the Contact helper to set last_message_at to the message timestamp.
-    let mut contact = crate::users::contact::Contact::new(external_user);
+    let mut contact = Contact::new(message.external_user);
     contact.set_last_message_at(message_time);
-    // This will insert or update the contact for the storage owner.
-    crate::util::chats_util::mod_user(storage_owner, &contact);
+    chats_util::mod_user(&tx, message.storage_owner, &contact)?;
+    if !message.sent_by_self {
+        chats_util::refresh_unread(&tx, message.storage_owner, message.external_user)?;
+    }
+    tx.commit()?;
+    Ok(false)
+}
+
+/// State of the copy `storage_owner` keeps of message `message_id` from or
+/// to `external_user`.
+pub fn get_message_state(
+    conn: &Connection,
+    storage_owner: i64,
+    external_user: i64,
+    message_id: u32,
+) -> rusqlite::Result<Option<MessageState>> {
+    let state = conn
+        .query_row(
+            r#"
+            SELECT message_state FROM messages
+            WHERE storage_owner = ?1 AND external_user = ?2 AND message_id = ?3
+            "#,
+            params![storage_owner, external_user, message_id as i64],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    Ok(state.map(|state| MessageState::from_str(&state)))
 }
 
+/// Updates the state of one stored copy, never downgrading it. The copy is
+/// found by its wire `message_id`; rows stored before ids were recorded are
+/// matched by partner and `timestamp` instead.
 pub fn change_message_state(
+    conn: &Connection,
+    message_id: u32,
     timestamp: i64,
     storage_owner: i64,
     external_user: i64,
     new_state: MessageState,
-) -> io::Result<()> {
-    // Run the SELECT and UPDATE inside with_conn to centralize connection access.
-    let res: Result<(), String> = db::with_conn(&MESSAGES_DB, |conn| {
-        let current: Option<String> = match conn.query_row(
+) -> rusqlite::Result<()> {
+    let current: Option<(i64, String, bool)> = conn
+        .query_row(
             r#"
-            SELECT message_state
+            SELECT id, message_state, sent_by_self
             FROM messages
             WHERE storage_owner = ?1
-              AND external_user = ?2
-              AND message_time = ?3
-            ORDER BY id DESC
+              AND external_user = ?3
+              AND (message_id = ?2 OR (message_id IS NULL AND message_time = ?4))
+            ORDER BY message_id IS NULL, id DESC
             LIMIT 1
             "#,
-            params![storage_owner, external_user, timestamp],
-            |row| row.get(0),
-        ) {
-            Ok(state) => Some(state),
-            Err(rusqlite::Error::QueryReturnedNoRows) => None,
-            Err(e) => return Err(e),
-        };
+            params![storage_owner, message_id as i64, external_user, timestamp],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get::<_, i64>(2)? != 0)),
+        )
+        .optional()?;
 
-        let Some(current_state_raw) = current else {
-            return Ok(());
-        };
+    let Some((id, current_state_raw, sent_by_self)) = current else {
+        return Ok(());
+    };
 
-        let upgraded = MessageState::from_str(&current_state_raw)
-            .upgrade(new_state)
-            .as_str()
-            .to_string();
+    let upgraded = MessageState::from_str(&current_state_raw).upgrade(new_state);
 
-        conn.execute(
-            r#"
-            UPDATE messages
-            SET message_state = ?1
-            WHERE id = (
-                SELECT id
-                FROM messages
-                WHERE storage_owner = ?2
-                  AND external_user = ?3
-                  AND message_time = ?4
-                ORDER BY id DESC
-                LIMIT 1
-            )
-            "#,
-            params![upgraded, storage_owner, external_user, timestamp],
-        )?;
-        Ok(())
-    });
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE messages SET message_state = ?1 WHERE id = ?2",
+        params![upgraded.as_str(), id],
+    )?;
+    // Reading a received message also reads everything before it.
+    if upgraded == MessageState::Read && !sent_by_self {
+        chats_util::mark_read(&tx, storage_owner, external_user, id)?;
+    }
+    tx.commit()
+}
+
+/// One side's stored copy of a message, as needed to edit or delete it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredCopy {
+    pub row_id: i64,
+    pub message_id: Option<u32>,
+    pub external_user: i64,
+    pub message_time: i64,
+    pub sent_by_self: bool,
+    pub deleted: bool,
+}
+
+const COPY_COLUMNS: &str =
+    "id, message_id, external_user, message_time, sent_by_self, deleted_at IS NOT NULL";
 
-    match res {
-        Ok(_) => Ok(()),
-        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+fn read_copy(row: &rusqlite::Row) -> rusqlite::Result<StoredCopy> {
+    Ok(StoredCopy {
+        row_id: row.get(0)?,
+        message_id: row.get::<_, Option<i64>>(1)?.map(|id| id as u32),
+        external_user: row.get(2)?,
+        message_time: row.get(3)?,
+        sent_by_self: row.get::<_, i64>(4)? != 0,
+        deleted: row.get(5)?,
+    })
+}
+
+/// The copy `storage_owner` keeps under the stable `row_id` from `get_messages`.
+pub fn get_copy(
+    conn: &Connection,
+    storage_owner: i64,
+    row_id: i64,
+) -> rusqlite::Result<Option<StoredCopy>> {
+    conn.query_row(
+        &format!("SELECT {COPY_COLUMNS} FROM messages WHERE storage_owner = ?1 AND id = ?2"),
+        params![storage_owner, row_id],
+        read_copy,
+    )
+    .optional()
+}
+
+/// The counterpart of a copy in another owner's storage: matched by the
+/// shared wire id, or by partner and time for rows stored without one.
+pub fn find_copy(
+    conn: &Connection,
+    storage_owner: i64,
+    external_user: i64,
+    message_id: Option<u32>,
+    message_time: i64,
+) -> rusqlite::Result<Option<StoredCopy>> {
+    conn.query_row(
+        &format!(
+            "SELECT {COPY_COLUMNS} FROM messages
+             WHERE storage_owner = ?1
+               AND external_user = ?2
+               AND (message_id = ?3 OR (?3 IS NULL AND message_id IS NULL AND message_time = ?4))
+             ORDER BY id DESC
+             LIMIT 1"
+        ),
+        params![
+            storage_owner,
+            external_user,
+            message_id.map(|id| id as i64),
+            message_time
+        ],
+        read_copy,
+    )
+    .optional()
+}
+
+/// The storage owner of row `row_id`, whose keys seal its content.
+pub fn copy_owner(conn: &Connection, row_id: i64) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT storage_owner FROM messages WHERE id = ?1",
+        params![row_id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Replaces the content of one copy, keeping the previous text as history.
+pub fn edit_copy(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    row_id: i64,
+    content: &str,
+    edited_at: i64,
+) -> rusqlite::Result<()> {
+    let (stored, encrypted) = message_crypto::seal(keys, content);
+    let search_tokens = message_crypto::search_tokens(keys, content);
+
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO message_edits (message_row, content, encrypted, replaced_at)
+         SELECT id, content, encrypted, ?2 FROM messages WHERE id = ?1",
+        params![row_id, edited_at],
+    )?;
+    tx.execute(
+        "UPDATE messages SET content = ?2, encrypted = ?3, search_tokens = ?4, edited_at = ?5
+         WHERE id = ?1",
+        params![row_id, stored, encrypted, search_tokens, edited_at],
+    )?;
+    tx.commit()
+}
+
+/// Turns one copy into a tombstone: the row (and its id) stays so clients
+/// can render the gap, while the content and its edit history are dropped.
+pub fn delete_copy(conn: &Connection, row_id: i64, deleted_at: i64) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "DELETE FROM message_edits WHERE message_row = ?1",
+        params![row_id],
+    )?;
+    tx.execute(
+        "UPDATE messages
+         SET content = '', encrypted = 0, search_tokens = '', blob_hash = NULL, deleted_at = ?2
+         WHERE id = ?1",
+        params![row_id, deleted_at],
+    )?;
+    let (storage_owner, external_user): (i64, i64) = tx.query_row(
+        "SELECT storage_owner, external_user FROM messages WHERE id = ?1",
+        params![row_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    chats_util::refresh_unread(&tx, storage_owner, external_user)?;
+    tx.commit()
+}
+
+/// Previous versions of a copy, oldest first, as `(content, replaced_at)`.
+pub fn get_edit_history(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    row_id: i64,
+) -> rusqlite::Result<Vec<(String, i64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT content, encrypted, replaced_at FROM message_edits
+         WHERE message_row = ?1 ORDER BY id",
+    )?;
+    let rows = stmt.query_map(params![row_id], |row| {
+        let content: String = row.get(0)?;
+        Ok((
+            message_crypto::open(keys, &content, row.get(1)?)?,
+            row.get(2)?,
+        ))
+    })?;
+    rows.collect()
+}
+
+/// Where a page of `get_messages` starts. Ids are the stable row ids
+/// returned as `id` with every message; a cursor must name a stored
+/// message of the conversation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageCursor {
+    /// Skip this many of the newest messages (legacy clients).
+    Offset(i64),
+    /// Messages older than the one with this id.
+    Before(i64),
+    /// Messages newer than the one with this id.
+    After(i64),
+}
+
+/// A `Before` or `After` id that names no message of the conversation,
+/// e.g. one that was purged since the client saw it.
+#[derive(Debug)]
+pub struct UnknownCursor(pub i64);
+
+impl std::fmt::Display for UnknownCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "message {} is not part of this conversation", self.0)
+    }
+}
+
+impl std::error::Error for UnknownCursor {}
+
+/// Fails with `UnknownCursor` unless `id` is a message `storage_owner`
+/// exchanged with `external_user`.
+fn check_cursor(
+    conn: &Connection,
+    storage_owner: i64,
+    external_user: i64,
+    id: i64,
+) -> rusqlite::Result<()> {
+    let known = conn.query_row(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM messages WHERE id = ?1 AND storage_owner = ?2 AND external_user = ?3
+        )
+        "#,
+        params![id, storage_owner, external_user],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if known {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::UserFunctionError(Box::new(UnknownCursor(
+            id,
+        ))))
     }
 }
 
+/// A page of messages, newest first, ordered by `message_time` with the id
+/// breaking ties so pages never overlap or skip rows. Fails with
+/// `UnknownCursor` rather than returning an empty page for a cursor that
+/// names no message of the conversation.
 pub fn get_messages(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
     storage_owner: i64,
     external_user: i64,
-    loaded_messages: i64,
+    cursor: MessageCursor,
     amount: i64,
-) -> JsonValue {
+) -> rusqlite::Result<JsonValue> {
     let messages = array![];
 
-    if amount <= 0 || loaded_messages < 0 {
-        return messages;
+    if amount <= 0 {
+        return Ok(messages);
     }
 
-    let res: Result<JsonValue, String> = db::with_conn(&MESSAGES_DB, |conn| {
-        let mut stmt = conn.prepare(
+    if let MessageCursor::Before(id) | MessageCursor::After(id) = cursor {
+        check_cursor(conn, storage_owner, external_user, id)?;
+    }
+    let (filter, order, cursor_value) = match cursor {
+        MessageCursor::Offset(offset) if offset < 0 => return Ok(messages),
+        MessageCursor::Offset(offset) => ("", "DESC", offset),
+        MessageCursor::Before(id) => (
+            "AND (m.message_time, m.id) < (c.message_time, c.id)",
+            "DESC",
+            id,
+        ),
+        MessageCursor::After(id) => (
+            "AND (m.message_time, m.id) > (c.message_time, c.id)",
+            "ASC",
+            id,
+        ),
+    };
+    let sql = if filter.is_empty() {
+        format!(
             r#"
-            SELECT
-                message_time,
-                content,
-                sent_by_self,
-                message_state,
-                height
-            FROM messages
-            WHERE storage_owner = ?1
-              AND external_user = ?2
-            ORDER BY message_time DESC, id DESC
-            LIMIT ?3 OFFSET ?4
-            "#,
-        )?;
-
-        let rows = stmt.query_map(
-            params![storage_owner, external_user, amount, loaded_messages],
-            |row| {
-                let message_time: i64 = row.get(0)?;
-                let content: String = row.get(1)?;
-                let sent_by_self: i64 = row.get(2)?;
-                let message_state: String = row.get(3)?;
-                let height: i64 = row.get(4).unwrap_or(0);
-                Ok((message_time, content, sent_by_self, message_state, height))
-            },
-        )?;
-
-        let mut out = array![];
-        for row in rows {
-            match row {
-                Ok((message_time, content, sent_by_self, message_state, height)) => {
-                    let msg = object! {
-                        "message_time" => message_time,
-                        "content" => content,
-                        "sent_by_self" => (sent_by_self != 0),
-                        "message_state" => message_state,
-                        "height" => height
-                    };
-                    if let Err(e) = out.push(msg) {
-                        // out.push returns a JsonError; log it instead of using `?` to avoid
-                        // incompatible error conversions inside the DB closure.
-                        log!("Failed to append message to output array: {:?}", e);
-                    }
-                }
-                Err(e) => {
-                    log!("Failed to read row from sqlite: {}", e);
-                }
-            }
-        }
-        Ok(out)
-    });
-
-    match res {
-        Ok(v) => v,
-        Err(e) => {
-            log!("Failed to query messages: {}", e);
-            messages
-        }
+            SELECT m.id, m.message_time, m.content, m.sent_by_self, m.message_state, m.height,
+                   m.edited_at, m.deleted_at, m.blob_hash, m.expires_at, m.encrypted
+            FROM messages m
+            WHERE m.storage_owner = ?1
+              AND m.external_user = ?2
+            ORDER BY m.message_time {order}, m.id {order}
+            LIMIT ?4 OFFSET ?3
+            "#
+        )
+    } else {
+        format!(
+            r#"
+            SELECT m.id, m.message_time, m.content, m.sent_by_self, m.message_state, m.height,
+                   m.edited_at, m.deleted_at, m.blob_hash, m.expires_at, m.encrypted
+            FROM messages m
+            JOIN messages c ON c.id = ?3 AND c.storage_owner = ?1 AND c.external_user = ?2
+            WHERE m.storage_owner = ?1
+              AND m.external_user = ?2
+              {filter}
+            ORDER BY m.message_time {order}, m.id {order}
+            LIMIT ?4
+            "#
+        )
+    };
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(
+        params![storage_owner, external_user, cursor_value, amount],
+        |row| {
+            let id: i64 = row.get(0)?;
+            let message_time: i64 = row.get(1)?;
+            let content: String = row.get(2)?;
+            let sent_by_self: i64 = row.get(3)?;
+            let message_state: String = row.get(4)?;
+            let height: i64 = row.get(5).unwrap_or(0);
+            let edited_at: Option<i64> = row.get(6)?;
+            let deleted_at: Option<i64> = row.get(7)?;
+            let blob_hash: Option<String> = row.get(8)?;
+            let expires_at: Option<i64> = row.get(9)?;
+            let encrypted: bool = row.get(10)?;
+            let content = message_crypto::open(keys, &content, encrypted)?;
+            Ok(object! {
+                "id" => id,
+                "message_time" => message_time,
+                "content" => content,
+                "sent_by_self" => (sent_by_self != 0),
+                "message_state" => message_state,
+                "height" => height,
+                "edited_at" => edited_at,
+                "deleted_at" => deleted_at,
+                "blob_hash" => blob_hash,
+                "expires_at" => expires_at
+            })
+        },
+    )?;
+
+    // A row that cannot be read or opened fails the page instead of
+    // silently leaving a gap in it.
+    let mut page = rows.collect::<Result<Vec<_>, _>>()?;
+    // `After` pages are read oldest first to stay next to the cursor.
+    if let MessageCursor::After(_) = cursor {
+        page.reverse();
+    }
+    Ok(JsonValue::Array(page))
+}
+
+/// Filters for `search_messages`; `None` leaves that dimension open.
+#[derive(Clone, Debug, Default)]
+pub struct SearchFilter {
+    pub external_user: Option<i64>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+/// Full-text search over the conversations of `storage_owner`, newest first.
+/// Each hit carries its stable `id`, partner and a `snippet` with the
+/// matched words in `[brackets]`. The index holds keyed word tokens, so the
+/// snippet is cut from the decrypted content of each hit.
+pub fn search_messages(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    storage_owner: i64,
+    query: &str,
+    filter: &SearchFilter,
+    offset: i64,
+    amount: i64,
+) -> rusqlite::Result<JsonValue> {
+    let words = message_crypto::words(query);
+    let query = message_crypto::search_query(keys, query);
+    if query.is_empty() || amount <= 0 || offset < 0 {
+        return Ok(array![]);
     }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT
+            m.id,
+            m.external_user,
+            m.message_time,
+            m.sent_by_self,
+            m.content,
+            m.encrypted
+        FROM messages_fts
+        JOIN messages m ON m.id = messages_fts.rowid
+        WHERE messages_fts MATCH ?1
+          AND m.storage_owner = ?2
+          AND m.deleted_at IS NULL
+          AND (?3 IS NULL OR m.external_user = ?3)
+          AND (?4 IS NULL OR m.message_time >= ?4)
+          AND (?5 IS NULL OR m.message_time <= ?5)
+        ORDER BY m.message_time DESC, m.id DESC
+        LIMIT ?6 OFFSET ?7
+        "#,
+    )?;
+    let rows = stmt.query_map(
+        params![
+            query,
+            storage_owner,
+            filter.external_user,
+            filter.since,
+            filter.until,
+            amount,
+            offset
+        ],
+        |row| {
+            let content = message_crypto::open(keys, &row.get::<_, String>(4)?, row.get(5)?)?;
+            Ok(object! {
+                "id" => row.get::<_, i64>(0)?,
+                "external_user" => row.get::<_, i64>(1)?,
+                "message_time" => row.get::<_, i64>(2)?,
+                "sent_by_self" => (row.get::<_, i64>(3)? != 0),
+                "snippet" => message_crypto::snippet(&content, &words),
+            })
+        },
+    )?;
+    Ok(JsonValue::Array(rows.collect::<Result<Vec<_>, _>>()?))
 }
 
 #[cfg(test)]
 mod tests {
-    use super::MessageState;
+    use super::{MessageCursor, MessageState, NewMessage, SearchFilter, add_message as store};
+    use crate::util::db::{self, Database};
+    use crate::util::repository::{ContactStore, MessageStore, SqliteStore};
+    use ttp_core::rand_u32;
+
+    async fn add_message(
+        store: &SqliteStore,
+        message_id: u32,
+        send_time: u128,
+        sent_by_self: bool,
+        storage_owner: i64,
+        external_user: i64,
+        content: &str,
+    ) -> bool {
+        store
+            .add_message(NewMessage {
+                message_id,
+                send_time,
+                sent_by_self,
+                storage_owner,
+                external_user,
+                content: content.to_string(),
+                height: 0,
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn cursors_page_through_messages_sharing_a_timestamp() {
+        let store = SqliteStore::in_memory();
+        let (owner, partner) = (1, 2);
+        for (n, content) in ["a", "b", "c"].iter().enumerate() {
+            add_message(&store, n as u32, 1_000, false, owner, partner, content).await;
+        }
+
+        let newest = store
+            .get_messages(owner, partner, MessageCursor::Offset(0), 1)
+            .await
+            .unwrap();
+        assert_eq!(newest[0]["content"].as_str(), Some("c"));
+        let id_c = newest[0]["id"].as_i64().unwrap();
+
+        let older = store
+            .get_messages(owner, partner, MessageCursor::Before(id_c), 10)
+            .await
+            .unwrap();
+        assert_eq!(older.len(), 2);
+        assert_eq!(older[0]["content"].as_str(), Some("b"));
+        assert_eq!(older[1]["content"].as_str(), Some("a"));
+
+        let id_a = older[1]["id"].as_i64().unwrap();
+        let newer = store
+            .get_messages(owner, partner, MessageCursor::After(id_a), 1)
+            .await
+            .unwrap();
+        assert_eq!(newer.len(), 1);
+        assert_eq!(newer[0]["content"].as_str(), Some("b"));
+
+        add_message(&store, 9, 1_000, false, owner, partner + 1, "x").await;
+        let elsewhere = store
+            .get_messages(owner, partner + 1, MessageCursor::Offset(0), 1)
+            .await
+            .unwrap();
+        let id_x = elsewhere[0]["id"].as_i64().unwrap();
+        for cursor in [MessageCursor::Before(id_x), MessageCursor::After(id_x + 1)] {
+            assert!(
+                store
+                    .get_messages(owner, partner, cursor, 10)
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn message_ids_are_unique_per_conversation() {
+        let db = Database::open_in_memory(db::MESSAGES_MIGRATIONS).unwrap();
+        let message = |external_user: i64, send_time: u128| NewMessage {
+            message_id: 7,
+            send_time,
+            sent_by_self: false,
+            storage_owner: 1,
+            external_user,
+            content: "hi".to_string(),
+            height: 0,
+        };
+        let add = |m: NewMessage| db.write_blocking(move |conn| store(conn, None, &m));
+
+        assert_eq!(add(message(2, 1_000)), Ok(false));
+        assert_eq!(add(message(3, 1_000)), Ok(false));
+        assert_eq!(add(message(2, 1_000)), Ok(true));
+        assert!(add(message(4, u128::MAX)).is_err());
+    }
 
     #[test]
     fn upgrade_prefers_highest_state() {
@@ -264,6 +680,14 @@ mod tests {
             MessageState::Received.upgrade(MessageState::Read),
             MessageState::Read
         );
+        assert_eq!(
+            MessageState::Sent.upgrade(MessageState::Blocked),
+            MessageState::Blocked
+        );
+        assert_eq!(
+            MessageState::Blocked.upgrade(MessageState::Sending),
+            MessageState::Blocked
+        );
     }
 
     #[test]
@@ -271,6 +695,145 @@ mod tests {
         assert_eq!(MessageState::from_str("READ"), MessageState::Read);
         assert_eq!(MessageState::from_str("received"), MessageState::Received);
         assert_eq!(MessageState::from_str("Sent"), MessageState::Sent);
+        assert_eq!(MessageState::from_str("blocked"), MessageState::Blocked);
         assert_eq!(MessageState::from_str("unknown"), MessageState::Sending);
     }
+
+    #[tokio::test]
+    async fn edits_keep_history_and_deletes_leave_a_tombstone() {
+        let store = SqliteStore::in_memory();
+        let (author, partner) = (1, 2);
+        let message_id = rand_u32();
+        add_message(&store, message_id, 5_000, true, author, partner, "helo").await;
+        add_message(&store, message_id, 5_000, false, partner, author, "helo").await;
+
+        let own = store
+            .get_messages(author, partner, MessageCursor::Offset(0), 1)
+            .await
+            .unwrap();
+        let own = store
+            .get_copy(author, own[0]["id"].as_i64().unwrap())
+            .await
+            .unwrap();
+        assert!(own.sent_by_self);
+        let theirs = store
+            .find_copy(partner, author, own.message_id, own.message_time)
+            .await
+            .unwrap();
+        assert!(!theirs.sent_by_self);
+
+        store.edit_copy(own.row_id, "hello", 6_000).await.unwrap();
+        assert_eq!(
+            store.get_edit_history(own.row_id).await,
+            vec![("helo".to_string(), 6_000)]
+        );
+        let page = store
+            .get_messages(author, partner, MessageCursor::Offset(0), 1)
+            .await
+            .unwrap();
+        assert_eq!(page[0]["content"].as_str(), Some("hello"));
+        assert_eq!(page[0]["edited_at"].as_i64(), Some(6_000));
+
+        store.delete_copy(theirs.row_id, 7_000).await.unwrap();
+        assert!(
+            store
+                .get_copy(partner, theirs.row_id)
+                .await
+                .unwrap()
+                .deleted
+        );
+        let page = store
+            .get_messages(partner, author, MessageCursor::Offset(0), 1)
+            .await
+            .unwrap();
+        assert_eq!(page[0]["content"].as_str(), Some(""));
+        assert_eq!(page[0]["deleted_at"].as_i64(), Some(7_000));
+    }
+
+    #[tokio::test]
+    async fn search_is_scoped_to_the_owner_and_filters() {
+        let store = SqliteStore::in_memory();
+        let (owner, partner, other) = (1, 2, 3);
+        add_message(&store, 1, 1_000, false, owner, partner, "lunch at noon?").await;
+        add_message(&store, 2, 2_000, true, owner, other, "no lunch today").await;
+        add_message(
+            &store,
+            3,
+            3_000,
+            false,
+            partner,
+            owner,
+            "lunch for someone else",
+        )
+        .await;
+
+        let search = |filter: SearchFilter, offset| {
+            let store = &store;
+            async move {
+                store
+                    .search_messages(owner, "lunch", &filter, offset, 10)
+                    .await
+            }
+        };
+        let all = search(SearchFilter::default(), 0).await;
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0]["external_user"].as_i64(), Some(other));
+        assert!(all[0]["snippet"].as_str().unwrap().contains("[lunch]"));
+
+        let filter = SearchFilter {
+            external_user: Some(partner),
+            ..Default::default()
+        };
+        assert_eq!(search(filter, 0).await.len(), 1);
+
+        let filter = SearchFilter {
+            since: Some(1_500),
+            ..Default::default()
+        };
+        assert_eq!(search(filter, 0).await.len(), 1);
+        assert_eq!(search(SearchFilter::default(), 1).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unread_count_follows_incoming_messages_and_reads() {
+        let store = SqliteStore::in_memory();
+        let (owner, partner) = (1, 2);
+        let unread = || async {
+            let contact = store.get_user(owner, partner).await.unwrap();
+            (contact.unread_count, contact.last_read_id)
+        };
+
+        add_message(&store, 1, 1_000, false, owner, partner, "one").await;
+        add_message(&store, 2, 2_000, false, owner, partner, "two").await;
+        add_message(&store, 3, 3_000, true, owner, partner, "mine").await;
+        assert_eq!(unread().await, (2, None));
+
+        store
+            .change_message_state(2, 2_000, owner, partner, MessageState::Read)
+            .await
+            .unwrap();
+        let marker = store
+            .find_copy(owner, partner, Some(2), 2_000)
+            .await
+            .unwrap()
+            .row_id;
+        assert_eq!(unread().await, (0, Some(marker)));
+
+        // An older receipt arriving late does not move the marker back.
+        store
+            .change_message_state(1, 1_000, owner, partner, MessageState::Read)
+            .await
+            .unwrap();
+        assert_eq!(unread().await, (0, Some(marker)));
+
+        add_message(&store, 4, 4_000, false, owner, partner, "three").await;
+        assert_eq!(unread().await.0, 1);
+        let row_id = store
+            .find_copy(owner, partner, Some(4), 4_000)
+            .await
+            .unwrap()
+            .row_id;
+        store.delete_copy(row_id, 5_000).await.unwrap();
+        assert_eq!(unread().await.0, 0);
+    }
 }
@@ -1,121 +1,197 @@
-use crate::users::contact::Contact;
-use crate::util::db;
-use rusqlite::params;
-use std::sync::{Arc, LazyLock, Mutex};
+//! Contact rows of the messages DB, on a connection from `db::Database`.
+//! `repository::SqliteStore` exposes them as a `ContactStore`.
 
-/// Shared DB connection for contacts/messages (created by db helper).
-static MESSAGES_DB: LazyLock<Arc<Mutex<rusqlite::Connection>>> = LazyLock::new(|| {
-    db::create_general_messages_db().expect("Failed to create or initialize general messages DB")
-});
+use crate::users::contact::Contact;
+use rusqlite::{Connection, OptionalExtension, params};
 
 /// Insert or update a contact for the given storage owner.
-pub fn mod_user(storage_owner: i64, contact: &Contact) {
-    if let Err(e) = db::with_conn(&MESSAGES_DB, |conn| {
-        conn.execute(
-            r#"
-            INSERT INTO contacts (
-                storage_owner,
-                user_id,
-                user_name,
-                last_message_at
-            ) VALUES (?1, ?2, ?3, ?4)
-            ON CONFLICT(storage_owner, user_id) DO UPDATE SET
-                user_name = excluded.user_name,
-                last_message_at = excluded.last_message_at
-            "#,
-            params![
-                storage_owner,
-                contact.user_id,
-                contact.user_name.clone(),
-                contact.last_message_at
-            ],
-        )?;
-        Ok(())
-    }) {
-        eprintln!("Failed to mod_user: {}", e);
-    }
+pub fn mod_user(conn: &Connection, storage_owner: i64, contact: &Contact) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO contacts (
+            storage_owner,
+            user_id,
+            user_name,
+            last_message_at
+        ) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT(storage_owner, user_id) DO UPDATE SET
+            user_name = excluded.user_name,
+            last_message_at = excluded.last_message_at
+        "#,
+        params![
+            storage_owner,
+            contact.user_id,
+            contact.user_name.clone(),
+            contact.last_message_at
+        ],
+    )?;
+    Ok(())
 }
 
-/// Retrieve a single contact for storage_owner/user_id.
-pub fn get_user(storage_owner: i64, user_id: i64) -> Option<Contact> {
-    let res: Result<Option<Contact>, String> = db::with_conn(&MESSAGES_DB, |conn| {
-        match conn.query_row(
-            r#"
-            SELECT user_id, user_name, last_message_at
-            FROM contacts
-            WHERE storage_owner = ?1 AND user_id = ?2
-            LIMIT 1
-            "#,
-            params![storage_owner, user_id],
-            |r| {
-                let user_id: i64 = r.get(0)?;
-                let user_name: Option<String> = r.get(1)?;
-                let last_message_at: Option<i64> = r.get(2)?;
-                Ok(Contact {
-                    user_id,
-                    user_name,
-                    last_message_at,
-                })
-            },
-        ) {
-            Ok(c) => Ok(Some(c)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e),
-        }
-    });
+/// Recounts the messages from `user_id` that `storage_owner` has received
+/// after their last-read marker. Takes the connection so callers can keep it
+/// in the same transaction as the change that moved the count.
+pub fn refresh_unread(conn: &Connection, storage_owner: i64, user_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        UPDATE contacts SET unread_count = (
+            SELECT COUNT(*)
+            FROM messages m
+            LEFT JOIN messages r ON r.id = contacts.last_read_id
+            WHERE m.storage_owner = contacts.storage_owner
+              AND m.external_user = contacts.user_id
+              AND m.sent_by_self = 0
+              AND m.deleted_at IS NULL
+              AND (r.id IS NULL OR (m.message_time, m.id) > (r.message_time, r.id))
+        )
+        WHERE storage_owner = ?1 AND user_id = ?2
+        "#,
+        params![storage_owner, user_id],
+    )?;
+    Ok(())
+}
 
-    match res {
-        Ok(opt) => opt,
-        Err(e) => {
-            eprintln!("Error querying user in get_user: {}", e);
-            None
-        }
-    }
+/// Moves the last-read marker of `storage_owner` in the conversation with
+/// `user_id` up to message row `row_id`. Markers never move backwards.
+pub fn mark_read(
+    conn: &Connection,
+    storage_owner: i64,
+    user_id: i64,
+    row_id: i64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        UPDATE contacts SET last_read_id = ?3
+        WHERE storage_owner = ?1
+          AND user_id = ?2
+          AND (
+            last_read_id IS NULL
+            OR (SELECT (n.message_time, n.id) > (o.message_time, o.id)
+                FROM messages n, messages o
+                WHERE n.id = ?3 AND o.id = contacts.last_read_id)
+            OR NOT EXISTS (SELECT 1 FROM messages o WHERE o.id = contacts.last_read_id)
+          )
+        "#,
+        params![storage_owner, user_id, row_id],
+    )?;
+    refresh_unread(conn, storage_owner, user_id)
+}
+
+/// A change to the flags of one contact; `None` leaves a flag as it is.
+#[derive(Clone, Debug, Default)]
+pub struct ContactFlags {
+    pub blocked: Option<bool>,
+    pub muted: Option<bool>,
+    pub archived: Option<bool>,
+    pub pinned: Option<bool>,
 }
 
-/// Retrieve all contacts for a storage owner, ordered by last_message_at desc / user_id asc.
-pub fn get_users(storage_owner: i64) -> Vec<Contact> {
-    let contacts_out = Vec::new();
+/// Applies `flags` to the contact `user_id` of `storage_owner`, creating the
+/// contact if needed so a user can be blocked before they ever wrote.
+pub fn set_flags(
+    conn: &Connection,
+    storage_owner: i64,
+    user_id: i64,
+    flags: &ContactFlags,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO contacts (storage_owner, user_id, blocked, muted, archived, pinned)
+        VALUES (
+            ?1, ?2,
+            coalesce(?3, 0), coalesce(?4, 0), coalesce(?5, 0), coalesce(?6, 0)
+        )
+        ON CONFLICT(storage_owner, user_id) DO UPDATE SET
+            blocked = coalesce(?3, blocked),
+            muted = coalesce(?4, muted),
+            archived = coalesce(?5, archived),
+            pinned = coalesce(?6, pinned)
+        "#,
+        params![
+            storage_owner,
+            user_id,
+            flags.blocked,
+            flags.muted,
+            flags.archived,
+            flags.pinned
+        ],
+    )?;
+    Ok(())
+}
 
-    let res: Result<Vec<Contact>, String> = db::with_conn(&MESSAGES_DB, |conn| {
-        let mut stmt = conn.prepare(
-            r#"
-            SELECT user_id, user_name, last_message_at
-            FROM contacts
-            WHERE storage_owner = ?1
-            ORDER BY
-                CASE WHEN last_message_at IS NULL THEN 1 ELSE 0 END,
-                last_message_at DESC,
-                user_id ASC
-            "#,
-        )?;
+/// Whether `storage_owner` has blocked `user_id`.
+pub fn is_blocked(conn: &Connection, storage_owner: i64, user_id: i64) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT blocked FROM contacts WHERE storage_owner = ?1 AND user_id = ?2",
+        params![storage_owner, user_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map(|blocked| blocked.unwrap_or(false))
+}
 
-        let rows = stmt.query_map(params![storage_owner], |r| {
-            let user_id: i64 = r.get(0)?;
-            let user_name: Option<String> = r.get(1)?;
-            let last_message_at: Option<i64> = r.get(2)?;
-            Ok(Contact {
-                user_id,
-                user_name,
-                last_message_at,
-            })
-        })?;
+fn read_contact(r: &rusqlite::Row) -> rusqlite::Result<Contact> {
+    let user_id: i64 = r.get(0)?;
+    let user_name: Option<String> = r.get(1)?;
+    let last_message_at: Option<i64> = r.get(2)?;
+    Ok(Contact {
+        user_id,
+        user_name,
+        last_message_at,
+        last_read_id: r.get(3)?,
+        unread_count: r.get(4)?,
+        blocked: r.get(5)?,
+        muted: r.get(6)?,
+        archived: r.get(7)?,
+        pinned: r.get(8)?,
+    })
+}
 
-        let mut out = Vec::new();
-        for row in rows {
-            match row {
-                Ok(contact) => out.push(contact),
-                Err(e) => eprintln!("Failed to read contact row: {}", e),
-            }
-        }
-        Ok(out)
-    });
+/// Retrieve a single contact for storage_owner/user_id.
+pub fn get_user(
+    conn: &Connection,
+    storage_owner: i64,
+    user_id: i64,
+) -> rusqlite::Result<Option<Contact>> {
+    conn.query_row(
+        r#"
+        SELECT user_id, user_name, last_message_at, last_read_id, unread_count,
+               blocked, muted, archived, pinned
+        FROM contacts
+        WHERE storage_owner = ?1 AND user_id = ?2
+        LIMIT 1
+        "#,
+        params![storage_owner, user_id],
+        read_contact,
+    )
+    .optional()
+}
+
+/// Retrieve all contacts for a storage owner, pinned first, then ordered by
+/// last_message_at desc / user_id asc.
+pub fn get_users(conn: &Connection, storage_owner: i64) -> rusqlite::Result<Vec<Contact>> {
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT user_id, user_name, last_message_at, last_read_id, unread_count,
+               blocked, muted, archived, pinned
+        FROM contacts
+        WHERE storage_owner = ?1
+        ORDER BY
+            pinned DESC,
+            CASE WHEN last_message_at IS NULL THEN 1 ELSE 0 END,
+            last_message_at DESC,
+            user_id ASC
+        "#,
+    )?;
+
+    let rows = stmt.query_map(params![storage_owner], read_contact)?;
 
-    match res {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("Failed to query contacts in get_users: {}", e);
-            contacts_out
+    let mut out = Vec::new();
+    for row in rows {
+        match row {
+            Ok(contact) => out.push(contact),
+            Err(e) => eprintln!("Failed to read contact row: {}", e),
         }
     }
+    Ok(out)
 }
@@ -35,8 +35,74 @@ impl ConfigUtil {
         self.config["port"].as_u16().unwrap_or(1984)
     }
 
-    pub fn get_public_key(&self) -> Option<String> {
-        self.config["public_key"].as_str().map(String::from)
+    pub fn get_omikron_host(&self) -> Option<String> {
+        self.config["omikron_host"].as_str().map(String::from)
+    }
+
+    pub fn get_omikron_port(&self) -> Option<u16> {
+        self.config["omikron_port"].as_u16()
+    }
+
+    /// Ordered fallback Omikrons as `host` or `host:port` entries.
+    pub fn get_omikron_fallbacks(&self) -> Vec<String> {
+        self.config["omikron_fallbacks"]
+            .members()
+            .filter_map(|v| v.as_str().map(String::from))
+            .collect()
+    }
+
+    /// Seconds a queued outbound message of `communication_type` stays valid.
+    /// Looked up in `outbox_expiry`, then `outbox_expiry.default`; `0` disables queueing.
+    /// `None` if neither is configured.
+    pub fn get_outbox_expiry(&self, communication_type: &str) -> Option<u64> {
+        let expiry = &self.config["outbox_expiry"];
+        expiry[communication_type]
+            .as_u64()
+            .or(expiry["default"].as_u64())
+    }
+
+    /// Token bucket for client requests of `communication_type` as `(burst, per_second)`.
+    /// Looked up in `rate_limits`, then `rate_limits.default`; `per_second` `0` disables limiting.
+    pub fn get_rate_limit(&self, communication_type: &str) -> (u32, f64) {
+        let limits = &self.config["rate_limits"];
+        let limit = if limits[communication_type].is_object() {
+            &limits[communication_type]
+        } else {
+            &limits["default"]
+        };
+        (
+            limit["burst"].as_u32().unwrap_or(20),
+            limit["per_second"].as_f64().unwrap_or(5.0),
+        )
+    }
+
+    /// Bytes a user may occupy: their user directory plus the attachments they uploaded.
+    pub fn get_blob_quota(&self) -> u64 {
+        self.config["blob_quota"]
+            .as_u64()
+            .unwrap_or(1024 * 1024 * 1024)
+    }
+
+    /// Iota-wide retention as `(max_age_ms, max_count)`, from `retention.max_age_days`
+    /// and `retention.max_count`. `None` keeps messages regardless of age or count;
+    /// so does an age too long to count in milliseconds.
+    pub fn get_retention(&self) -> (Option<i64>, Option<i64>) {
+        let retention = &self.config["retention"];
+        (
+            retention["max_age_days"]
+                .as_i64()
+                .filter(|days| *days > 0)
+                .and_then(|days| days.checked_mul(24 * 60 * 60 * 1000)),
+            retention["max_count"].as_i64().filter(|count| *count > 0),
+        )
+    }
+
+    /// Seconds between two retention sweeps.
+    pub fn get_retention_interval(&self) -> u64 {
+        self.config["retention"]["interval_secs"]
+            .as_u64()
+            .unwrap_or(60)
+            .max(1)
     }
 
     pub fn get_private_key(&self) -> Option<String> {
@@ -52,6 +118,13 @@ impl ConfigUtil {
         self.unique = true;
     }
 
+    pub fn remove(&mut self, key: &str) {
+        if self.config.has_key(key) {
+            self.config.remove(key);
+            self.unique = true;
+        }
+    }
+
     pub fn update(&mut self) {
         if self.unique {
             save_file("", "config.json", &self.config.to_string());
@@ -47,16 +47,15 @@ pub fn secret_key_to_base64(secret: &Secret) -> String {
 }
 
 pub fn load_public_key(base64_pub: &str) -> Option<PublicKey> {
-    let bytes = STANDARD.decode(base64_pub).unwrap();
+    let bytes = STANDARD.decode(base64_pub).ok()?;
     PublicKey::from_bytes(&bytes)
 }
 
 pub fn load_secret_key(base64_secret: &str) -> Option<Secret> {
-    let bytes = STANDARD.decode(base64_secret).unwrap();
+    let bytes = STANDARD.decode(base64_secret).ok()?;
     Secret::from_bytes(&bytes)
 }
 
-#[allow(dead_code)]
 fn derive_aes_key(shared: &SharedSecret) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(shared.as_bytes());
@@ -66,19 +65,33 @@ fn derive_aes_key(shared: &SharedSecret) -> [u8; 32] {
     key
 }
 
-#[allow(dead_code)]
+/// The AES key `encrypt`/`decrypt` use between `base64_secret` and `base64_peer_pub`.
+pub fn shared_key(base64_secret: &str, base64_peer_pub: &str) -> Result<[u8; 32], CryptoError> {
+    let secret = load_secret_key(base64_secret).ok_or(CryptoError::InvalidKey)?;
+    let peer_pub = load_public_key(base64_peer_pub).ok_or(CryptoError::InvalidKey)?;
+    let shared = secret
+        .to_diffie_hellman(&peer_pub)
+        .ok_or(CryptoError::AgreementError)?;
+    Ok(derive_aes_key(&shared))
+}
+
 pub fn encrypt(
     base64_secret: &str,
     base64_peer_pub: &str,
     plaintext: &str,
 ) -> Result<String, CryptoError> {
-    let secret = load_secret_key(base64_secret).unwrap();
-    let peer_pub = load_public_key(base64_peer_pub).unwrap();
+    let secret = load_secret_key(base64_secret).ok_or(CryptoError::InvalidKey)?;
+    let peer_pub = load_public_key(base64_peer_pub).ok_or(CryptoError::InvalidKey)?;
     let shared = secret
         .to_diffie_hellman(&peer_pub)
         .ok_or(CryptoError::AgreementError)?;
-    let key_bytes = derive_aes_key(&shared);
-    let cipher = Aes256Gcm::new_from_slice(&key_bytes).expect("Key length should be correct");
+    encrypt_with_key(&derive_aes_key(&shared), plaintext)
+}
+
+/// `encrypt` with a key from `shared_key`, for callers that seal many
+/// values for the same pair and should not redo the key agreement.
+pub fn encrypt_with_key(key: &[u8; 32], plaintext: &str) -> Result<String, CryptoError> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Key length should be correct");
     let mut nonce_bytes = [0u8; 12];
     OsRng.fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
@@ -92,19 +105,22 @@ pub fn encrypt(
     Ok(STANDARD.encode(&out))
 }
 
-#[allow(dead_code)]
 pub fn decrypt(
     base64_secret: &str,
     base64_peer_pub: &str,
     encrypted_base64: &str,
 ) -> Result<String, CryptoError> {
-    let secret = load_secret_key(base64_secret).unwrap();
-    let peer_pub = load_public_key(base64_peer_pub).unwrap();
+    let secret = load_secret_key(base64_secret).ok_or(CryptoError::InvalidKey)?;
+    let peer_pub = load_public_key(base64_peer_pub).ok_or(CryptoError::InvalidKey)?;
     let shared = secret
         .to_diffie_hellman(&peer_pub)
         .ok_or(CryptoError::AgreementError)?;
-    let key_bytes = derive_aes_key(&shared);
-    let cipher = Aes256Gcm::new_from_slice(&key_bytes).expect("Key length should be correct");
+    decrypt_with_key(&derive_aes_key(&shared), encrypted_base64)
+}
+
+/// `decrypt` with a key from `shared_key`.
+pub fn decrypt_with_key(key: &[u8; 32], encrypted_base64: &str) -> Result<String, CryptoError> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Key length should be correct");
 
     let encrypted = STANDARD.decode(encrypted_base64)?;
     if encrypted.len() < 12 {
@@ -121,13 +137,13 @@ pub fn decrypt(
     Ok(plaintext)
 }
 
-pub fn hash_it(input: &str) -> Vec<u8> {
+pub fn hash_it(input: impl AsRef<[u8]>) -> Vec<u8> {
     let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
+    hasher.update(input.as_ref());
     hasher.finalize().to_vec()
 }
 
-pub fn hex_hash(input: &str) -> String {
+pub fn hex_hash(input: impl AsRef<[u8]>) -> String {
     let digest = hash_it(input);
     digest.iter().map(|b| format!("{:02x}", b)).collect()
 }
@@ -1,15 +1,45 @@
 //! Database helper utilities.
 //!
-//! This module provides small helpers to open/init sqlite databases and to
-//! create a shared (Arc<Mutex<Connection>>) connection wrapper callers can
-//! reuse. The goal is to centralize the "open and initialize" logic and
-//! provide small convenience helpers used by other util modules.
+//! This module opens and migrates the sqlite databases and hands out access
+//! through `Database`: one writer connection whose callers queue up in
+//! arrival order, and a small pool of read-only connections. The messages
+//! DB is shared process-wide as `MESSAGES_DB`; `repository` builds the typed
+//! stores on top of it.
 
 use crate::util::file_util::get_directory;
-use rusqlite::{Connection, Error as RusqliteError};
+use rusqlite::{Connection, Error as RusqliteError, TransactionBehavior};
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
 use std::time::Duration;
+use tokio::runtime::RuntimeFlavor;
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Read-only connections kept open next to the writer.
+const READERS: usize = 4;
+
+/// The messages DB every store and util module works on. Opened by
+/// `init_messages_db` at startup; only code running without it (tests)
+/// opens it on first use.
+pub static MESSAGES_DB: LazyLock<Arc<Database>> = LazyLock::new(|| {
+    OPENED_MESSAGES_DB.get().cloned().unwrap_or_else(|| {
+        Arc::new(
+            create_general_messages_db()
+                .expect("Failed to create or initialize general messages DB"),
+        )
+    })
+});
+
+static OPENED_MESSAGES_DB: OnceLock<Arc<Database>> = OnceLock::new();
+
+/// Opens and migrates the messages DB and makes it `MESSAGES_DB`, so a
+/// failure stops startup with a message instead of a panic on first use.
+pub fn init_messages_db() -> Result<(), String> {
+    let db = create_general_messages_db()?;
+    let _ = OPENED_MESSAGES_DB.set(Arc::new(db));
+    LazyLock::force(&MESSAGES_DB);
+    Ok(())
+}
 
 /// Returns the file path for a named DB inside the application's data directory.
 ///
@@ -41,145 +71,649 @@ pub fn open_and_init(db_name: &str, init_sql: &str) -> Result<Connection, Rusqli
     Ok(conn)
 }
 
-/// Create a shared, Arc<Mutex<Connection>> initialized with the given SQL.
-///
-/// This is a convenience wrapper that returns an owned Arc<Mutex<Connection>>
-/// so caller modules can store it in a `static` or pass it around.
+type Shared = Arc<AsyncMutex<Connection>>;
+
+/// A migrated database with one writer and a pool of readers.
 ///
-/// Arguments:
-/// - `db_name`: DB name (without extension).
-/// - `init_sql`: init SQL (eg PRAGMA + CREATE TABLE statements).
-pub fn create_shared_connection(
-    db_name: &str,
-    init_sql: &str,
-) -> Result<Arc<Mutex<Connection>>, String> {
-    match open_and_init(db_name, init_sql) {
-        Ok(conn) => {
-            // Configure some sensible defaults for concurrency
-            // Attempt to set a busy timeout to reduce SQLITE_BUSY failures.
-            let _ = conn.busy_timeout(Duration::from_millis(250));
-            Ok(Arc::new(Mutex::new(conn)))
+/// The writer sits behind a fair mutex, so writes run one at a time in the
+/// order they were requested. Async callers run their closure on the
+/// blocking thread pool; the `_blocking` variants are for code that is not
+/// async yet. Without readers (in-memory databases) reads use the writer.
+pub struct Database {
+    writer: Shared,
+    readers: Vec<Shared>,
+    next_reader: AtomicUsize,
+}
+
+impl Database {
+    /// Opens the named DB file, applies `migrations` on the writer and then
+    /// opens `readers` read-only connections.
+    ///
+    /// Arguments:
+    /// - `db_name`: DB name (without extension).
+    /// - `init_sql`: PRAGMAs run on the writer before migrating.
+    /// - `migrations`: the complete, ordered migration list for this DB.
+    /// - `readers`: size of the reader pool.
+    pub fn open(
+        db_name: &str,
+        init_sql: &str,
+        migrations: &[Migration],
+        readers: usize,
+    ) -> Result<Self, String> {
+        let fail = |e: RusqliteError| format!("Failed to open/init DB '{}': {}", db_name, e);
+
+        let mut writer = open_and_init(db_name, init_sql).map_err(fail)?;
+        // Attempt to set a busy timeout to reduce SQLITE_BUSY failures.
+        let _ = writer.busy_timeout(Duration::from_millis(250));
+        migrate(&mut writer, migrations)?;
+
+        let mut pool = Vec::with_capacity(readers);
+        for _ in 0..readers {
+            let reader = open_and_init(db_name, "PRAGMA query_only = ON;").map_err(fail)?;
+            let _ = reader.busy_timeout(Duration::from_millis(250));
+            pool.push(Arc::new(AsyncMutex::new(reader)));
         }
-        Err(e) => Err(format!("Failed to open/init DB '{}': {}", db_name, e)),
+
+        Ok(Database {
+            writer: Arc::new(AsyncMutex::new(writer)),
+            readers: pool,
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    /// A private in-memory database with `migrations` applied, for tests.
+    pub fn open_in_memory(migrations: &[Migration]) -> Result<Self, String> {
+        let mut conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+        migrate(&mut conn, migrations)?;
+        Ok(Database {
+            writer: Arc::new(AsyncMutex::new(conn)),
+            readers: Vec::new(),
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    fn reader(&self) -> &Shared {
+        if self.readers.is_empty() {
+            return &self.writer;
+        }
+        let n = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        &self.readers[n % self.readers.len()]
+    }
+
+    /// Runs `f` on a reader connection.
+    pub async fn read<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, RusqliteError> + Send + 'static,
+    {
+        run(self.reader(), f).await
+    }
+
+    /// Queues `f` for the writer connection.
+    pub async fn write<T, F>(&self, f: F) -> Result<T, String>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, RusqliteError> + Send + 'static,
+    {
+        run(&self.writer, f).await
+    }
+
+    /// Blocking counterpart of `read`, for startup, the console and the
+    /// Omikron tasks. On a current-thread runtime, such as an actix-web
+    /// worker, it fails instead of blocking; use `read` there.
+    pub fn read_blocking<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&Connection) -> Result<T, RusqliteError>,
+    {
+        run_blocking(self.reader(), f)
+    }
+
+    /// Blocking counterpart of `write`, with the same limits as
+    /// `read_blocking`.
+    pub fn write_blocking<T, F>(&self, f: F) -> Result<T, String>
+    where
+        F: FnOnce(&Connection) -> Result<T, RusqliteError>,
+    {
+        run_blocking(&self.writer, f)
     }
 }
 
-/// Acquire the Connection from an Arc<Mutex<Connection>> and run the provided
-/// closure. Converts rusqlite::Error into a String on error.
-///
-/// Arguments:
-/// - `shared`: Arc<Mutex<Connection>>
-/// - `f`: closure that receives &Connection and returns Result<T, RusqliteError>
-///
-/// Returns Ok(T) or Err(String).
-pub fn with_conn<T, F>(shared: &Arc<Mutex<Connection>>, f: F) -> Result<T, String>
+async fn run<T, F>(conn: &Shared, f: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> Result<T, RusqliteError> + Send + 'static,
+{
+    let guard = conn.clone().lock_owned().await;
+    tokio::task::spawn_blocking(move || f(&*guard).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| format!("DB task failed: {}", e))?
+}
+
+fn run_blocking<T, F>(conn: &Shared, f: F) -> Result<T, String>
 where
     F: FnOnce(&Connection) -> Result<T, RusqliteError>,
 {
-    // When invoked from within an async runtime (such as Tokio), taking a blocking
-    // std::sync::Mutex lock on the runtime thread can cause deadlocks or permanent
-    // awaits. Detect whether we're running inside a Tokio runtime and, if so,
-    // execute the blocking lock + database closure using Tokio's blocking helper.
-    //
-    // The blocking section returns Result<T, String> so we can propagate errors
-    // in the same form as before.
-    if tokio::runtime::Handle::try_current().is_ok() {
-        tokio::task::block_in_place(|| {
-            let guard = shared
-                .lock()
-                .map_err(|e| format!("DB mutex poisoned: {:?}", e))?;
-            f(&*guard).map_err(|e| e.to_string())
-        })
-    } else {
-        let guard = shared
-            .lock()
-            .map_err(|e| format!("DB mutex poisoned: {:?}", e))?;
+    let run = || {
+        let guard = conn.blocking_lock();
         f(&*guard).map_err(|e| e.to_string())
+    };
+    // Waiting for the lock must not stall a runtime worker thread, and
+    // `block_in_place` panics on a current-thread runtime.
+    match tokio::runtime::Handle::try_current().map(|h| h.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(run),
+        Ok(_) => Err("blocking DB access on a current-thread runtime".to_string()),
+        Err(_) => run(),
     }
 }
 
-/// Initialize a general-purpose messages+contacts DB and return a shared
-/// connection. This helper creates a single DB file that can contain multiple
-/// tables (messages, contacts, ...). The SQL here is conservative and intended
-/// to be safe if called multiple times.
+/// A single schema step. Runs inside a transaction together with the
+/// `user_version` bump, so it either applies completely or not at all.
+pub struct Migration {
+    pub name: &'static str,
+    pub apply: fn(&Connection) -> Result<(), RusqliteError>,
+}
+
+/// Applies every migration past the DB's `PRAGMA user_version`, in order.
+/// Migration `n` (1-based) leaves the DB at `user_version = n`.
 ///
-/// Callers may prefer to call `create_shared_connection("messages", INIT_SQL)`
-/// directly, but this convenience is useful for code that expects both tables.
-pub fn create_general_messages_db() -> Result<Arc<Mutex<Connection>>, String> {
-    // Keep PRAGMA and schema in one multi-statement string so callers only
-    // need to call a single execute_batch.
-    const INIT_SQL: &str = r#"
-        PRAGMA journal_mode = WAL;
-        PRAGMA synchronous = NORMAL;
+/// Arguments:
+/// - `conn`: open connection to migrate.
+/// - `migrations`: the complete, ordered migration list for this DB.
+pub fn migrate(conn: &mut Connection, migrations: &[Migration]) -> Result<(), String> {
+    loop {
+        // IMMEDIATE takes the write lock before the version is read, so two
+        // handles opening the same file never apply the same step twice.
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(|e| format!("Starting schema migration failed: {}", e))?;
+        let current =
+            tx.pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))
+                .map_err(|e| format!("Reading schema version failed: {}", e))? as usize;
+        if current > migrations.len() {
+            return Err(format!(
+                "Database schema version {} is newer than this build supports ({})",
+                current,
+                migrations.len()
+            ));
+        }
+        let Some(migration) = migrations.get(current) else {
+            return Ok(());
+        };
 
-        CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            storage_owner INTEGER NOT NULL,
-            external_user INTEGER NOT NULL,
-            message_time INTEGER NOT NULL,
-            content TEXT NOT NULL,
-            sent_by_self INTEGER NOT NULL,
-            message_state TEXT NOT NULL,
-            height INTEGER NOT NULL DEFAULT 0
-        );
-
-        CREATE INDEX IF NOT EXISTS idx_messages_lookup
-            ON messages (storage_owner, external_user, message_time DESC);
-
-        CREATE TABLE IF NOT EXISTS contacts (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            storage_owner INTEGER NOT NULL,
-            user_id INTEGER NOT NULL,
-            user_name TEXT,
-            last_message_at INTEGER,
-            UNIQUE(storage_owner, user_id)
-        );
-
-        CREATE INDEX IF NOT EXISTS idx_contacts_owner
-            ON contacts (storage_owner, last_message_at DESC, user_id ASC);
-
-        CREATE TABLE IF NOT EXISTS communities (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            storage_owner INTEGER NOT NULL,
-            address TEXT NOT NULL,
-            title TEXT NOT NULL,
-            position TEXT NOT NULL,
-            UNIQUE(storage_owner, address)
-        );
-
-        CREATE INDEX IF NOT EXISTS idx_communities_owner
-            ON communities (storage_owner);
-    "#;
+        let version = current + 1;
+        (migration.apply)(&tx)
+            .and_then(|_| tx.pragma_update(None, "user_version", version as i64))
+            .and_then(|_| tx.commit())
+            .map_err(|e| format!("Migration {} ({}) failed: {}", version, migration.name, e))?;
+    }
+}
+
+/// Checkpoints and truncates the WAL, then rewrites the DB file, so content
+/// that was overwritten (e.g. before it was sealed) leaves no copies behind
+/// in free pages or the log. Must run outside a transaction.
+pub fn scrub(conn: &Connection) -> Result<(), RusqliteError> {
+    conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+}
+
+/// Whether `table` already has `column`; used by migrations that must also
+/// cope with databases created before migrations were tracked.
+pub fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, RusqliteError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        if row.get::<_, String>(1)? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Migrations for the shared messages DB. Append only: never edit or
+/// reorder an entry that has shipped.
+pub const MESSAGES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "base schema",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    storage_owner INTEGER NOT NULL,
+                    external_user INTEGER NOT NULL,
+                    message_time INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    sent_by_self INTEGER NOT NULL,
+                    message_state TEXT NOT NULL
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_messages_lookup
+                    ON messages (storage_owner, external_user, message_time DESC);
+
+                CREATE TABLE IF NOT EXISTS contacts (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    storage_owner INTEGER NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    user_name TEXT,
+                    last_message_at INTEGER,
+                    UNIQUE(storage_owner, user_id)
+                );
 
-    match create_shared_connection("messages", INIT_SQL) {
-        Ok(shared_conn) => {
-            // Attempt to add the height column for backwards compatibility.
-            // This will fail if the column already exists, which is expected.
-            let _ = with_conn(&shared_conn, |conn| {
-                let _ = conn.execute(
+                CREATE INDEX IF NOT EXISTS idx_contacts_owner
+                    ON contacts (storage_owner, last_message_at DESC, user_id ASC);
+
+                CREATE TABLE IF NOT EXISTS communities (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    storage_owner INTEGER NOT NULL,
+                    address TEXT NOT NULL,
+                    title TEXT NOT NULL,
+                    position TEXT NOT NULL,
+                    UNIQUE(storage_owner, address)
+                );
+
+                CREATE INDEX IF NOT EXISTS idx_communities_owner
+                    ON communities (storage_owner);
+
+                CREATE TABLE IF NOT EXISTS outbox (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    communication_type TEXT NOT NULL,
+                    payload TEXT NOT NULL,
+                    queued_at INTEGER NOT NULL,
+                    expires_at INTEGER NOT NULL
+                );
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "message height",
+        apply: |conn| {
+            if !has_column(conn, "messages", "height")? {
+                conn.execute(
                     "ALTER TABLE messages ADD COLUMN height INTEGER NOT NULL DEFAULT 0",
                     [],
+                )?;
+            }
+            Ok(())
+        },
+    },
+    Migration {
+        name: "message ids",
+        apply: |conn| {
+            if !has_column(conn, "messages", "message_id")? {
+                conn.execute("ALTER TABLE messages ADD COLUMN message_id INTEGER", [])?;
+            }
+            // Each side keeps its own copy, and ids come from the sender's
+            // side, so two partners of one owner may pick the same one: only
+            // owner, partner and id together are unique. Rows stored before
+            // ids were recorded stay NULL and never collide.
+            conn.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_conversation_message_id
+                    ON messages (storage_owner, external_user, message_id)",
+                [],
+            )?;
+            Ok(())
+        },
+    },
+    Migration {
+        name: "message cursor index",
+        apply: |conn| {
+            conn.execute_batch(
+                "CREATE INDEX IF NOT EXISTS idx_messages_cursor
+                    ON messages (storage_owner, external_user, message_time, id);",
+            )
+        },
+    },
+    Migration {
+        name: "message edits and tombstones",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                ALTER TABLE messages ADD COLUMN edited_at INTEGER;
+                ALTER TABLE messages ADD COLUMN deleted_at INTEGER;
+
+                CREATE TABLE message_edits (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    message_row INTEGER NOT NULL REFERENCES messages (id),
+                    content TEXT NOT NULL,
+                    replaced_at INTEGER NOT NULL
                 );
-                Ok(())
-            });
-            Ok(shared_conn)
-        }
-        Err(e) => Err(e),
+
+                CREATE INDEX idx_message_edits_row ON message_edits (message_row);
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "message search",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                CREATE VIRTUAL TABLE messages_fts USING fts5 (
+                    content,
+                    content = 'messages',
+                    content_rowid = 'id'
+                );
+                INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+
+                CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+
+                CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                        VALUES ('delete', old.id, old.content);
+                END;
+
+                CREATE TRIGGER messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, content)
+                        VALUES ('delete', old.id, old.content);
+                    INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+                END;
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "contact read markers",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                ALTER TABLE contacts ADD COLUMN last_read_id INTEGER;
+                ALTER TABLE contacts ADD COLUMN unread_count INTEGER NOT NULL DEFAULT 0;
+
+                UPDATE contacts SET unread_count = (
+                    SELECT COUNT(*) FROM messages m
+                    WHERE m.storage_owner = contacts.storage_owner
+                      AND m.external_user = contacts.user_id
+                      AND m.sent_by_self = 0
+                      AND m.deleted_at IS NULL
+                      AND m.message_state != 'read'
+                );
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "message attachments",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                ALTER TABLE messages ADD COLUMN blob_hash TEXT;
+                CREATE INDEX idx_messages_blob ON messages (blob_hash)
+                    WHERE blob_hash IS NOT NULL;
+
+                CREATE TABLE blobs (
+                    hash TEXT PRIMARY KEY,
+                    size INTEGER NOT NULL,
+                    uploaded_by INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    refcount INTEGER NOT NULL DEFAULT 0
+                );
+                CREATE INDEX idx_blobs_uploader ON blobs (uploaded_by);
+
+                CREATE TRIGGER blobs_ref_insert AFTER INSERT ON messages
+                WHEN new.blob_hash IS NOT NULL BEGIN
+                    UPDATE blobs SET refcount = refcount + 1 WHERE hash = new.blob_hash;
+                END;
+
+                CREATE TRIGGER blobs_ref_delete AFTER DELETE ON messages
+                WHEN old.blob_hash IS NOT NULL BEGIN
+                    UPDATE blobs SET refcount = refcount - 1 WHERE hash = old.blob_hash;
+                END;
+
+                CREATE TRIGGER blobs_ref_update AFTER UPDATE OF blob_hash ON messages BEGIN
+                    UPDATE blobs SET refcount = refcount - 1
+                        WHERE old.blob_hash IS NOT NULL AND hash = old.blob_hash;
+                    UPDATE blobs SET refcount = refcount + 1
+                        WHERE new.blob_hash IS NOT NULL AND hash = new.blob_hash;
+                END;
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "message retention",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                ALTER TABLE messages ADD COLUMN expires_at INTEGER;
+                CREATE INDEX idx_messages_expiry ON messages (expires_at)
+                    WHERE expires_at IS NOT NULL;
+
+                CREATE TABLE retention_policies (
+                    storage_owner INTEGER NOT NULL,
+                    external_user INTEGER NOT NULL,
+                    max_age_ms INTEGER,
+                    max_count INTEGER,
+                    PRIMARY KEY (storage_owner, external_user)
+                );
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "encrypted content",
+        apply: |conn| {
+            // The index moves from the content, which becomes ciphertext, to
+            // per-user search tokens. Existing rows start without tokens, so no
+            // plaintext is copied into the index; they are sealed and indexed
+            // at startup by `message_crypto::encrypt_existing_rows`, once the
+            // keys are loaded.
+            conn.execute_batch(
+                r#"
+                ALTER TABLE messages ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE messages ADD COLUMN search_tokens TEXT NOT NULL DEFAULT '';
+                ALTER TABLE message_edits ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;
+
+                DROP TRIGGER messages_fts_insert;
+                DROP TRIGGER messages_fts_delete;
+                DROP TRIGGER messages_fts_update;
+                DROP TABLE messages_fts;
+
+                CREATE VIRTUAL TABLE messages_fts USING fts5 (
+                    search_tokens,
+                    content = 'messages',
+                    content_rowid = 'id'
+                );
+                INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+
+                CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                    INSERT INTO messages_fts (rowid, search_tokens)
+                        VALUES (new.id, new.search_tokens);
+                END;
+
+                CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, search_tokens)
+                        VALUES ('delete', old.id, old.search_tokens);
+                END;
+
+                CREATE TRIGGER messages_fts_update AFTER UPDATE OF search_tokens ON messages BEGIN
+                    INSERT INTO messages_fts (messages_fts, rowid, search_tokens)
+                        VALUES ('delete', old.id, old.search_tokens);
+                    INSERT INTO messages_fts (rowid, search_tokens)
+                        VALUES (new.id, new.search_tokens);
+                END;
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "contact flags",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                ALTER TABLE contacts ADD COLUMN blocked INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE contacts ADD COLUMN muted INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE contacts ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE contacts ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "users",
+        apply: |conn| {
+            // Profiles used to live in users.json; `user_manager::load_users`
+            // imports that file once.
+            conn.execute_batch(
+                r#"
+                CREATE TABLE users (
+                    user_id INTEGER PRIMARY KEY,
+                    username TEXT NOT NULL UNIQUE,
+                    display_name TEXT,
+                    public_key TEXT NOT NULL,
+                    private_key_hash TEXT NOT NULL,
+                    reset_token TEXT NOT NULL,
+                    created_at INTEGER NOT NULL
+                );
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "user purges",
+        apply: |conn| {
+            conn.execute_batch(
+                r#"
+                CREATE TABLE user_purges (
+                    user_id INTEGER PRIMARY KEY,
+                    purge_at INTEGER NOT NULL,
+                    export INTEGER NOT NULL
+                );
+                "#,
+            )
+        },
+    },
+    Migration {
+        name: "user moves",
+        apply: |conn| conn.execute_batch("ALTER TABLE users ADD COLUMN moved_at INTEGER;"),
+    },
+    Migration {
+        name: "purges without export",
+        // Exports are handed to the operator before a purge is scheduled.
+        apply: |conn| conn.execute_batch("ALTER TABLE user_purges DROP COLUMN export;"),
+    },
+];
+
+/// The oldest SQLite `MESSAGES_MIGRATIONS` run on: `DROP COLUMN` needs 3.35.
+const MIN_SQLITE_VERSION: i32 = 3_035_000;
+
+/// Fails with a clear error if the linked SQLite cannot run
+/// `MESSAGES_MIGRATIONS`, instead of a migration failing halfway through
+/// startup.
+fn check_sqlite() -> Result<(), String> {
+    if rusqlite::version_number() < MIN_SQLITE_VERSION {
+        return Err(format!(
+            "SQLite {} is too old, at least 3.35.0 is required",
+            rusqlite::version()
+        ));
     }
+    // Message search is an FTS5 table.
+    Connection::open_in_memory()
+        .and_then(|conn| conn.execute_batch("CREATE VIRTUAL TABLE temp.probe USING fts5(text);"))
+        .map_err(|e| {
+            format!(
+                "SQLite {} lacks FTS5, which message search needs: {}",
+                rusqlite::version(),
+                e
+            )
+        })
 }
 
-/*
-Example usage:
+/// Open the general-purpose messages+contacts DB and bring its schema up to
+/// date. The single DB file holds several tables (messages, contacts, ...);
+/// see `MESSAGES_MIGRATIONS` for the schema.
+///
+/// Only `MESSAGES_DB` holds the result; everything goes through it.
+fn create_general_messages_db() -> Result<Database, String> {
+    const INIT_SQL: &str = r#"
+        PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = NORMAL;
+        PRAGMA secure_delete = ON;
+    "#;
 
-// In some util module (at init time, e.g. lazy_static or LazyLock)
-static MESSAGES_DB: LazyLock<Arc<Mutex<Connection>>> = LazyLock::new(|| {
-    create_general_messages_db().expect("failed to create messages DB")
-});
+    check_sqlite()?;
+    Database::open("messages", INIT_SQL, MESSAGES_MIGRATIONS, READERS)
+}
 
-// Later, to run a query:
-let res: Result<Vec<MyRow>, String> = with_conn(&MESSAGES_DB, |conn| {
-    let mut stmt = conn.prepare("SELECT ...")?;
-    let rows = stmt.query_map(...)?;
-    // collect and return Ok(...)
-});
-*/
+#[cfg(test)]
+mod tests {
+    use super::{Database, MESSAGES_MIGRATIONS, Migration, check_sqlite, has_column, migrate};
+    use rusqlite::Connection;
+
+    fn user_version(conn: &Connection) -> i64 {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn untracked_database_with_height_column_is_adopted() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                storage_owner INTEGER NOT NULL,
+                external_user INTEGER NOT NULL,
+                message_time INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                sent_by_self INTEGER NOT NULL,
+                message_state TEXT NOT NULL,
+                height INTEGER NOT NULL DEFAULT 0
+            );",
+        )
+        .unwrap();
+
+        migrate(&mut conn, MESSAGES_MIGRATIONS).unwrap();
+        assert_eq!(user_version(&conn), MESSAGES_MIGRATIONS.len() as i64);
+        assert!(has_column(&conn, "messages", "message_id").unwrap());
+
+        // A second run has nothing left to do.
+        migrate(&mut conn, MESSAGES_MIGRATIONS).unwrap();
+    }
+
+    #[test]
+    fn linked_sqlite_runs_the_migrations() {
+        check_sqlite().unwrap();
+    }
+
+    #[test]
+    fn failed_migration_rolls_back_and_reports_its_name() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            Migration {
+                name: "create",
+                apply: |conn| conn.execute_batch("CREATE TABLE a (x INTEGER);"),
+            },
+            Migration {
+                name: "broken",
+                apply: |conn| {
+                    conn.execute_batch("CREATE TABLE b (x INTEGER);")?;
+                    conn.execute_batch("ALTER TABLE missing ADD COLUMN y INTEGER;")
+                },
+            },
+        ];
+
+        let err = migrate(&mut conn, &migrations).unwrap_err();
+        assert!(err.contains("Migration 2 (broken)"));
+        assert_eq!(user_version(&conn), 1);
+        assert!(conn.prepare("SELECT * FROM b").is_err());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", 99).unwrap();
+        assert!(migrate(&mut conn, MESSAGES_MIGRATIONS).is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn blocking_access_fails_on_a_current_thread_runtime() {
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        assert!(db.read_blocking(user_version_of).is_err());
+        assert!(db.read(user_version_of).await.is_ok());
+    }
+
+    fn user_version_of(conn: &Connection) -> Result<i64, rusqlite::Error> {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+    }
+}
@@ -0,0 +1,518 @@
+//! Per-user export and import of stored conversations.
+//!
+//! An export is JSON Lines: a header, then one line per contact, community
+//! and message of the `storage_owner`, each tagged with its `kind`. Imports
+//! merge such a file into an owner's storage; messages with a wire id are
+//! matched by it, older rows without one by partner, time, side and content,
+//! so importing the same file twice adds nothing.
+
+use crate::util::chats_util;
+use crate::util::db::MESSAGES_DB;
+use crate::util::message_crypto::{self, UserKeys, keys_for};
+use json::{JsonValue, object};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub messages: usize,
+    pub contacts: usize,
+    pub communities: usize,
+    /// Messages already stored and left untouched.
+    pub skipped: usize,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Everything `storage_owner` has stored, as JSON Lines.
+pub async fn export(storage_owner: i64) -> Result<String, String> {
+    let keys = keys_for(storage_owner);
+    MESSAGES_DB
+        .read(move |conn| export_rows(conn, keys.as_ref(), storage_owner))
+        .await
+}
+
+fn export_rows(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    storage_owner: i64,
+) -> rusqlite::Result<String> {
+    let mut lines = vec![
+        object! {
+            "kind" => "export",
+            "version" => EXPORT_VERSION,
+            "storage_owner" => storage_owner,
+            "exported_at" => now_millis(),
+        }
+        .dump(),
+    ];
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT user_id, user_name, last_message_at, last_read_id,
+               blocked, muted, archived, pinned
+        FROM contacts WHERE storage_owner = ?1 ORDER BY user_id
+        "#,
+    )?;
+    let contacts = stmt.query_map(params![storage_owner], |r| {
+        Ok(object! {
+            "kind" => "contact",
+            "user_id" => r.get::<_, i64>(0)?,
+            "user_name" => r.get::<_, Option<String>>(1)?,
+            "last_message_at" => r.get::<_, Option<i64>>(2)?,
+            "last_read_id" => r.get::<_, Option<i64>>(3)?,
+            "blocked" => r.get::<_, bool>(4)?,
+            "muted" => r.get::<_, bool>(5)?,
+            "archived" => r.get::<_, bool>(6)?,
+            "pinned" => r.get::<_, bool>(7)?,
+        })
+    })?;
+    for contact in contacts {
+        lines.push(contact?.dump());
+    }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT address, title, position
+        FROM communities WHERE storage_owner = ?1 ORDER BY address
+        "#,
+    )?;
+    let communities = stmt.query_map(params![storage_owner], |r| {
+        Ok(object! {
+            "kind" => "community",
+            "address" => r.get::<_, String>(0)?,
+            "title" => r.get::<_, String>(1)?,
+            "position" => r.get::<_, String>(2)?,
+        })
+    })?;
+    for community in communities {
+        lines.push(community?.dump());
+    }
+
+    let mut stmt = conn.prepare(
+        r#"
+        SELECT id, external_user, message_id, message_time, content, sent_by_self,
+               message_state, height, edited_at, deleted_at, blob_hash, expires_at, encrypted
+        FROM messages WHERE storage_owner = ?1
+        ORDER BY external_user, message_time, id
+        "#,
+    )?;
+    let messages = stmt.query_map(params![storage_owner], |r| {
+        Ok(object! {
+            "kind" => "message",
+            "id" => r.get::<_, i64>(0)?,
+            "external_user" => r.get::<_, i64>(1)?,
+            "message_id" => r.get::<_, Option<i64>>(2)?,
+            "message_time" => r.get::<_, i64>(3)?,
+            "content" => message_crypto::open(keys, &r.get::<_, String>(4)?, r.get(12)?)?,
+            "sent_by_self" => r.get::<_, i64>(5)? != 0,
+            "message_state" => r.get::<_, String>(6)?,
+            "height" => r.get::<_, Option<i64>>(7)?.unwrap_or(0),
+            "edited_at" => r.get::<_, Option<i64>>(8)?,
+            "deleted_at" => r.get::<_, Option<i64>>(9)?,
+            "blob_hash" => r.get::<_, Option<String>>(10)?,
+            "expires_at" => r.get::<_, Option<i64>>(11)?,
+        })
+    })?;
+    for message in messages {
+        lines.push(message?.dump());
+    }
+
+    Ok(lines.join("\n") + "\n")
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A read-only page of an export, one section per conversation.
+pub fn render_html(jsonl: &str) -> String {
+    let mut names = HashMap::new();
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Chat export</title>\
+         <style>body{font-family:sans-serif}.self{text-align:right}\
+         .meta{color:#888;font-size:small}</style></head><body>\n",
+    );
+    let mut current = None;
+
+    for line in jsonl.lines() {
+        let Ok(entry) = json::parse(line) else {
+            continue;
+        };
+        match entry["kind"].as_str() {
+            Some("contact") => {
+                if let (Some(id), Some(name)) =
+                    (entry["user_id"].as_i64(), entry["user_name"].as_str())
+                {
+                    names.insert(id, name.to_string());
+                }
+            }
+            Some("message") => {
+                let partner = entry["external_user"].as_i64().unwrap_or(0);
+                if current != Some(partner) {
+                    if current.is_some() {
+                        html.push_str("</section>\n");
+                    }
+                    let title = names
+                        .get(&partner)
+                        .cloned()
+                        .unwrap_or_else(|| partner.to_string());
+                    html.push_str(&format!("<section><h2>{}</h2>\n", escape_html(&title)));
+                    current = Some(partner);
+                }
+                let class = if entry["sent_by_self"].as_bool().unwrap_or(false) {
+                    "self"
+                } else {
+                    "partner"
+                };
+                let content = if entry["deleted_at"].is_number() {
+                    "<i>deleted</i>".to_string()
+                } else {
+                    escape_html(entry["content"].as_str().unwrap_or(""))
+                };
+                html.push_str(&format!(
+                    "<p class=\"{}\">{}<br><span class=\"meta\">{}{}</span></p>\n",
+                    class,
+                    content,
+                    entry["message_time"].as_i64().unwrap_or(0),
+                    if entry["edited_at"].is_number() {
+                        " (edited)"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+            _ => {}
+        }
+    }
+    if current.is_some() {
+        html.push_str("</section>\n");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+/// Merges an export into `storage_owner`'s storage in one transaction.
+pub async fn import(storage_owner: i64, jsonl: &str) -> Result<ImportSummary, String> {
+    let mut entries = Vec::new();
+    for (number, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = json::parse(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        if entry["kind"] == "export" && entry["version"].as_u32().unwrap_or(0) > EXPORT_VERSION {
+            return Err(format!(
+                "export version {} is newer than this Iota",
+                entry["version"]
+            ));
+        }
+        entries.push(entry);
+    }
+
+    let keys = keys_for(storage_owner);
+    MESSAGES_DB
+        .write(move |conn| import_entries(conn, keys.as_ref(), storage_owner, &entries))
+        .await
+}
+
+fn import_entries(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    storage_owner: i64,
+    entries: &[JsonValue],
+) -> rusqlite::Result<ImportSummary> {
+    let tx = conn.unchecked_transaction()?;
+    let mut summary = ImportSummary::default();
+    let mut conversations = BTreeSet::new();
+    // Exported row ids of read markers, resolved once messages exist.
+    let mut markers = Vec::new();
+
+    for entry in entries {
+        match entry["kind"].as_str() {
+            Some("contact") => {
+                let Some(user_id) = entry["user_id"].as_i64() else {
+                    continue;
+                };
+                import_contact(&tx, storage_owner, entry, user_id)?;
+                if let Some(marker) = entry["last_read_id"].as_i64() {
+                    markers.push((user_id, marker));
+                }
+                conversations.insert(user_id);
+                summary.contacts += 1;
+            }
+            Some("community") => {
+                summary.communities += tx.execute(
+                    r#"
+                    INSERT INTO communities (storage_owner, address, title, position)
+                    VALUES (?1, ?2, ?3, ?4)
+                    ON CONFLICT (storage_owner, address) DO NOTHING
+                    "#,
+                    params![
+                        storage_owner,
+                        entry["address"].as_str().unwrap_or(""),
+                        entry["title"].as_str().unwrap_or(""),
+                        entry["position"].as_str().unwrap_or("")
+                    ],
+                )?;
+            }
+            Some("message") => {
+                let Some(external_user) = entry["external_user"].as_i64() else {
+                    continue;
+                };
+                if import_message(&tx, keys.as_ref(), storage_owner, external_user, entry)? {
+                    summary.messages += 1;
+                } else {
+                    summary.skipped += 1;
+                }
+                conversations.insert(external_user);
+            }
+            _ => {}
+        }
+    }
+
+    for (user_id, marker) in markers {
+        if let Some(row_id) = resolve_row(&tx, storage_owner, user_id, entries, marker)? {
+            chats_util::mark_read(&tx, storage_owner, user_id, row_id)?;
+        }
+    }
+    for user_id in conversations {
+        // Conversations imported without a contact line still show up in `get_chats`.
+        tx.execute(
+            r#"
+            INSERT INTO contacts (storage_owner, user_id, last_message_at)
+            SELECT ?1, ?2, MAX(message_time) FROM messages
+            WHERE storage_owner = ?1 AND external_user = ?2
+            ON CONFLICT (storage_owner, user_id) DO UPDATE SET
+                last_message_at = MAX(
+                    COALESCE(contacts.last_message_at, excluded.last_message_at),
+                    COALESCE(excluded.last_message_at, contacts.last_message_at)
+                )
+            "#,
+            params![storage_owner, user_id],
+        )?;
+        chats_util::refresh_unread(&tx, storage_owner, user_id)?;
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+fn import_contact(
+    conn: &Connection,
+    storage_owner: i64,
+    entry: &JsonValue,
+    user_id: i64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO contacts (
+            storage_owner, user_id, user_name, last_message_at,
+            blocked, muted, archived, pinned
+        )
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        ON CONFLICT (storage_owner, user_id) DO UPDATE SET
+            user_name = COALESCE(contacts.user_name, excluded.user_name),
+            last_message_at = MAX(
+                COALESCE(contacts.last_message_at, excluded.last_message_at),
+                COALESCE(excluded.last_message_at, contacts.last_message_at)
+            )
+        "#,
+        params![
+            storage_owner,
+            user_id,
+            entry["user_name"].as_str(),
+            entry["last_message_at"].as_i64(),
+            entry["blocked"].as_bool().unwrap_or(false),
+            entry["muted"].as_bool().unwrap_or(false),
+            entry["archived"].as_bool().unwrap_or(false),
+            entry["pinned"].as_bool().unwrap_or(false)
+        ],
+    )?;
+    Ok(())
+}
+
+/// Inserts one exported message unless an equal copy is already stored.
+fn import_message(
+    conn: &Connection,
+    keys: Option<&UserKeys>,
+    storage_owner: i64,
+    external_user: i64,
+    entry: &JsonValue,
+) -> rusqlite::Result<bool> {
+    let message_id = entry["message_id"].as_i64();
+    let message_time = entry["message_time"].as_i64().unwrap_or(0);
+    let content = entry["content"].as_str().unwrap_or("");
+    let sent_by_self = entry["sent_by_self"].as_bool().unwrap_or(false) as i64;
+
+    if message_id.is_none() {
+        // Sealed content differs between copies, so compare the plaintext.
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT content, encrypted FROM messages
+            WHERE storage_owner = ?1 AND external_user = ?2 AND message_id IS NULL
+              AND message_time = ?3 AND sent_by_self = ?4
+            "#,
+        )?;
+        let candidates = stmt.query_map(
+            params![storage_owner, external_user, message_time, sent_by_self],
+            |r| Ok((r.get::<_, String>(0)?, r.get::<_, bool>(1)?)),
+        )?;
+        for candidate in candidates {
+            let (stored, encrypted) = candidate?;
+            if message_crypto::open(keys, &stored, encrypted)? == content {
+                return Ok(false);
+            }
+        }
+    }
+
+    let (stored, encrypted) = message_crypto::seal(keys, content);
+
+    let inserted = conn.execute(
+        r#"
+        INSERT INTO messages (
+            storage_owner, external_user, message_id, message_time, content, sent_by_self,
+            message_state, height, edited_at, deleted_at, blob_hash, expires_at, encrypted,
+            search_tokens
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+        ON CONFLICT (storage_owner, external_user, message_id) DO NOTHING
+        "#,
+        params![
+            storage_owner,
+            external_user,
+            message_id,
+            message_time,
+            stored,
+            sent_by_self,
+            entry["message_state"].as_str().unwrap_or("sent"),
+            entry["height"].as_i64().unwrap_or(0),
+            entry["edited_at"].as_i64(),
+            entry["deleted_at"].as_i64(),
+            entry["blob_hash"].as_str(),
+            entry["expires_at"].as_i64(),
+            encrypted,
+            message_crypto::search_tokens(keys, content)
+        ],
+    )?;
+    Ok(inserted > 0)
+}
+
+/// Finds the local row matching the exported row `exported_id`.
+fn resolve_row(
+    conn: &Connection,
+    storage_owner: i64,
+    external_user: i64,
+    entries: &[JsonValue],
+    exported_id: i64,
+) -> rusqlite::Result<Option<i64>> {
+    let Some(entry) = entries
+        .iter()
+        .find(|e| e["kind"] == "message" && e["id"].as_i64() == Some(exported_id))
+    else {
+        return Ok(None);
+    };
+    conn.query_row(
+        r#"
+        SELECT id FROM messages
+        WHERE storage_owner = ?1 AND external_user = ?2
+          AND (message_id = ?3 OR (?3 IS NULL AND message_id IS NULL AND message_time = ?4))
+        ORDER BY id DESC
+        LIMIT 1
+        "#,
+        params![
+            storage_owner,
+            external_user,
+            entry["message_id"].as_i64(),
+            entry["message_time"].as_i64().unwrap_or(0)
+        ],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::chat_files::{self, MessageCursor, NewMessage};
+    use crate::util::communities_util::CommunitiesUtil;
+    use ttp_core::rand_u32;
+
+    fn add_message(sent_by_self: bool, send_time: u128, owner: i64, partner: i64, content: &str) {
+        let message = NewMessage {
+            message_id: rand_u32(),
+            send_time,
+            sent_by_self,
+            storage_owner: owner,
+            external_user: partner,
+            content: content.to_string(),
+            height: 0,
+        };
+        MESSAGES_DB
+            .write_blocking(|conn| chat_files::add_message(conn, None, &message))
+            .unwrap();
+    }
+
+    // The blocking helpers need a multi-threaded runtime.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_round_trips_and_import_is_idempotent() {
+        let owner = 10_000_000 + rand_u32() as i64;
+        let partner = owner + 1;
+        let target = owner + 2;
+        add_message(true, 1_000, owner, partner, "hi <b>");
+        add_message(false, 2_000, owner, partner, "hello");
+        MESSAGES_DB
+            .write_blocking(|conn| {
+                CommunitiesUtil::add_community(conn, owner, "c.example", "Club", "0")
+            })
+            .unwrap();
+
+        let exported = export(owner).await.unwrap();
+        assert_eq!(exported.lines().count(), 1 + 1 + 1 + 2);
+
+        let first = import(target, &exported).await.unwrap();
+        assert_eq!(
+            first,
+            ImportSummary {
+                messages: 2,
+                contacts: 1,
+                communities: 1,
+                skipped: 0,
+            }
+        );
+        let second = import(target, &exported).await.unwrap();
+        assert_eq!(second.messages, 0);
+        assert_eq!(second.skipped, 2);
+        assert_eq!(second.communities, 0);
+
+        let page = MESSAGES_DB
+            .read_blocking(|conn| {
+                chat_files::get_messages(conn, None, target, partner, MessageCursor::Offset(0), 10)
+            })
+            .unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[1]["content"], "hi <b>");
+
+        let html = render_html(&exported);
+        assert!(html.contains("hi &lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[tokio::test]
+    async fn newer_exports_and_broken_lines_are_refused() {
+        assert!(
+            import(1, "{\"kind\":\"export\",\"version\":99}\n")
+                .await
+                .is_err()
+        );
+        assert!(import(1, "not json\n").await.is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use reqwest::Client;
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::{self, BufReader, Read};
+use std::io::{self, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use sysinfo::System;
 use tokio::io::AsyncWriteExt;
@@ -144,6 +144,33 @@ pub fn save_file(path: &str, name: &str, value: &str) {
     }
 }
 
+/// Like `save_file`, but returns errors instead of logging them, and
+/// replaces the file atomically: the content is synced to a `.tmp` sibling
+/// first and then renamed over `name`.
+pub fn write_file(path: &str, name: &str, value: &[u8]) -> io::Result<()> {
+    let dir = Path::new(&get_directory()).join(path);
+    fs::create_dir_all(&dir)?;
+    let temp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&temp)?;
+    file.write_all(value)?;
+    file.sync_all()?;
+    fs::rename(&temp, dir.join(name))
+}
+
+pub fn rename_file(path: &str, name: &str, new_name: &str) -> bool {
+    let dir = Path::new(&get_directory()).join(path);
+    if let Err(e) = fs::rename(dir.join(name), dir.join(new_name)) {
+        log!(
+            "[IMPORTANT] Couldn't rename {} to {}: {}",
+            name,
+            new_name,
+            e
+        );
+        return false;
+    }
+    true
+}
+
 pub fn get_children(path: &str) -> Vec<String> {
     let dir = Path::new(&get_directory()).join(path);
     let mut children = Vec::new();
@@ -157,6 +184,8 @@ pub fn get_children(path: &str) -> Vec<String> {
     children
 }
 
+/// The data directory, next to the executable.
+#[cfg(not(test))]
 pub fn get_directory() -> String {
     let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("."));
     exe.parent()
@@ -165,6 +194,15 @@ pub fn get_directory() -> String {
         .to_string()
 }
 
+/// A temporary data directory of the test run's own, so tests never write
+/// into a real one.
+#[cfg(test)]
+pub fn get_directory() -> String {
+    let dir = std::env::temp_dir().join(format!("iota-test-{}", std::process::id()));
+    let _ = fs::create_dir_all(&dir);
+    dir.to_string_lossy().to_string()
+}
+
 // Helper to download the zip file content to a file on disk
 #[allow(dead_code)]
 pub fn used_space() -> u64 {
@@ -0,0 +1,328 @@
+//! Encrypted at-rest storage for the Iota's X448 identity.
+//!
+//! The keypair lives in `keystore.json`, with the secret sealed by AES-256-GCM.
+//! The wrapping key is derived with PBKDF2 from `IOTA_KEYSTORE_PASSPHRASE`
+//! when that variable is set, otherwise it is read from the machine-bound
+//! `keystore.key` file (created on first use). Once loaded, the keys are kept
+//! in memory for `handle_challenge` and friends. `rewrap` replaces the
+//! wrapping key; the identity itself is never rotated.
+
+use crate::util::config_util::CONFIG;
+use crate::util::crypto_helper::{self, KeyPair};
+use crate::util::file_util::{get_directory, has_file, load_file};
+use aes_gcm::{
+    Aes256Gcm, Nonce,
+    aead::{Aead, KeyInit, OsRng},
+};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use json::object;
+use once_cell::sync::Lazy;
+use rand_core::RngCore;
+use sha2::Sha256;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+const KEYSTORE_FILE: &str = "keystore.json";
+const KEY_FILE: &str = "keystore.key";
+const PASSPHRASE_ENV: &str = "IOTA_KEYSTORE_PASSPHRASE";
+pub const PBKDF2_ROUNDS: u32 = 600_000;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Corrupt(String),
+    WrongKey,
+    NotLoaded,
+    Io(String),
+}
+
+impl std::fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeystoreError::Corrupt(e) => write!(f, "keystore is corrupt: {}", e),
+            KeystoreError::WrongKey => write!(
+                f,
+                "keystore could not be decrypted (wrong passphrase or key file)"
+            ),
+            KeystoreError::NotLoaded => write!(f, "no identity loaded"),
+            KeystoreError::Io(e) => write!(f, "keystore I/O failed: {}", e),
+        }
+    }
+}
+
+/// Base64 encoded identity as used on the wire.
+#[derive(Clone)]
+pub struct IotaKeys {
+    pub public_key: String,
+    pub private_key: String,
+}
+
+static KEYS: Lazy<RwLock<Option<IotaKeys>>> = Lazy::new(|| RwLock::new(None));
+
+pub fn get_keys() -> Option<IotaKeys> {
+    KEYS.read().unwrap().clone()
+}
+
+pub fn has_keystore() -> bool {
+    has_file("", KEYSTORE_FILE)
+}
+
+/// Load the keystore into memory. `Ok(false)` means none exists yet.
+pub fn load() -> Result<bool, KeystoreError> {
+    if !has_keystore() {
+        return Ok(false);
+    }
+    let keys = match read_keystore(KEYSTORE_FILE) {
+        // A rewrap that was interrupted after the new key file was moved in
+        // left the matching keystore next to it; finish the rewrap.
+        Err(KeystoreError::WrongKey) if has_file("", &pending(KEYSTORE_FILE)) => {
+            let keys = read_keystore(&pending(KEYSTORE_FILE))?;
+            let dir = PathBuf::from(get_directory());
+            fs::rename(dir.join(pending(KEYSTORE_FILE)), dir.join(KEYSTORE_FILE))
+                .map_err(|e| KeystoreError::Io(e.to_string()))?;
+            keys
+        }
+        result => result?,
+    };
+    *KEYS.write().unwrap() = Some(keys);
+    Ok(true)
+}
+
+fn read_keystore(name: &str) -> Result<IotaKeys, KeystoreError> {
+    let stored =
+        json::parse(&load_file("", name)).map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+
+    let public_key = stored["public_key"]
+        .as_str()
+        .ok_or(KeystoreError::Corrupt("public_key missing".to_string()))?
+        .to_string();
+    let salt = decode_field(&stored["salt"], "salt")?;
+    let sealed = decode_field(&stored["private_key"], "private_key")?;
+
+    let key = wrapping_key(stored["kdf"].as_str().unwrap_or("keyfile"), &salt, None)?;
+    let secret = open(&key, &sealed)?;
+    Ok(IotaKeys {
+        public_key,
+        private_key: STANDARD.encode(secret),
+    })
+}
+
+/// One identity for the whole test run, so tests that seal under it do not
+/// replace each other's.
+#[cfg(test)]
+pub fn test_identity() -> IotaKeys {
+    static STORED: std::sync::Once = std::sync::Once::new();
+    STORED.call_once(|| store(&crypto_helper::generate_keypair()).unwrap());
+    get_keys().unwrap()
+}
+
+/// Move a plaintext keypair left in `config.json` by older builds into the
+/// keystore and strip it from the config.
+pub async fn import_from_config() -> Result<(), KeystoreError> {
+    let mut conf = CONFIG.write().await;
+    let Some(private_key) = conf.get_private_key() else {
+        return Ok(());
+    };
+    if !has_keystore() {
+        let secret = crypto_helper::load_secret_key(&private_key).ok_or(KeystoreError::Corrupt(
+            "private_key in config.json".to_string(),
+        ))?;
+        let public = x448::PublicKey::from(&secret);
+        store(&KeyPair { secret, public })?;
+    }
+    conf.remove("private_key");
+    conf.remove("public_key");
+    conf.update();
+    Ok(())
+}
+
+/// Seal `key_pair` under a fresh salt and make it the active identity.
+pub fn store(key_pair: &KeyPair) -> Result<(), KeystoreError> {
+    let keys = IotaKeys {
+        public_key: crypto_helper::public_key_to_base64(&key_pair.public),
+        private_key: crypto_helper::secret_key_to_base64(&key_pair.secret),
+    };
+    write(&keys, false)?;
+    *KEYS.write().unwrap() = Some(keys);
+    Ok(())
+}
+
+/// Re-encrypt the current identity with a new salt and, in key file mode,
+/// a newly generated key file. The X448 identity itself is unchanged: the
+/// message keys and key bundles are derived from it, and Omikron knows the
+/// Iota by its public key.
+pub fn rewrap() -> Result<(), KeystoreError> {
+    let keys = get_keys().ok_or(KeystoreError::NotLoaded)?;
+    write(&keys, true)
+}
+
+/// Seals `keys` into `keystore.json`. Both files are replaced atomically,
+/// the key file first; `load` completes a write interrupted in between.
+fn write(keys: &IotaKeys, new_key_file: bool) -> Result<(), KeystoreError> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    let passphrase = std::env::var(PASSPHRASE_ENV).is_ok();
+    let kdf = if passphrase { "passphrase" } else { "keyfile" };
+    let mut machine_key = None;
+    if !passphrase && (new_key_file || !has_file("", KEY_FILE)) {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        machine_key = Some(bytes);
+    }
+    let key = wrapping_key(kdf, &salt, machine_key.as_ref())?;
+    let secret = STANDARD
+        .decode(&keys.private_key)
+        .map_err(|e| KeystoreError::Corrupt(e.to_string()))?;
+
+    let stored = object! {
+        "version" => 1,
+        "kdf" => kdf,
+        "salt" => STANDARD.encode(salt),
+        "public_key" => keys.public_key.clone(),
+        "private_key" => STANDARD.encode(seal(&key, &secret)?),
+    };
+
+    let dir = PathBuf::from(get_directory());
+    let mut files = Vec::new();
+    if let Some(machine_key) = &machine_key {
+        files.push((dir.join(KEY_FILE), machine_key.to_vec()));
+    }
+    files.push((dir.join(KEYSTORE_FILE), stored.pretty(2).into_bytes()));
+    replace_files(&files).map_err(|e| KeystoreError::Io(e.to_string()))
+}
+
+fn pending(name: &str) -> String {
+    format!("{}.tmp", name)
+}
+
+/// Writes every file to a synced `.tmp` sibling, then renames them over the
+/// originals in order. Nothing is replaced unless all were written.
+fn replace_files(files: &[(PathBuf, Vec<u8>)]) -> io::Result<()> {
+    let temp =
+        |path: &Path| path.with_file_name(pending(&path.file_name().unwrap().to_string_lossy()));
+    for (path, content) in files {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(temp(path))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    for (path, _) in files {
+        fs::rename(temp(path), path)?;
+        #[cfg(unix)]
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// A base64 field of a stored JSON document.
+pub fn decode_field(value: &json::JsonValue, name: &str) -> Result<Vec<u8>, KeystoreError> {
+    let raw = value
+        .as_str()
+        .ok_or(KeystoreError::Corrupt(format!("{} missing", name)))?;
+    STANDARD
+        .decode(raw)
+        .map_err(|e| KeystoreError::Corrupt(format!("{}: {}", name, e)))
+}
+
+/// The key `keystore.json` is sealed with. `machine_key` stands in for a
+/// key file that is not written yet.
+fn wrapping_key(
+    kdf: &str,
+    salt: &[u8],
+    machine_key: Option<&[u8; 32]>,
+) -> Result<[u8; 32], KeystoreError> {
+    let mut key = [0u8; 32];
+    match kdf {
+        "passphrase" => {
+            let passphrase = std::env::var(PASSPHRASE_ENV)
+                .map_err(|_| KeystoreError::Io(format!("{} is not set", PASSPHRASE_ENV)))?;
+            pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+        }
+        _ => {
+            let machine_key = match machine_key {
+                Some(machine_key) => machine_key.to_vec(),
+                None => {
+                    let path = Path::new(&get_directory()).join(KEY_FILE);
+                    fs::read(&path).map_err(|e| KeystoreError::Io(e.to_string()))?
+                }
+            };
+            if machine_key.len() != 32 {
+                return Err(KeystoreError::Corrupt(format!(
+                    "{} has wrong length",
+                    KEY_FILE
+                )));
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(&machine_key, salt, 1, &mut key);
+        }
+    }
+    Ok(key)
+}
+
+/// AES-256-GCM under `key`, with a random nonce prefixed.
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>, KeystoreError> {
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Key length should be correct");
+    let mut nonce_bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+        .map_err(|_| KeystoreError::Corrupt("encryption failed".to_string()))?;
+    // prefix nonce to ciphertext
+    let mut out = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Reverses `seal`; a wrong key is `KeystoreError::WrongKey`.
+pub fn open(key: &[u8; 32], sealed: &[u8]) -> Result<Vec<u8>, KeystoreError> {
+    if sealed.len() < 12 {
+        return Err(KeystoreError::Corrupt("sealed key too short".to_string()));
+    }
+    let cipher = Aes256Gcm::new_from_slice(key).expect("Key length should be correct");
+    cipher
+        .decrypt(Nonce::from_slice(&sealed[..12]), &sealed[12..])
+        .map_err(|_| KeystoreError::WrongKey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, replace_files, seal};
+    use std::fs;
+
+    #[test]
+    fn sealed_secret_only_opens_with_the_same_key() {
+        let key = [7u8; 32];
+        let sealed = seal(&key, b"secret").unwrap();
+        assert_eq!(open(&key, &sealed).unwrap(), b"secret");
+        assert!(open(&[8u8; 32], &sealed).is_err());
+        assert!(open(&key, &sealed[..8]).is_err());
+    }
+
+    #[test]
+    fn files_are_replaced_together() {
+        let dir = std::env::temp_dir().join(format!("keystore-{}", ttp_core::rand_u32()));
+        let (key, store) = (dir.join("keystore.key"), dir.join("keystore.json"));
+        replace_files(&[
+            (key.clone(), b"old".to_vec()),
+            (store.clone(), b"{}".to_vec()),
+        ])
+        .unwrap();
+        replace_files(&[(key.clone(), b"new".to_vec())]).unwrap();
+
+        assert_eq!(fs::read(&key).unwrap(), b"new");
+        assert_eq!(fs::read(&store).unwrap(), b"{}");
+        assert!(!dir.join("keystore.key.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//! Encryption at rest for message content.
+//!
+//! Each user's rows are sealed with `crypto_helper::encrypt`, keyed by the
+//! X448 agreement between the Iota identity and the user's public key.
+//! The identity is only as safe as the keystore: with
+//! `IOTA_KEYSTORE_PASSPHRASE` set, a copy of the data directory reveals no
+//! content; in key file mode `keystore.key` sits in that same directory and
+//! the sealing only protects against reading the DB file on its own. Search
+//! runs over keyed hashes of the words (`search_tokens`), which are stable
+//! per user but unreadable without the key. Rows written while no key is
+//! available stay plaintext with `encrypted = 0` and are sealed by
+//! `encrypt_existing_rows` at the next start.
+
+use crate::users::user_manager;
+use crate::util::crypto_helper::{self, hash_it};
+use crate::util::db::{self, MESSAGES_DB};
+use crate::util::keystore;
+use rusqlite::{Connection, params};
+use std::collections::BTreeSet;
+
+/// What sealing and search need for one storage owner.
+#[derive(Clone)]
+pub struct UserKeys {
+    /// Derived once; sealing a row must not redo the key agreement.
+    shared_key: [u8; 32],
+    search_key: Vec<u8>,
+}
+
+impl UserKeys {
+    pub fn new(iota_secret: &str, user_public: &str) -> Option<Self> {
+        let shared = crypto_helper::shared_key(iota_secret, user_public).ok()?;
+        let mut search_key = shared.to_vec();
+        search_key.extend_from_slice(b"message-search");
+        Some(UserKeys {
+            shared_key: shared,
+            search_key: hash_it(&search_key),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Option<String> {
+        crypto_helper::encrypt_with_key(&self.shared_key, plaintext).ok()
+    }
+
+    pub fn open(&self, sealed: &str) -> Option<String> {
+        crypto_helper::decrypt_with_key(&self.shared_key, sealed).ok()
+    }
+
+    fn token(&self, word: &str) -> String {
+        let mut input = self.search_key.clone();
+        input.extend_from_slice(word.as_bytes());
+        hex::encode(&hash_it(&input)[..8])
+    }
+}
+
+/// Keys for a local user, or `None` while the keystore or the user is
+/// unavailable.
+pub fn keys_for(storage_owner: i64) -> Option<UserKeys> {
+    let iota = keystore::get_keys()?;
+    let user = user_manager::get_user(storage_owner)?;
+    UserKeys::new(&iota.private_key, &user.public_key)
+}
+
+/// Lowercased words of `text`, without duplicates.
+pub fn words(text: &str) -> Vec<String> {
+    let mut seen = BTreeSet::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .filter(|w| seen.insert(w.clone()))
+        .collect()
+}
+
+/// `(content, encrypted)` as it should be stored.
+pub fn seal(keys: Option<&UserKeys>, plaintext: &str) -> (String, bool) {
+    match keys.and_then(|k| k.seal(plaintext)) {
+        Some(sealed) => (sealed, true),
+        None => (plaintext.to_string(), false),
+    }
+}
+
+/// A sealed row the available keys cannot open: the keystore or the user is
+/// not loaded, or the row was sealed under another key.
+#[derive(Debug)]
+pub struct OpenError;
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stored content could not be decrypted (missing or wrong key)"
+        )
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<OpenError> for rusqlite::Error {
+    fn from(e: OpenError) -> Self {
+        rusqlite::Error::UserFunctionError(Box::new(e))
+    }
+}
+
+/// The readable content of a stored row.
+pub fn open(keys: Option<&UserKeys>, stored: &str, encrypted: bool) -> Result<String, OpenError> {
+    if !encrypted {
+        return Ok(stored.to_string());
+    }
+    keys.and_then(|k| k.open(stored)).ok_or(OpenError)
+}
+
+/// The `search_tokens` column for `plaintext`.
+pub fn search_tokens(keys: Option<&UserKeys>, plaintext: &str) -> String {
+    words(plaintext)
+        .iter()
+        .map(|w| match keys {
+            Some(keys) => keys.token(w),
+            None => w.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// An FTS5 query requiring every word of `input`. Tokens are quoted, so
+/// operators in the input are matched literally.
+pub fn search_query(keys: Option<&UserKeys>, input: &str) -> String {
+    search_tokens(keys, input)
+        .split(' ')
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("\"{}\"", t))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// About a dozen words of `content` around the first match, with matched
+/// words in `[brackets]`.
+pub fn snippet(content: &str, query_words: &[String]) -> String {
+    let parts: Vec<&str> = content.split_whitespace().collect();
+    let hit = |part: &str| words(part).iter().any(|w| query_words.contains(w));
+    let first = parts.iter().position(|p| hit(p)).unwrap_or(0);
+    let start = first.saturating_sub(4);
+    let end = (start + 12).min(parts.len());
+
+    let mut out = Vec::new();
+    if start > 0 {
+        out.push("…".to_string());
+    }
+    for part in &parts[start..end] {
+        if hit(part) {
+            out.push(format!("[{}]", part));
+        } else {
+            out.push(part.to_string());
+        }
+    }
+    if end < parts.len() {
+        out.push("…".to_string());
+    }
+    out.join(" ")
+}
+
+/// Seals every plaintext row (and edit history) of users whose keys are
+/// available. Runs once per start; rows already sealed are skipped.
+pub fn encrypt_existing_rows() -> Result<usize, String> {
+    let owners: Vec<i64> = MESSAGES_DB.read_blocking(|conn| {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT storage_owner FROM messages WHERE encrypted = 0
+            UNION
+            SELECT m.storage_owner FROM message_edits e
+            JOIN messages m ON m.id = e.message_row
+            WHERE e.encrypted = 0
+            "#,
+        )?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    })?;
+
+    let mut sealed = 0;
+    for owner in owners {
+        let Some(keys) = keys_for(owner) else {
+            continue;
+        };
+        sealed += MESSAGES_DB.write_blocking(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            let n = reseal(&tx, owner, None, &keys)?;
+            tx.commit()?;
+            Ok(n)
+        })?;
+    }
+    if sealed > 0 {
+        MESSAGES_DB.write_blocking(db::scrub)?;
+    }
+    Ok(sealed)
+}
+
+/// Seals the rows (and edit history) of `storage_owner` with `new`, after
+/// opening them with `old`. Without `old` only plaintext rows are touched.
+/// Fails on the first row that does not open with `old`, so callers that
+/// run it in a transaction keep every row as it was.
+/// Runs on the caller's connection so a key change can commit together
+/// with the profile that holds the new key.
+pub fn reseal(
+    conn: &Connection,
+    storage_owner: i64,
+    old: Option<&UserKeys>,
+    new: &UserKeys,
+) -> rusqlite::Result<usize> {
+    let rows: Vec<(i64, String, bool)> = {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT id, content, encrypted FROM messages
+            WHERE storage_owner = ?1 AND (encrypted = 0 OR ?2)
+            "#,
+        )?;
+        let rows = stmt.query_map(params![storage_owner, old.is_some()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect::<Result<_, _>>()?
+    };
+    for (id, content, encrypted) in &rows {
+        let plaintext = open(old, content, *encrypted)?;
+        let (stored, encrypted) = seal(Some(new), &plaintext);
+        conn.execute(
+            "UPDATE messages SET content = ?2, encrypted = ?3, search_tokens = ?4 WHERE id = ?1",
+            params![id, stored, encrypted, search_tokens(Some(new), &plaintext)],
+        )?;
+    }
+
+    let edits: Vec<(i64, String, bool)> = {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT e.id, e.content, e.encrypted FROM message_edits e
+            JOIN messages m ON m.id = e.message_row
+            WHERE m.storage_owner = ?1 AND (e.encrypted = 0 OR ?2)
+            "#,
+        )?;
+        let rows = stmt.query_map(params![storage_owner, old.is_some()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect::<Result<_, _>>()?
+    };
+    for (id, content, encrypted) in &edits {
+        let plaintext = open(old, content, *encrypted)?;
+        let (stored, encrypted) = seal(Some(new), &plaintext);
+        conn.execute(
+            "UPDATE message_edits SET content = ?2, encrypted = ?3 WHERE id = ?1",
+            params![id, stored, encrypted],
+        )?;
+    }
+    Ok(rows.len() + edits.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::crypto_helper::{
+        generate_keypair, public_key_to_base64, secret_key_to_base64,
+    };
+
+    fn user_keys() -> UserKeys {
+        let iota = generate_keypair();
+        let user = generate_keypair();
+        UserKeys::new(
+            &secret_key_to_base64(&iota.secret),
+            &public_key_to_base64(&user.public),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sealed_content_opens_only_with_the_same_keys() {
+        let keys = user_keys();
+        let (stored, encrypted) = seal(Some(&keys), "meet at noon");
+        assert!(encrypted);
+        assert!(!stored.contains("noon"));
+        assert_eq!(open(Some(&keys), &stored, true).unwrap(), "meet at noon");
+        assert!(open(Some(&user_keys()), &stored, true).is_err());
+        assert!(open(None, &stored, true).is_err());
+        assert_eq!(seal(None, "plain"), ("plain".to_string(), false));
+    }
+
+    #[test]
+    fn sealed_rows_keep_the_encrypt_format() {
+        let iota = generate_keypair();
+        let user = generate_keypair();
+        let (secret, public) = (
+            secret_key_to_base64(&iota.secret),
+            public_key_to_base64(&user.public),
+        );
+        let keys = UserKeys::new(&secret, &public).unwrap();
+        let stored = keys.seal("meet at noon").unwrap();
+        assert_eq!(
+            crypto_helper::decrypt(&secret, &public, &stored).unwrap(),
+            "meet at noon"
+        );
+        let sealed = crypto_helper::encrypt(&secret, &public, "meet at noon").unwrap();
+        assert_eq!(keys.open(&sealed).unwrap(), "meet at noon");
+    }
+
+    #[test]
+    fn search_tokens_are_keyed_and_match_queries() {
+        let keys = user_keys();
+        let tokens = search_tokens(Some(&keys), "Lunch, lunch at NOON");
+        assert_eq!(tokens.split(' ').count(), 3);
+        assert!(!tokens.contains("lunch"));
+        assert!(tokens.contains(&keys.token("noon")));
+        assert_ne!(
+            tokens,
+            search_tokens(Some(&user_keys()), "Lunch, lunch at NOON")
+        );
+
+        assert_eq!(search_query(None, "  a OR \"b "), "\"a\" \"or\" \"b\"");
+        assert_eq!(search_query(None, "  "), "");
+    }
+
+    #[test]
+    fn snippet_marks_matches_around_the_first_hit() {
+        let words = vec!["lunch".to_string()];
+        assert_eq!(snippet("no lunch today", &words), "no [lunch] today");
+        let long = "a b c d e f g h lunch? i j k l m n o p";
+        assert_eq!(snippet(long, &words), "… e f g h [lunch?] i j k l m n o …");
+    }
+
+    #[test]
+    fn reseal_moves_rows_to_the_new_keys() {
+        use crate::util::chat_files::{self, MessageCursor, NewMessage};
+        use crate::util::db::{Database, MESSAGES_MIGRATIONS};
+
+        let (old, new) = (user_keys(), user_keys());
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        db.write_blocking(|conn| {
+            let message = NewMessage {
+                message_id: 1,
+                send_time: 1_000,
+                sent_by_self: true,
+                storage_owner: 1,
+                external_user: 2,
+                content: "see you at noon".to_string(),
+                height: 0,
+            };
+            chat_files::add_message(conn, Some(&old), &message)?;
+            assert_eq!(reseal(conn, 1, None, &new)?, 0);
+            assert!(reseal(conn, 1, Some(&user_keys()), &new).is_err());
+            assert_eq!(reseal(conn, 1, Some(&old), &new)?, 1);
+
+            let messages =
+                chat_files::get_messages(conn, Some(&new), 1, 2, MessageCursor::Offset(0), 10)?;
+            assert_eq!(messages[0]["content"].as_str(), Some("see you at noon"));
+            Ok(())
+        })
+        .unwrap();
+    }
+}
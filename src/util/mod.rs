@@ -1,3 +1,4 @@
+pub mod blob_store;
 pub mod chat_files;
 pub mod chats_util;
 pub mod communities_util;
@@ -5,5 +6,12 @@ pub mod config_util;
 pub mod crypto_helper;
 pub mod crypto_util;
 pub mod db;
+pub mod export_util;
 pub mod file_util;
+pub mod keystore;
 pub mod logger;
+pub mod message_crypto;
+pub mod outbox_util;
+pub mod repository;
+pub mod retention_util;
+pub mod users_util;
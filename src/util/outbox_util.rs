@@ -0,0 +1,70 @@
+//! Durable queue for Omikron messages that could not be sent.
+//!
+//! Rows live in the `outbox` table of the messages DB and are replayed in
+//! insertion order once the Iota is identified again.
+
+use crate::util::db::MESSAGES_DB;
+use rusqlite::params;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct OutboxEntry {
+    pub id: i64,
+    pub communication_type: String,
+    pub payload: String,
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Queue a serialized message that expires `ttl_secs` from now.
+pub fn enqueue(communication_type: &str, payload: &str, ttl_secs: u64) -> Result<(), String> {
+    let now = now_millis();
+    let expires_at = now.saturating_add((ttl_secs as i64).saturating_mul(1000));
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute(
+            r#"
+            INSERT INTO outbox (communication_type, payload, queued_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4)
+            "#,
+            params![communication_type, payload, now, expires_at],
+        )?;
+        Ok(())
+    })
+}
+
+/// Drop expired rows and return the rest, oldest first.
+pub fn pending() -> Result<Vec<OutboxEntry>, String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute(
+            "DELETE FROM outbox WHERE expires_at <= ?1",
+            params![now_millis()],
+        )?;
+
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT id, communication_type, payload
+            FROM outbox
+            ORDER BY id ASC
+            "#,
+        )?;
+        let rows = stmt.query_map([], |r| {
+            Ok(OutboxEntry {
+                id: r.get(0)?,
+                communication_type: r.get(1)?,
+                payload: r.get(2)?,
+            })
+        })?;
+        rows.collect()
+    })
+}
+
+pub fn remove(id: i64) -> Result<(), String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute("DELETE FROM outbox WHERE id = ?1", params![id])?;
+        Ok(())
+    })
+}
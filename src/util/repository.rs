@@ -0,0 +1,468 @@
+//! Async access to messages, contacts and communities.
+//!
+//! The traits describe what handlers need from storage; `SqliteStore`
+//! implements all of them on a `db::Database`, reading from its pool and
+//! queueing writes on its writer. `STORE` is the instance backed by the
+//! messages DB, `SqliteStore::in_memory` gives tests a private one. Keys for
+//! sealed content are looked up before a connection is taken.
+
+use crate::log;
+use crate::users::contact::Contact;
+use crate::util::chat_files::{
+    self, MessageCursor, MessageState, NewMessage, SearchFilter, StoredCopy,
+};
+use crate::util::chats_util::{self, ContactFlags};
+use crate::util::communities_util::CommunitiesUtil;
+use crate::util::db::{self, Database, MESSAGES_DB};
+use crate::util::message_crypto::keys_for;
+use async_trait::async_trait;
+use json::{Array, JsonValue, array};
+use std::sync::{Arc, LazyLock};
+
+/// The store handlers use unless their connection brings its own.
+pub static STORE: LazyLock<SqliteStore> = LazyLock::new(|| SqliteStore::new(MESSAGES_DB.clone()));
+
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// Stores one side's copy. Returns `true` if this owner already has a
+    /// copy with the same wire id (a redelivery); nothing is written then.
+    async fn add_message(&self, message: NewMessage) -> bool;
+
+    async fn get_message_state(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        message_id: u32,
+    ) -> Option<MessageState>;
+
+    /// Upgrades the state of one copy, matched by wire id or by partner and
+    /// `timestamp` for rows stored without one.
+    async fn change_message_state(
+        &self,
+        message_id: u32,
+        timestamp: i64,
+        storage_owner: i64,
+        external_user: i64,
+        new_state: MessageState,
+    ) -> Result<(), String>;
+
+    async fn get_copy(&self, storage_owner: i64, row_id: i64) -> Option<StoredCopy>;
+
+    async fn find_copy(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        message_id: Option<u32>,
+        message_time: i64,
+    ) -> Option<StoredCopy>;
+
+    async fn edit_copy(&self, row_id: i64, content: &str, edited_at: i64) -> Result<(), String>;
+
+    async fn delete_copy(&self, row_id: i64, deleted_at: i64) -> Result<(), String>;
+
+    async fn get_edit_history(&self, row_id: i64) -> Vec<(String, i64)>;
+
+    async fn get_messages(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        cursor: MessageCursor,
+        amount: i64,
+    ) -> Result<JsonValue, String>;
+
+    async fn search_messages(
+        &self,
+        storage_owner: i64,
+        query: &str,
+        filter: &SearchFilter,
+        offset: i64,
+        amount: i64,
+    ) -> JsonValue;
+}
+
+#[async_trait]
+pub trait ContactStore: Send + Sync {
+    async fn get_user(&self, storage_owner: i64, user_id: i64) -> Option<Contact>;
+
+    async fn get_users(&self, storage_owner: i64) -> Vec<Contact>;
+
+    async fn mod_user(&self, storage_owner: i64, contact: &Contact);
+
+    /// Applies `flags` and returns the contact as it is now stored.
+    async fn set_flags(
+        &self,
+        storage_owner: i64,
+        user_id: i64,
+        flags: ContactFlags,
+    ) -> Result<Contact, String>;
+
+    /// Whether `storage_owner` refuses messages from `user_id`. A block state
+    /// that cannot be read counts as blocked, so a failing DB never lets a
+    /// blocked sender through.
+    async fn is_blocked(&self, storage_owner: i64, user_id: i64) -> bool;
+}
+
+#[async_trait]
+pub trait CommunityStore: Send + Sync {
+    async fn add_community(&self, storage_owner: i64, address: &str, title: &str, position: &str);
+
+    async fn remove_community(&self, storage_owner: i64, address: &str);
+
+    async fn get_communities(&self, storage_owner: i64) -> Array;
+}
+
+/// The stores on one SQLite database.
+pub struct SqliteStore {
+    db: Arc<Database>,
+}
+
+impl SqliteStore {
+    pub fn new(db: Arc<Database>) -> Self {
+        SqliteStore { db }
+    }
+
+    /// A store on a fresh in-memory database with the full schema.
+    pub fn in_memory() -> Self {
+        let db = Database::open_in_memory(db::MESSAGES_MIGRATIONS)
+            .expect("Failed to create in-memory messages DB");
+        SqliteStore::new(Arc::new(db))
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqliteStore {
+    async fn add_message(&self, message: NewMessage) -> bool {
+        let keys = keys_for(message.storage_owner);
+        let res = self
+            .db
+            .write(move |conn| chat_files::add_message(conn, keys.as_ref(), &message))
+            .await;
+        match res {
+            Ok(redelivered) => redelivered,
+            Err(e) => {
+                log!("Failed to insert message into sqlite: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn get_message_state(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        message_id: u32,
+    ) -> Option<MessageState> {
+        self.db
+            .read(move |conn| {
+                chat_files::get_message_state(conn, storage_owner, external_user, message_id)
+            })
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn change_message_state(
+        &self,
+        message_id: u32,
+        timestamp: i64,
+        storage_owner: i64,
+        external_user: i64,
+        new_state: MessageState,
+    ) -> Result<(), String> {
+        self.db
+            .write(move |conn| {
+                chat_files::change_message_state(
+                    conn,
+                    message_id,
+                    timestamp,
+                    storage_owner,
+                    external_user,
+                    new_state,
+                )
+            })
+            .await
+    }
+
+    async fn get_copy(&self, storage_owner: i64, row_id: i64) -> Option<StoredCopy> {
+        self.db
+            .read(move |conn| chat_files::get_copy(conn, storage_owner, row_id))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn find_copy(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        message_id: Option<u32>,
+        message_time: i64,
+    ) -> Option<StoredCopy> {
+        self.db
+            .read(move |conn| {
+                chat_files::find_copy(conn, storage_owner, external_user, message_id, message_time)
+            })
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn edit_copy(&self, row_id: i64, content: &str, edited_at: i64) -> Result<(), String> {
+        let owner = self
+            .db
+            .read(move |conn| chat_files::copy_owner(conn, row_id))
+            .await?
+            .ok_or_else(|| format!("message {} not found", row_id))?;
+        let keys = keys_for(owner);
+        let content = content.to_string();
+        self.db
+            .write(move |conn| {
+                chat_files::edit_copy(conn, keys.as_ref(), row_id, &content, edited_at)
+            })
+            .await
+    }
+
+    async fn delete_copy(&self, row_id: i64, deleted_at: i64) -> Result<(), String> {
+        self.db
+            .write(move |conn| chat_files::delete_copy(conn, row_id, deleted_at))
+            .await
+    }
+
+    async fn get_edit_history(&self, row_id: i64) -> Vec<(String, i64)> {
+        let Ok(Some(owner)) = self
+            .db
+            .read(move |conn| chat_files::copy_owner(conn, row_id))
+            .await
+        else {
+            return Vec::new();
+        };
+        let keys = keys_for(owner);
+        self.db
+            .read(move |conn| chat_files::get_edit_history(conn, keys.as_ref(), row_id))
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn get_messages(
+        &self,
+        storage_owner: i64,
+        external_user: i64,
+        cursor: MessageCursor,
+        amount: i64,
+    ) -> Result<JsonValue, String> {
+        let keys = keys_for(storage_owner);
+        self.db
+            .read(move |conn| {
+                chat_files::get_messages(
+                    conn,
+                    keys.as_ref(),
+                    storage_owner,
+                    external_user,
+                    cursor,
+                    amount,
+                )
+            })
+            .await
+    }
+
+    async fn search_messages(
+        &self,
+        storage_owner: i64,
+        query: &str,
+        filter: &SearchFilter,
+        offset: i64,
+        amount: i64,
+    ) -> JsonValue {
+        let keys = keys_for(storage_owner);
+        let query = query.to_string();
+        let filter = filter.clone();
+        let res = self
+            .db
+            .read(move |conn| {
+                chat_files::search_messages(
+                    conn,
+                    keys.as_ref(),
+                    storage_owner,
+                    &query,
+                    &filter,
+                    offset,
+                    amount,
+                )
+            })
+            .await;
+        match res {
+            Ok(hits) => hits,
+            Err(e) => {
+                log!("Failed to search messages: {}", e);
+                array![]
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ContactStore for SqliteStore {
+    async fn get_user(&self, storage_owner: i64, user_id: i64) -> Option<Contact> {
+        match self
+            .db
+            .read(move |conn| chats_util::get_user(conn, storage_owner, user_id))
+            .await
+        {
+            Ok(contact) => contact,
+            Err(e) => {
+                eprintln!("Error querying user in get_user: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn get_users(&self, storage_owner: i64) -> Vec<Contact> {
+        match self
+            .db
+            .read(move |conn| chats_util::get_users(conn, storage_owner))
+            .await
+        {
+            Ok(contacts) => contacts,
+            Err(e) => {
+                eprintln!("Failed to query contacts in get_users: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    async fn mod_user(&self, storage_owner: i64, contact: &Contact) {
+        let contact = contact.clone();
+        if let Err(e) = self
+            .db
+            .write(move |conn| chats_util::mod_user(conn, storage_owner, &contact))
+            .await
+        {
+            eprintln!("Failed to mod_user: {}", e);
+        }
+    }
+
+    async fn set_flags(
+        &self,
+        storage_owner: i64,
+        user_id: i64,
+        flags: ContactFlags,
+    ) -> Result<Contact, String> {
+        self.db
+            .write(move |conn| {
+                chats_util::set_flags(conn, storage_owner, user_id, &flags)?;
+                chats_util::get_user(conn, storage_owner, user_id)
+            })
+            .await?
+            .ok_or_else(|| format!("contact {} not found", user_id))
+    }
+
+    async fn is_blocked(&self, storage_owner: i64, user_id: i64) -> bool {
+        match self
+            .db
+            .read(move |conn| chats_util::is_blocked(conn, storage_owner, user_id))
+            .await
+        {
+            Ok(blocked) => blocked,
+            Err(e) => {
+                log!("Failed to query block state, treating as blocked: {}", e);
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CommunityStore for SqliteStore {
+    async fn add_community(&self, storage_owner: i64, address: &str, title: &str, position: &str) {
+        let (address, title, position) =
+            (address.to_string(), title.to_string(), position.to_string());
+        if let Err(e) = self
+            .db
+            .write(move |conn| {
+                CommunitiesUtil::add_community(conn, storage_owner, &address, &title, &position)
+            })
+            .await
+        {
+            eprintln!("Failed to add_community: {}", e);
+        }
+    }
+
+    async fn remove_community(&self, storage_owner: i64, address: &str) {
+        let address = address.to_string();
+        if let Err(e) = self
+            .db
+            .write(move |conn| CommunitiesUtil::remove_community(conn, storage_owner, &address))
+            .await
+        {
+            eprintln!("Failed to remove_community: {}", e);
+        }
+    }
+
+    async fn get_communities(&self, storage_owner: i64) -> Array {
+        match self
+            .db
+            .read(move |conn| CommunitiesUtil::get_communities(conn, storage_owner))
+            .await
+        {
+            Ok(communities) => communities,
+            Err(e) => {
+                eprintln!("Failed to query communities in get_communities: {}", e);
+                Array::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_stores_are_independent() {
+        let (a, b) = (SqliteStore::in_memory(), SqliteStore::in_memory());
+        a.add_community(1, "c.example", "Club", "0").await;
+        assert_eq!(a.get_communities(1).await.len(), 1);
+        assert!(b.get_communities(1).await.is_empty());
+
+        a.remove_community(1, "c.example").await;
+        assert!(a.get_communities(1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn contacts_round_trip() {
+        let store = SqliteStore::in_memory();
+        let mut contact = Contact::new(2);
+        contact.set_last_message_at(1_000);
+        store.mod_user(1, &contact).await;
+
+        let stored = store.get_user(1, 2).await.unwrap();
+        assert_eq!(stored.last_message_at, Some(1_000));
+        assert_eq!(store.get_users(1).await.len(), 1);
+        assert!(store.get_user(2, 1).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn flags_change_independently_and_survive_mod_user() {
+        let store = SqliteStore::in_memory();
+        assert!(!store.is_blocked(1, 2).await);
+
+        let blocked = ContactFlags {
+            blocked: Some(true),
+            ..Default::default()
+        };
+        let contact = store.set_flags(1, 2, blocked).await.unwrap();
+        assert!(contact.blocked && !contact.pinned);
+        assert!(store.is_blocked(1, 2).await);
+        assert!(!store.is_blocked(2, 1).await);
+
+        let pinned = ContactFlags {
+            pinned: Some(true),
+            ..Default::default()
+        };
+        store.set_flags(1, 3, pinned).await.unwrap();
+        store.mod_user(1, &Contact::new(2)).await;
+        let users = store.get_users(1).await;
+        assert_eq!(users[0].user_id, 3);
+        assert!(users[1].blocked);
+    }
+}
@@ -0,0 +1,319 @@
+//! Retention policies and disappearing messages.
+//!
+//! A policy limits how old a conversation's messages may get and how many
+//! are kept. The most specific one wins: the conversation's own policy,
+//! then the owner's policy for every conversation (`external_user = 0`),
+//! then the Iota-wide `retention` config. A `NULL` column inherits from the
+//! next level, `0` lifts the limit. Independently, a sender can give a
+//! message an `expires_at`, which applies to both copies.
+
+use crate::util::chats_util;
+use crate::util::db::MESSAGES_DB;
+use rusqlite::{Connection, params};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_age_ms: Option<i64>,
+    pub max_count: Option<i64>,
+}
+
+/// A stored copy removed by retention.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiredMessage {
+    pub row_id: i64,
+    pub storage_owner: i64,
+    pub external_user: i64,
+    pub message_id: Option<u32>,
+    /// Removed because of its own `expires_at` rather than a policy; the
+    /// partner's copy has to go as well.
+    pub disappearing: bool,
+}
+
+/// Sets the policy of `storage_owner` for the conversation with
+/// `external_user`, or for all conversations when it is `0`.
+pub fn set_policy(
+    storage_owner: i64,
+    external_user: i64,
+    policy: RetentionPolicy,
+) -> Result<(), String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        if policy == RetentionPolicy::default() {
+            conn.execute(
+                "DELETE FROM retention_policies WHERE storage_owner = ?1 AND external_user = ?2",
+                params![storage_owner, external_user],
+            )?;
+        } else {
+            conn.execute(
+                r#"
+                INSERT INTO retention_policies (storage_owner, external_user, max_age_ms, max_count)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (storage_owner, external_user) DO UPDATE SET
+                    max_age_ms = excluded.max_age_ms,
+                    max_count = excluded.max_count
+                "#,
+                params![
+                    storage_owner,
+                    external_user,
+                    policy.max_age_ms,
+                    policy.max_count
+                ],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Gives `storage_owner`'s copy of message `message_id` from or to
+/// `external_user` an expiry time.
+pub fn set_expiry(
+    storage_owner: i64,
+    external_user: i64,
+    message_id: u32,
+    expires_at: i64,
+) -> Result<(), String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        conn.execute(
+            r#"
+            UPDATE messages SET expires_at = ?4
+            WHERE storage_owner = ?1 AND external_user = ?2 AND message_id = ?3
+            "#,
+            params![storage_owner, external_user, message_id as i64, expires_at],
+        )?;
+        Ok(())
+    })
+}
+
+/// Deletes every copy that expired by `now` under its own expiry or the
+/// policy that applies to it, and returns what was removed.
+pub fn purge(now: i64, global: RetentionPolicy) -> Result<Vec<ExpiredMessage>, String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        let tx = conn.unchecked_transaction()?;
+        mark_rows(&tx)?;
+        tx.execute(
+            r#"
+            INSERT INTO purge_rows (id)
+            SELECT id FROM (
+                SELECT
+                    m.id,
+                    m.message_time,
+                    m.expires_at,
+                    COALESCE(c.max_age_ms, u.max_age_ms, ?2) AS max_age,
+                    COALESCE(c.max_count, u.max_count, ?3) AS max_count,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY m.storage_owner, m.external_user
+                        ORDER BY m.message_time DESC, m.id DESC
+                    ) AS position
+                FROM messages m
+                LEFT JOIN retention_policies c
+                    ON c.storage_owner = m.storage_owner AND c.external_user = m.external_user
+                LEFT JOIN retention_policies u
+                    ON u.storage_owner = m.storage_owner AND u.external_user = 0
+            )
+            WHERE expires_at <= ?1
+               OR (max_age > 0 AND message_time < ?1 - max_age)
+               OR (max_count > 0 AND position > max_count)
+            "#,
+            params![now, global.max_age_ms, global.max_count],
+        )?;
+        let expired = remove_marked(&tx, now)?;
+        tx.commit()?;
+        Ok(expired)
+    })
+}
+
+/// Deletes the copies of disappearing messages that `external_user`'s Iota
+/// reported as expired. Copies without an expiry are left alone, so a relay
+/// can only speed up what the sender already announced.
+pub fn expire_relayed(
+    storage_owner: i64,
+    external_user: i64,
+    message_ids: &[u32],
+) -> Result<Vec<ExpiredMessage>, String> {
+    MESSAGES_DB.write_blocking(|conn| {
+        let tx = conn.unchecked_transaction()?;
+        mark_rows(&tx)?;
+        let mut stmt = tx.prepare(
+            r#"
+            INSERT INTO purge_rows (id)
+            SELECT id FROM messages
+            WHERE storage_owner = ?1
+              AND external_user = ?2
+              AND message_id = ?3
+              AND expires_at IS NOT NULL
+            "#,
+        )?;
+        for message_id in message_ids {
+            stmt.execute(params![storage_owner, external_user, *message_id as i64])?;
+        }
+        drop(stmt);
+        let expired = remove_marked(&tx, i64::MAX)?;
+        tx.commit()?;
+        Ok(expired)
+    })
+}
+
+/// Prepares the per-connection scratch table listing rows to delete.
+fn mark_rows(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        CREATE TEMP TABLE IF NOT EXISTS purge_rows (id INTEGER PRIMARY KEY);
+        DELETE FROM purge_rows;
+        "#,
+    )
+}
+
+/// Deletes the rows listed in `purge_rows`. Last-read markers pointing at
+/// them move back to the newest surviving message before them, so unread
+/// counts stay right.
+fn remove_marked(conn: &Connection, now: i64) -> rusqlite::Result<Vec<ExpiredMessage>> {
+    let expired = {
+        let mut stmt = conn.prepare(
+            r#"
+            SELECT m.id, m.storage_owner, m.external_user, m.message_id,
+                   COALESCE(m.expires_at <= ?1, 0)
+            FROM messages m
+            JOIN purge_rows p ON p.id = m.id
+            "#,
+        )?;
+        let rows = stmt.query_map(params![now], |row| {
+            Ok(ExpiredMessage {
+                row_id: row.get(0)?,
+                storage_owner: row.get(1)?,
+                external_user: row.get(2)?,
+                message_id: row.get::<_, Option<i64>>(3)?.map(|id| id as u32),
+                disappearing: row.get(4)?,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+    if expired.is_empty() {
+        return Ok(expired);
+    }
+
+    conn.execute_batch(
+        r#"
+        UPDATE contacts SET last_read_id = (
+            SELECT m.id
+            FROM messages m, messages r
+            WHERE r.id = contacts.last_read_id
+              AND m.storage_owner = contacts.storage_owner
+              AND m.external_user = contacts.user_id
+              AND m.id NOT IN (SELECT id FROM purge_rows)
+              AND (m.message_time, m.id) < (r.message_time, r.id)
+            ORDER BY m.message_time DESC, m.id DESC
+            LIMIT 1
+        )
+        WHERE last_read_id IN (SELECT id FROM purge_rows);
+
+        DELETE FROM message_edits WHERE message_row IN (SELECT id FROM purge_rows);
+        DELETE FROM messages WHERE id IN (SELECT id FROM purge_rows);
+        DELETE FROM purge_rows;
+        "#,
+    )?;
+
+    let mut conversations: Vec<(i64, i64)> = expired
+        .iter()
+        .map(|e| (e.storage_owner, e.external_user))
+        .collect();
+    conversations.sort();
+    conversations.dedup();
+    for (storage_owner, external_user) in conversations {
+        chats_util::refresh_unread(conn, storage_owner, external_user)?;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::chat_files::{self, MessageCursor, NewMessage};
+    use ttp_core::rand_u32;
+
+    fn add_message(
+        message_id: u32,
+        send_time: u128,
+        sent_by_self: bool,
+        owner: i64,
+        partner: i64,
+        content: &str,
+    ) {
+        let message = NewMessage {
+            message_id,
+            send_time,
+            sent_by_self,
+            storage_owner: owner,
+            external_user: partner,
+            content: content.to_string(),
+            height: 0,
+        };
+        MESSAGES_DB
+            .write_blocking(|conn| chat_files::add_message(conn, None, &message))
+            .unwrap();
+    }
+
+    fn stored(owner: i64, partner: i64) -> Vec<String> {
+        MESSAGES_DB
+            .read_blocking(|conn| {
+                chat_files::get_messages(conn, None, owner, partner, MessageCursor::Offset(0), 100)
+            })
+            .unwrap()
+            .members()
+            .map(|m| m["content"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    // One test: a sweep is Iota-wide, so parallel sweeps would race.
+    #[test]
+    fn purge_applies_policies_and_expiry() {
+        let owner = 8_000_000 + rand_u32() as i64;
+        let (kept, trimmed) = (owner + 1, owner + 2);
+        for partner in [kept, trimmed] {
+            for (time, content) in [(1_000, "old"), (9_000, "mid"), (9_500, "new")] {
+                add_message(rand_u32(), time, false, owner, partner, content);
+            }
+        }
+        // Everything of this owner is limited by age; one conversation
+        // lifts that and only caps the count instead.
+        let everywhere = RetentionPolicy {
+            max_age_ms: Some(5_000),
+            max_count: None,
+        };
+        let conversation = RetentionPolicy {
+            max_age_ms: Some(0),
+            max_count: Some(2),
+        };
+        set_policy(owner, 0, everywhere).unwrap();
+        set_policy(owner, kept, conversation).unwrap();
+
+        let reader = owner + 3;
+        let (gone, relayed, plain) = (rand_u32(), rand_u32(), rand_u32());
+        add_message(gone, 1_000, true, reader, owner, "gone");
+        add_message(relayed, 2_000, false, reader, owner, "relayed");
+        add_message(plain, 3_000, false, reader, owner, "plain");
+        set_expiry(reader, owner, gone, 5_000).unwrap();
+        set_expiry(reader, owner, relayed, i64::MAX).unwrap();
+
+        let expired = purge(10_000, RetentionPolicy::default()).unwrap();
+        let by_policy: Vec<_> = expired
+            .iter()
+            .filter(|e| e.storage_owner == owner)
+            .collect();
+        assert_eq!(by_policy.len(), 2);
+        assert!(by_policy.iter().all(|e| !e.disappearing));
+        assert_eq!(stored(owner, kept), vec!["new", "mid"]);
+        assert_eq!(stored(owner, trimmed), vec!["new", "mid"]);
+
+        let disappeared: Vec<_> = expired
+            .iter()
+            .filter(|e| e.storage_owner == reader)
+            .collect();
+        assert_eq!(disappeared.len(), 1);
+        assert_eq!(disappeared[0].message_id, Some(gone));
+        assert!(disappeared[0].disappearing);
+
+        // Relays only remove copies that carry an expiry.
+        let relayed_out = expire_relayed(reader, owner, &[relayed, plain]).unwrap();
+        assert_eq!(relayed_out.len(), 1);
+        assert_eq!(stored(reader, owner), vec!["plain"]);
+    }
+}
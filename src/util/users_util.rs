@@ -0,0 +1,300 @@
+//! User profile rows of the messages DB, on a connection from
+//! `db::Database`. `user_manager` keeps its cache in step with them, and
+//! `user_purge` uses the purge schedule kept here.
+
+use crate::users::user_profile::UserProfile;
+use rusqlite::{Connection, OptionalExtension, params};
+
+fn read_user(r: &rusqlite::Row) -> rusqlite::Result<UserProfile> {
+    Ok(UserProfile {
+        user_id: r.get(0)?,
+        username: r.get(1)?,
+        display_name: r.get(2)?,
+        public_key: r.get(3)?,
+        private_key_hash: r.get(4)?,
+        reset_token: r.get(5)?,
+        created_at: r.get(6)?,
+        moved_at: r.get(7)?,
+    })
+}
+
+const COLUMNS: &str = "user_id, username, display_name, public_key, private_key_hash, \
+                       reset_token, created_at, moved_at";
+
+/// Stores a new profile. Fails if the id or the username is taken.
+pub fn insert_user(conn: &Connection, user: &UserProfile) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!("INSERT INTO users ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"),
+        params![
+            user.user_id,
+            user.username,
+            user.display_name,
+            user.public_key,
+            user.private_key_hash,
+            user.reset_token,
+            user.created_at,
+            user.moved_at
+        ],
+    )?;
+    Ok(())
+}
+
+/// Overwrites the stored profile of `user.user_id`. Returns `false` if there
+/// is none.
+pub fn update_user(conn: &Connection, user: &UserProfile) -> rusqlite::Result<bool> {
+    let changed = conn.execute(
+        r#"
+        UPDATE users SET
+            username = ?2,
+            display_name = ?3,
+            public_key = ?4,
+            private_key_hash = ?5,
+            reset_token = ?6,
+            created_at = ?7,
+            moved_at = ?8
+        WHERE user_id = ?1
+        "#,
+        params![
+            user.user_id,
+            user.username,
+            user.display_name,
+            user.public_key,
+            user.private_key_hash,
+            user.reset_token,
+            user.created_at,
+            user.moved_at
+        ],
+    )?;
+    Ok(changed > 0)
+}
+
+/// Deletes the profile and every row stored for the user: messages with
+/// their edit history, contacts, communities, retention policies and a
+/// pending purge. Other users' copies of shared conversations stay. Returns
+/// `false` if there was no such user.
+pub fn delete_user(conn: &Connection, user_id: i64) -> rusqlite::Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        r#"
+        DELETE FROM message_edits WHERE message_row IN
+            (SELECT id FROM messages WHERE storage_owner = ?1)
+        "#,
+        params![user_id],
+    )?;
+    for table in ["messages", "contacts", "communities", "retention_policies"] {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE storage_owner = ?1"),
+            params![user_id],
+        )?;
+    }
+    tx.execute(
+        "DELETE FROM user_purges WHERE user_id = ?1",
+        params![user_id],
+    )?;
+    let removed = tx.execute("DELETE FROM users WHERE user_id = ?1", params![user_id])? > 0;
+    tx.commit()?;
+    Ok(removed)
+}
+
+/// Schedules the purge of `user_id` at `purge_at`, replacing an earlier one.
+pub fn schedule_purge(conn: &Connection, user_id: i64, purge_at: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        INSERT INTO user_purges (user_id, purge_at) VALUES (?1, ?2)
+        ON CONFLICT(user_id) DO UPDATE SET purge_at = excluded.purge_at
+        "#,
+        params![user_id, purge_at],
+    )?;
+    Ok(())
+}
+
+/// Returns `false` if no purge was scheduled.
+pub fn cancel_purge(conn: &Connection, user_id: i64) -> rusqlite::Result<bool> {
+    Ok(conn.execute(
+        "DELETE FROM user_purges WHERE user_id = ?1",
+        params![user_id],
+    )? > 0)
+}
+
+/// When `user_id` is scheduled to be purged, if at all.
+pub fn purge_at(conn: &Connection, user_id: i64) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        "SELECT purge_at FROM user_purges WHERE user_id = ?1",
+        params![user_id],
+        |r| r.get(0),
+    )
+    .optional()
+}
+
+/// Every scheduled purge, as `(user_id, purge_at)`.
+pub fn scheduled_purges(conn: &Connection) -> rusqlite::Result<Vec<(i64, i64)>> {
+    let mut stmt = conn.prepare("SELECT user_id, purge_at FROM user_purges")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    rows.collect()
+}
+
+/// The users whose purge is due at `now`.
+pub fn due_purges(conn: &Connection, now: i64) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt =
+        conn.prepare("SELECT user_id FROM user_purges WHERE purge_at <= ?1 ORDER BY purge_at")?;
+    let rows = stmt.query_map(params![now], |r| r.get(0))?;
+    rows.collect()
+}
+
+pub fn get_user(conn: &Connection, user_id: i64) -> rusqlite::Result<Option<UserProfile>> {
+    conn.query_row(
+        &format!("SELECT {COLUMNS} FROM users WHERE user_id = ?1"),
+        params![user_id],
+        read_user,
+    )
+    .optional()
+}
+
+/// All profiles, oldest first.
+pub fn get_users(conn: &Connection) -> rusqlite::Result<Vec<UserProfile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {COLUMNS} FROM users ORDER BY created_at, user_id"
+    ))?;
+    let rows = stmt.query_map([], read_user)?;
+    rows.collect()
+}
+
+/// Stores `users` in one transaction, skipping ids and usernames that are
+/// already taken. Returns how many were added.
+pub fn import_users(conn: &Connection, users: &[UserProfile]) -> rusqlite::Result<usize> {
+    let tx = conn.unchecked_transaction()?;
+    let mut added = 0;
+    for user in users {
+        let exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE user_id = ?1 OR username = ?2)",
+            params![user.user_id, user.username],
+            |r| r.get(0),
+        )?;
+        if !exists {
+            insert_user(&tx, user)?;
+            added += 1;
+        }
+    }
+    tx.commit()?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::chat_files::{self, NewMessage};
+    use crate::util::communities_util::CommunitiesUtil;
+    use crate::util::db::{Database, MESSAGES_MIGRATIONS};
+
+    fn profile(user_id: i64, username: &str) -> UserProfile {
+        UserProfile::new(
+            user_id,
+            username.to_string(),
+            None,
+            "public".to_string(),
+            "hash".to_string(),
+            "token".to_string(),
+        )
+    }
+
+    #[test]
+    fn profiles_round_trip_and_stay_unique() {
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        db.write_blocking(|conn| {
+            insert_user(conn, &profile(1, "alice"))?;
+            assert!(insert_user(conn, &profile(2, "alice")).is_err());
+
+            let mut alice = get_user(conn, 1)?.unwrap();
+            alice.display_name = Some("Alice".to_string());
+            alice.moved_at = Some(5_000);
+            assert!(update_user(conn, &alice)?);
+            assert_eq!(get_user(conn, 1)?.unwrap().moved_at, Some(5_000));
+            assert!(!update_user(conn, &profile(3, "carol"))?);
+            assert_eq!(
+                get_user(conn, 1)?.unwrap().display_name.as_deref(),
+                Some("Alice")
+            );
+
+            assert!(delete_user(conn, 1)?);
+            assert!(!delete_user(conn, 1)?);
+            assert!(get_users(conn)?.is_empty());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn delete_removes_only_the_users_rows() {
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        db.write_blocking(|conn| {
+            for (owner, partner) in [(1, 2), (2, 1)] {
+                insert_user(conn, &profile(owner, &format!("user{owner}")))?;
+                let message = NewMessage {
+                    message_id: 1,
+                    send_time: 1_000,
+                    sent_by_self: owner == 1,
+                    storage_owner: owner,
+                    external_user: partner,
+                    content: "hi".to_string(),
+                    height: 0,
+                };
+                chat_files::add_message(conn, None, &message)?;
+                CommunitiesUtil::add_community(conn, owner, "c.example", "Club", "0")?;
+            }
+            schedule_purge(conn, 1, 5_000)?;
+            assert!(delete_user(conn, 1)?);
+
+            let count = |table: &str, owner: i64| -> rusqlite::Result<i64> {
+                conn.query_row(
+                    &format!("SELECT COUNT(*) FROM {table} WHERE storage_owner = ?1"),
+                    params![owner],
+                    |r| r.get(0),
+                )
+            };
+            for table in ["messages", "contacts", "communities"] {
+                assert_eq!(count(table, 1)?, 0, "{table}");
+                assert_eq!(count(table, 2)?, 1, "{table}");
+            }
+            assert_eq!(purge_at(conn, 1)?, None);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn purges_come_due_and_can_be_cancelled() {
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        db.write_blocking(|conn| {
+            schedule_purge(conn, 1, 1_000)?;
+            schedule_purge(conn, 2, 9_000)?;
+            schedule_purge(conn, 2, 2_000)?;
+            assert_eq!(due_purges(conn, 1_500)?, vec![1]);
+            assert_eq!(due_purges(conn, 2_000)?.len(), 2);
+
+            assert!(cancel_purge(conn, 1)?);
+            assert!(!cancel_purge(conn, 1)?);
+            assert_eq!(purge_at(conn, 2)?, Some(2_000));
+            assert_eq!(scheduled_purges(conn)?, vec![(2, 2_000)]);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn import_skips_taken_ids_and_names() {
+        let db = Database::open_in_memory(MESSAGES_MIGRATIONS).unwrap();
+        let added = db
+            .write_blocking(|conn| {
+                insert_user(conn, &profile(1, "alice"))?;
+                import_users(
+                    conn,
+                    &[profile(1, "other"), profile(2, "alice"), profile(3, "bob")],
+                )
+            })
+            .unwrap();
+        assert_eq!(added, 1);
+        let users = db.read_blocking(get_users).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[1].username, "bob");
+    }
+}